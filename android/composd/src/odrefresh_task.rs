@@ -215,6 +215,7 @@ fn run_in_vm(
         targetDirName: target_dir_name.to_string(),
         zygoteArch: zygote_arch,
         systemServerCompilerFilter: system_server_compiler_filter,
+        ..Default::default()
     };
     let exit_code = service.odrefresh(&args)?;
 
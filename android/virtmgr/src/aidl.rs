@@ -88,6 +88,7 @@ use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::raw::pid_t;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, Weak, LazyLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 use vbmeta::VbMetaImage;
 use vmconfig::{VmConfig, get_debug_level};
 use vsock::VsockStream;
@@ -168,7 +169,7 @@ fn create_or_update_idsig_file(
     Ok(())
 }
 
-fn get_current_sdk() -> Result<u32> {
+pub(crate) fn get_current_sdk() -> Result<u32> {
     let current_sdk = system_properties::read("ro.build.version.sdk")?;
     let current_sdk = current_sdk.ok_or_else(|| anyhow!("SDK version missing"))?;
     current_sdk.parse().context("Malformed SDK version")
@@ -269,7 +270,9 @@ impl IVirtualizationService for VirtualizationService {
 
         match partition_type {
             PartitionType::RAW => Ok(()),
-            PartitionType::ANDROID_VM_INSTANCE => format_as_android_vm_instance(&mut image),
+            PartitionType::ANDROID_VM_INSTANCE => {
+                format_as_android_vm_instance(&mut image, SystemTime::now())
+            }
             PartitionType::ENCRYPTEDSTORE => format_as_encryptedstore(&mut image),
             _ => Err(Error::new(
                 ErrorKind::Unsupported,
@@ -890,9 +893,13 @@ fn write_zero_filler(zero_filler_path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn format_as_android_vm_instance(part: &mut dyn Write) -> std::io::Result<()> {
+fn format_as_android_vm_instance(part: &mut dyn Write, now: SystemTime) -> std::io::Result<()> {
+    // The creation time is only informational, so don't fail on a clock set before the epoch.
+    // It follows the version, where readers of all versions ignore it, so it doesn't bump it.
+    let created_epoch = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
     part.write_all(ANDROID_VM_INSTANCE_MAGIC.as_bytes())?;
     part.write_all(&ANDROID_VM_INSTANCE_VERSION.to_le_bytes())?;
+    part.write_all(&created_epoch.to_le_bytes())?;
     part.flush()
 }
 
@@ -1131,16 +1138,17 @@ fn load_app_config(
     // Microdroid takes additional init ramdisk & (optionally) storage image
     add_microdroid_system_images(config, instance_file, storage_image, os_name, &mut vm_config)?;
 
-    // Include Microdroid payload disk (contains apks, idsigs) in vm config
+    // Include Microdroid payload disk (contains apks, idsigs) in vm config. The guest doesn't
+    // mount split APKs yet, so only the main APK is passed.
     add_microdroid_payload_images(
         config,
         debug_config,
         temporary_directory,
-        apk_file,
-        idsig_file,
+        vec![(apk_file, idsig_file)],
         extra_apk_files,
         &vm_payload_config,
         &mut vm_config,
+        /* observer= */ None,
     )?;
 
     Ok(vm_config)
@@ -1270,6 +1278,7 @@ fn is_safe_app_partition(label: &str) -> bool {
     label == "vm-instance"
         || label == "encryptedstore"
         || label == "microdroid-apk-idsig"
+        || label.starts_with("microdroid-apk-idsig-")
         || label == "payload-metadata"
         || label.starts_with("extra-idsig-")
 }
@@ -2276,4 +2285,18 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_format_as_android_vm_instance() -> Result<()> {
+        let mut image = Vec::new();
+        let now = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+
+        format_as_android_vm_instance(&mut image, now)?;
+
+        let mut expected = b"Android-VM-instance".to_vec();
+        expected.extend_from_slice(&1u16.to_le_bytes());
+        expected.extend_from_slice(&1_700_000_000u64.to_le_bytes());
+        assert_eq!(image, expected);
+        Ok(())
+    }
 }
@@ -15,22 +15,89 @@
 //! Functions for AVF debug policy and debug level
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
-    VirtualMachineAppConfig::DebugLevel::DebugLevel, VirtualMachineConfig::VirtualMachineConfig,
+    VirtualMachineAppConfig::DebugLevel::DebugLevel,
+    VirtualMachineAppConfig::DebugPolicy::DebugPolicy as AppDebugPolicy,
+    VirtualMachineConfig::VirtualMachineConfig,
 };
-use anyhow::{anyhow, Context, Error, Result};
-use libfdt::{Fdt, FdtError};
-use log::{info, warn};
+use cstr::cstr;
+use libfdt::{Fdt, FdtError, FdtNode};
+use log::{debug, info, warn};
 use rustutils::system_properties;
 use std::ffi::{CString, NulError};
+use std::fmt;
 use std::fs;
-use std::io::ErrorKind;
-use std::path::{Path, PathBuf};
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
 use std::sync::LazyLock;
 use vmconfig::get_debug_level;
 
 const CUSTOM_DEBUG_POLICY_OVERLAY_SYSPROP: &str =
     "hypervisor.virtualizationmanager.debug_policy.path";
+/// Comma-separated paths of the properties which the custom overlays must set, if any.
+const REQUIRED_DEBUG_POLICY_PROPS_SYSPROP: &str =
+    "hypervisor.virtualizationmanager.debug_policy.required_props";
 const DEVICE_TREE_EMPTY_TREE_SIZE_BYTES: usize = 100; // rough estimation.
+/// Directories under which debug policies may route ramdumps, e.g. on a dedicated volume.
+const RAMDUMP_DESTINATION_ROOTS: [&str; 2] =
+    ["/data/misc/virtualizationservice", "/data/vendor/virt"];
+const RAMDUMP_DESTINATION_MAX_LEN: usize = 256;
+
+/// Errors from reading the debug policy.
+#[derive(Debug)]
+pub enum DebugConfigError {
+    /// The debug policy file doesn't exist.
+    NotFound,
+    /// The debug policy is present but can't be interpreted.
+    Malformed(String),
+    /// libfdt failed to handle a well-formed device tree.
+    FdtFailure(FdtError),
+    /// The debug policy couldn't be read.
+    Io(io::Error),
+}
+
+impl fmt::Display for DebugConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Debug policy not found"),
+            Self::Malformed(reason) => write!(f, "Malformed debug policy: {reason}"),
+            Self::FdtFailure(e) => write!(f, "Failed to handle debug policy device tree: {e}"),
+            Self::Io(e) => write!(f, "Failed to read debug policy: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DebugConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DebugConfigError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => Self::NotFound,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<FdtError> for DebugConfigError {
+    fn from(e: FdtError) -> Self {
+        Self::FdtFailure(e)
+    }
+}
+
+impl From<NulError> for DebugConfigError {
+    fn from(e: NulError) -> Self {
+        Self::Malformed(e.to_string())
+    }
+}
+
+/// Result type for reading the debug policy.
+pub type Result<T, E = DebugConfigError> = std::result::Result<T, E>;
 
 struct DPPath {
     node_path: CString,
@@ -62,79 +129,213 @@ static DP_RAMDUMP_PATH: LazyLock<DPPath> =
     LazyLock::new(|| DPPath::new("/avf/guest/common", "ramdump").unwrap());
 static DP_ADB_PATH: LazyLock<DPPath> =
     LazyLock::new(|| DPPath::new("/avf/guest/microdroid", "adb").unwrap());
+static DP_SERIAL_SHELL_PATH: LazyLock<DPPath> =
+    LazyLock::new(|| DPPath::new("/avf/guest/microdroid", "serial_shell").unwrap());
+static DP_RAMDUMP_DESTINATION_PATH: LazyLock<DPPath> =
+    LazyLock::new(|| DPPath::new("/avf/guest/common", "ramdump_path").unwrap());
+
+/// Boolean properties, as <u32>, which debug policies may set.
+fn known_debug_policy_props() -> [&'static DPPath; 4] {
+    [&DP_LOG_PATH, &DP_RAMDUMP_PATH, &DP_ADB_PATH, &DP_SERIAL_SHELL_PATH]
+}
 
 /// Get debug policy value in bool. It's true iff the value is explicitly set to <1>.
 fn get_debug_policy_bool(path: &Path) -> Result<bool> {
     let value = match fs::read(path) {
         Ok(value) => value,
         Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
-        Err(error) => Err(error).with_context(|| format!("Failed to read {path:?}"))?,
+        Err(error) => return Err(error.into()),
     };
 
     // DT spec uses big endian although Android is always little endian.
-    match u32::from_be_bytes(value.try_into().map_err(|_| anyhow!("Malformed value in {path:?}"))?)
-    {
+    let value = value
+        .try_into()
+        .map_err(|_| DebugConfigError::Malformed(format!("Malformed value in {path:?}")))?;
+    match u32::from_be_bytes(value) {
         0 => Ok(false),
         1 => Ok(true),
-        value => Err(anyhow!("Invalid value {value} in {path:?}")),
+        value => Err(DebugConfigError::Malformed(format!("Invalid value {value} in {path:?}"))),
     }
 }
 
-/// Get property value in bool. It's true iff the value is explicitly set to <1>.
+/// Get debug policy value as a string of at most `max_len` bytes, or None if it isn't set.
+fn get_debug_policy_string(path: &Path, max_len: usize) -> Result<Option<String>> {
+    let value = match fs::read(path) {
+        Ok(value) => value,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let value = parse_string(&value, max_len)
+        .map_err(|reason| DebugConfigError::Malformed(format!("{reason} in {path:?}")))?;
+    Ok(Some(value.to_owned()))
+}
+
+/// Get property value in bool. It's true iff the value is explicitly set to <1>, or to one of
+/// the strings "true" and "1", which are tolerated with a warning.
 /// It takes path as &str instead of &Path, because we don't want OsStr.
 fn get_fdt_prop_bool(fdt: &Fdt, path: &DPPath) -> Result<bool> {
     let (node_path, prop_name) = (&path.node_path, &path.prop_name);
     let node = match fdt.node(node_path) {
         Ok(Some(node)) => node,
-        Err(error) if error != FdtError::NotFound => {
-            Err(Error::msg(error)).with_context(|| format!("Failed to get node {node_path:?}"))?
-        }
+        Err(error) if error != FdtError::NotFound => return Err(error.into()),
         _ => return Ok(false),
     };
 
     match node.getprop_u32(prop_name) {
         Ok(Some(0)) => Ok(false),
         Ok(Some(1)) => Ok(true),
-        Ok(Some(_)) => Err(anyhow!("Invalid prop value {prop_name:?} in node {node_path:?}")),
-        Err(error) if error != FdtError::NotFound => {
-            Err(Error::msg(error)).with_context(|| format!("Failed to get prop {prop_name:?}"))
+        Ok(Some(_)) => Err(DebugConfigError::Malformed(format!(
+            "Invalid prop value {prop_name:?} in node {node_path:?}"
+        ))),
+        // Not a <u32>, but possibly a common mistake that we can still make sense of.
+        Err(FdtError::BadValue) => {
+            let value = node.getprop(prop_name)?.unwrap_or_default();
+            let Some(value) = parse_bool_string(value) else {
+                return Err(DebugConfigError::Malformed(format!(
+                    "Invalid prop value {prop_name:?} in node {node_path:?}"
+                )));
+            };
+            warn!(
+                "Prop {prop_name:?} in node {node_path:?} is a string, use <{}> instead",
+                u8::from(value)
+            );
+            Ok(value)
         }
+        Err(error) if error != FdtError::NotFound => Err(error.into()),
         _ => Ok(false),
     }
 }
 
+/// Returns whether the property is set, whatever its value.
+fn has_fdt_prop(fdt: &Fdt, path: &DPPath) -> Result<bool> {
+    let node = match fdt.node(&path.node_path) {
+        Ok(Some(node)) => node,
+        Err(error) if error != FdtError::NotFound => return Err(error.into()),
+        _ => return Ok(false),
+    };
+    match node.getprop(&path.prop_name) {
+        Ok(value) => Ok(value.is_some()),
+        Err(FdtError::NotFound) => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Get property value as a string of at most `max_len` bytes, or None if it isn't set.
+/// Oversized values are rejected before being copied, as overlays aren't trusted to be small.
+fn get_fdt_prop_string_bounded(fdt: &Fdt, path: &DPPath, max_len: usize) -> Result<Option<String>> {
+    let (node_path, prop_name) = (&path.node_path, &path.prop_name);
+    let node = match fdt.node(node_path) {
+        Ok(Some(node)) => node,
+        Err(error) if error != FdtError::NotFound => return Err(error.into()),
+        _ => return Ok(None),
+    };
+    let Some(value) = node.getprop(prop_name)? else {
+        return Ok(None);
+    };
+    let value = parse_string(value, max_len).map_err(|reason| {
+        DebugConfigError::Malformed(format!(
+            "{reason} for prop {prop_name:?} in node {node_path:?}"
+        ))
+    })?;
+    Ok(Some(value.to_owned()))
+}
+
+/// Parses a NUL-terminated UTF-8 string of at most `max_len` bytes, or explains why it isn't.
+fn parse_string(value: &[u8], max_len: usize) -> Result<&str, String> {
+    let value = value.strip_suffix(b"\0").ok_or("Missing NUL terminator")?;
+    if value.len() > max_len {
+        return Err(format!("Value of {} bytes exceeds {max_len}", value.len()));
+    }
+    std::str::from_utf8(value).map_err(|_| "Invalid UTF-8".to_owned())
+}
+
+/// Checks that a ramdump destination is an absolute path under one of the allowed roots.
+fn check_ramdump_destination(path: String) -> Result<PathBuf> {
+    let path = PathBuf::from(path);
+    let allowed = path.is_absolute()
+        && !path.components().any(|c| c == Component::ParentDir)
+        && RAMDUMP_DESTINATION_ROOTS.iter().any(|root| path.starts_with(root));
+    if !allowed {
+        return Err(DebugConfigError::Malformed(format!(
+            "Ramdump destination {path:?} isn't under any of {RAMDUMP_DESTINATION_ROOTS:?}"
+        )));
+    }
+    Ok(path)
+}
+
+/// Parses the strings "true", "false", "1" and "0", with or without a NUL terminator.
+fn parse_bool_string(value: &[u8]) -> Option<bool> {
+    match value.strip_suffix(b"\0").unwrap_or(value) {
+        b"true" | b"1" => Some(true),
+        b"false" | b"0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Returns the names of the fragments of `overlay` that are applied to a node referenced by
+/// phandle (`target`) rather than by path (`target-path`).
+fn phandle_targeted_fragments(overlay: &Fdt) -> Result<Vec<String>> {
+    let mut fragments = Vec::new();
+    for fragment in overlay.root().subnodes()? {
+        if fragment.getprop(cstr!("target"))?.is_some() {
+            fragments.push(fragment.name()?.to_string_lossy().into_owned());
+        }
+    }
+    Ok(fragments)
+}
+
 /// Fdt with owned vector.
 struct OwnedFdt {
     buffer: Vec<u8>,
 }
 
 impl OwnedFdt {
-    fn from_overlay_onto_new_fdt(overlay_file_path: &Path) -> Result<Self> {
-        let mut overlay_buf = match fs::read(overlay_file_path) {
-            Ok(fdt) => fdt,
-            Err(error) if error.kind() == ErrorKind::NotFound => Default::default(),
-            Err(error) => {
-                Err(error).with_context(|| format!("Failed to read {overlay_file_path:?}"))?
+    /// Applies the overlays in order onto an empty device tree, so that later overlays override
+    /// the values set by earlier ones. Missing overlays are skipped.
+    ///
+    /// Returns `DebugConfigError::NotFound` if none of the overlays exist.
+    fn from_overlays_onto_new_fdt(overlay_file_paths: &[&Path]) -> Result<Self> {
+        let mut overlays = Vec::with_capacity(overlay_file_paths.len());
+        for path in overlay_file_paths {
+            match fs::read(path) {
+                Ok(buf) => overlays.push((path, buf)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    warn!("Skipping missing debug policy overlay {path:?}");
+                }
+                Err(e) => return Err(e.into()),
             }
-        };
+        }
+        if overlays.is_empty() {
+            return Err(DebugConfigError::NotFound);
+        }
 
-        let overlay_buf_size = overlay_buf.len();
+        let overlay_buf_size: usize = overlays.iter().map(|(_, buf)| buf.len()).sum();
 
         let fdt_estimated_size = overlay_buf_size + DEVICE_TREE_EMPTY_TREE_SIZE_BYTES;
         let mut fdt_buf = vec![0_u8; fdt_estimated_size];
-        let fdt = Fdt::create_empty_tree(fdt_buf.as_mut_slice())
-            .map_err(Error::msg)
-            .context("Failed to create an empty device tree")?;
+        let fdt = Fdt::create_empty_tree(fdt_buf.as_mut_slice())?;
 
-        if !overlay_buf.is_empty() {
-            let overlay_fdt = Fdt::from_mut_slice(overlay_buf.as_mut_slice())
-                .map_err(Error::msg)
-                .with_context(|| "Malformed {overlay_file_path:?}")?;
+        for (overlay_file_path, mut overlay_buf) in overlays {
+            if overlay_buf.is_empty() {
+                continue;
+            }
+            let overlay_fdt = Fdt::from_mut_slice(overlay_buf.as_mut_slice()).map_err(|e| {
+                DebugConfigError::Malformed(format!("{overlay_file_path:?} isn't a DTBO: {e}"))
+            })?;
+
+            for fragment in phandle_targeted_fragments(overlay_fdt)? {
+                warn!(
+                    "Fragment {fragment} of {overlay_file_path:?} targets a phandle, which can't \
+                    resolve against the empty device tree of debug policies. Use target-path."
+                );
+            }
 
             // SAFETY: Return immediately if error happens. Damaged fdt_buf and fdt are discarded.
             unsafe {
-                fdt.apply_overlay(overlay_fdt).map_err(Error::msg).with_context(|| {
-                    "Failed to overlay {overlay_file_path:?} onto empty device tree"
+                fdt.apply_overlay(overlay_fdt).map_err(|e| {
+                    DebugConfigError::Malformed(format!(
+                        "Failed to overlay {overlay_file_path:?} onto debug policy device tree: {e}"
+                    ))
                 })?;
             }
         }
@@ -149,23 +350,72 @@ impl OwnedFdt {
 }
 
 /// Debug configurations for debug policy.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct DebugPolicy {
     log: bool,
     ramdump: bool,
     adb: bool,
+    serial_shell: bool,
+    ramdump_destination: Option<PathBuf>,
 }
 
 impl DebugPolicy {
     /// Build from the passed DTBO path.
+    ///
+    /// Returns `DebugConfigError::NotFound` if there is no file at `path`.
     pub fn from_overlay(path: &Path) -> Result<Self> {
-        let owned_fdt = OwnedFdt::from_overlay_onto_new_fdt(path)?;
+        Self::from_overlays(&[path])
+    }
+
+    /// Build from the passed DTBO paths, applied in order so that later ones take precedence.
+    ///
+    /// Missing DTBOs are skipped, and `DebugConfigError::NotFound` is returned if all are.
+    pub fn from_overlays(paths: &[&Path]) -> Result<Self> {
+        let owned_fdt = OwnedFdt::from_overlays_onto_new_fdt(paths)?;
+        Self::from_fdt(owned_fdt.as_fdt())
+    }
+
+    /// Build from the passed DTBO paths, like `from_overlays()`, but fail closed: each of the
+    /// `required` properties (e.g. "/avf/guest/microdroid/adb") must be set by the overlays,
+    /// instead of being taken as false.
+    ///
+    /// Returns `DebugConfigError::Malformed` listing the required properties which are missing.
+    pub fn from_overlays_strict(paths: &[&Path], required: &[&str]) -> Result<Self> {
+        let owned_fdt = OwnedFdt::from_overlays_onto_new_fdt(paths)?;
         let fdt = owned_fdt.as_fdt();
 
+        let mut missing = vec![];
+        for prop_path in required {
+            let Some((node_path, prop_name)) = prop_path.rsplit_once('/') else {
+                return Err(DebugConfigError::Malformed(format!(
+                    "Invalid required property path {prop_path:?}"
+                )));
+            };
+            if !has_fdt_prop(fdt, &DPPath::new(node_path, prop_name)?)? {
+                missing.push(*prop_path);
+            }
+        }
+        if !missing.is_empty() {
+            return Err(DebugConfigError::Malformed(format!(
+                "Missing required properties {missing:?}"
+            )));
+        }
+        Self::from_fdt(fdt)
+    }
+
+    fn from_fdt(fdt: &Fdt) -> Result<Self> {
         Ok(Self {
             log: get_fdt_prop_bool(fdt, &DP_LOG_PATH)?,
             ramdump: get_fdt_prop_bool(fdt, &DP_RAMDUMP_PATH)?,
             adb: get_fdt_prop_bool(fdt, &DP_ADB_PATH)?,
+            serial_shell: get_fdt_prop_bool(fdt, &DP_SERIAL_SHELL_PATH)?,
+            ramdump_destination: get_fdt_prop_string_bounded(
+                fdt,
+                &DP_RAMDUMP_DESTINATION_PATH,
+                RAMDUMP_DESTINATION_MAX_LEN,
+            )?
+            .map(check_ramdump_destination)
+            .transpose()?,
         })
     }
 
@@ -175,8 +425,177 @@ impl DebugPolicy {
             log: get_debug_policy_bool(&DP_LOG_PATH.to_path())?,
             ramdump: get_debug_policy_bool(&DP_RAMDUMP_PATH.to_path())?,
             adb: get_debug_policy_bool(&DP_ADB_PATH.to_path())?,
+            serial_shell: get_debug_policy_bool(&DP_SERIAL_SHELL_PATH.to_path())?,
+            ramdump_destination: get_debug_policy_string(
+                &DP_RAMDUMP_DESTINATION_PATH.to_path(),
+                RAMDUMP_DESTINATION_MAX_LEN,
+            )?
+            .map(check_ramdump_destination)
+            .transpose()?,
         })
     }
+
+    /// Returns the directory where the policy routes ramdumps, if any, in which case callers
+    /// should use it instead of their default one.
+    #[allow(dead_code)] // TODO: Route ramdumps there once virtmgr may write and clean them up.
+    pub fn ramdump_destination(&self) -> Option<PathBuf> {
+        self.ramdump_destination.clone()
+    }
+
+    /// Returns the (name, old value, new value) of each field that differs in `other`.
+    pub fn diff(&self, other: &DebugPolicy) -> Vec<(&'static str, bool, bool)> {
+        [
+            ("log", self.log, other.log),
+            ("ramdump", self.ramdump, other.ramdump),
+            ("adb", self.adb, other.adb),
+            ("serial_shell", self.serial_shell, other.serial_shell),
+        ]
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .collect()
+    }
+
+    /// Restricts the policy to the debug features also allowed by the app, if any.
+    fn restrict_to(self, app_policy: Option<&AppDebugPolicy>) -> Self {
+        let Some(app_policy) = app_policy else {
+            return self;
+        };
+        Self {
+            log: self.log && app_policy.log,
+            ramdump: self.ramdump && app_policy.ramdump,
+            adb: self.adb && app_policy.adb,
+            serial_shell: self.serial_shell && app_policy.serial_shell,
+            ramdump_destination: self.ramdump_destination,
+        }
+    }
+}
+
+/// A way in which a debug policy overlay doesn't follow the schema of debug policies.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SchemaViolation {
+    /// The overlay sets a property, given by its path, which debug policies don't have.
+    UnknownProperty(String),
+    /// The overlay sets a property, given by its path, to a value of the wrong type, e.g. a boolean
+    /// property to something other than a <u32>.
+    WrongType(String),
+    /// The overlay sets a boolean property, given by its path, to a <u32> other than 0 and 1.
+    InvalidValue(String),
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownProperty(path) => write!(f, "Unknown property {path}"),
+            Self::WrongType(path) => write!(f, "Property {path} has the wrong type"),
+            Self::InvalidValue(path) => write!(f, "Property {path} is neither <0> nor <1>"),
+        }
+    }
+}
+
+/// Checks that the DTBO at `path` only sets the known `/avf/guest` properties, with the types
+/// and values the debug policy expects, for presubmit checks of debug policy overlays.
+///
+/// Unlike `DebugPolicy::from_overlay()`, which ignores them, returns all the violations found.
+pub fn check_debug_policy_schema(path: &Path) -> Result<Vec<SchemaViolation>> {
+    let owned_fdt = OwnedFdt::from_overlays_onto_new_fdt(&[path])?;
+    let mut violations = vec![];
+    if let Some(guest) = owned_fdt.as_fdt().node(cstr!("/avf/guest"))? {
+        check_node_schema(&guest, "/avf/guest", &mut violations)?;
+    }
+    Ok(violations)
+}
+
+fn check_node_schema(
+    node: &FdtNode,
+    node_path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) -> Result<()> {
+    for prop in node.properties()? {
+        let prop_name = prop.name()?.to_string_lossy();
+        let prop_path = format!("{node_path}/{prop_name}");
+        let ramdump_destination = &DP_RAMDUMP_DESTINATION_PATH;
+        if ramdump_destination.node_path.as_bytes() == node_path.as_bytes()
+            && ramdump_destination.prop_name.as_bytes() == prop_name.as_bytes()
+        {
+            if parse_string(prop.value()?, RAMDUMP_DESTINATION_MAX_LEN).is_err() {
+                violations.push(SchemaViolation::WrongType(prop_path));
+            }
+            continue;
+        }
+        let known = known_debug_policy_props().iter().any(|dp_path| {
+            dp_path.node_path.as_bytes() == node_path.as_bytes()
+                && dp_path.prop_name.as_bytes() == prop_name.as_bytes()
+        });
+        if !known {
+            violations.push(SchemaViolation::UnknownProperty(prop_path));
+            continue;
+        }
+        match prop.value()? {
+            [0, 0, 0, 0 | 1] => {}
+            [_, _, _, _] => violations.push(SchemaViolation::InvalidValue(prop_path)),
+            _ => violations.push(SchemaViolation::WrongType(prop_path)),
+        }
+    }
+    for subnode in node.subnodes()? {
+        let subnode_path = format!("{node_path}/{}", subnode.name()?.to_string_lossy());
+        check_node_schema(&subnode, &subnode_path, violations)?;
+    }
+    Ok(())
+}
+
+/// Splits the colon-separated list of overlays of the debug policy sysprop.
+fn overlay_paths(paths: &str) -> Vec<&Path> {
+    paths.split(':').filter(|path| !path.is_empty()).map(Path::new).collect()
+}
+
+/// Reads the sysprop listing the properties which the custom overlays must set, for deployments
+/// where a missing one should disable the debug policy rather than be taken as false.
+fn read_required_props() -> String {
+    system_properties::read(REQUIRED_DEBUG_POLICY_PROPS_SYSPROP)
+        .unwrap_or_else(|e| {
+            warn!("Failed to read sysprop {REQUIRED_DEBUG_POLICY_PROPS_SYSPROP}: {e}");
+            None
+        })
+        .unwrap_or_default()
+}
+
+/// Splits the comma-separated list of required properties of the debug policy sysprop.
+fn required_props(props: &str) -> Vec<&str> {
+    props.split(',').map(str::trim).filter(|prop| !prop.is_empty()).collect()
+}
+
+/// Warns about the schema violations of each of `paths`, which don't stop it from being loaded.
+fn warn_schema_violations(paths: &[&Path]) {
+    for path in paths {
+        match check_debug_policy_schema(path) {
+            Ok(violations) => {
+                for violation in violations {
+                    warn!("Debug policy overlay {path:?} doesn't follow the schema: {violation}");
+                }
+            }
+            Err(DebugConfigError::NotFound) => {}
+            Err(err) => warn!("Failed to check the schema of debug policy overlay {path:?}: {err}"),
+        }
+    }
+}
+
+fn get_app_debug_policy(config: &VirtualMachineConfig) -> Option<&AppDebugPolicy> {
+    match config {
+        VirtualMachineConfig::AppConfig(config) => config.debugPolicy.as_ref(),
+        VirtualMachineConfig::RawConfig(_) => None,
+    }
+}
+
+/// Where the debug policy of a VM came from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DebugPolicySource {
+    /// No debug policy could be loaded, so it's disabled.
+    #[default]
+    Disabled,
+    /// The overlays set with the `hypervisor.virtualizationmanager.debug_policy.path` sysprop.
+    CustomOverlay,
+    /// The /avf/guest subtree of the host DT.
+    HostOs,
 }
 
 /// Debug configurations for both debug level and debug policy
@@ -184,20 +603,35 @@ impl DebugPolicy {
 pub struct DebugConfig {
     pub debug_level: DebugLevel,
     debug_policy: DebugPolicy,
+    debug_policy_source: DebugPolicySource,
 }
 
 impl DebugConfig {
     pub fn new(config: &VirtualMachineConfig) -> Self {
         let debug_level = get_debug_level(config).unwrap_or(DebugLevel::NONE);
-        let debug_policy = Self::get_debug_policy().unwrap_or_else(|| {
+        let (debug_policy, debug_policy_source) = Self::get_debug_policy().unwrap_or_else(|| {
             info!("Debug policy is disabled");
             Default::default()
         });
+        let app_policy = get_app_debug_policy(config);
+        if let Some(app_policy) = app_policy {
+            info!("Restricting debug policy with the one of the VM config: {app_policy:?}");
+        }
+        let device_policy = debug_policy;
+        let debug_policy = device_policy.clone().restrict_to(app_policy);
+        for (name, old, new) in device_policy.diff(&debug_policy) {
+            info!("Debug policy {name} changed from {old} to {new} by the VM config");
+        }
 
-        Self { debug_level, debug_policy }
+        let debug_config = Self { debug_level, debug_policy, debug_policy_source };
+        info!("Debug config: {}", debug_config.debug_summary());
+        for decision in debug_config.debug_decisions() {
+            debug!("Debug decision: {decision}");
+        }
+        debug_config
     }
 
-    fn get_debug_policy() -> Option<DebugPolicy> {
+    fn get_debug_policy() -> Option<(DebugPolicy, DebugPolicySource)> {
         let dp_sysprop = system_properties::read(CUSTOM_DEBUG_POLICY_OVERLAY_SYSPROP);
         let custom_dp = dp_sysprop.unwrap_or_else(|e| {
             warn!("Failed to read sysprop {CUSTOM_DEBUG_POLICY_OVERLAY_SYSPROP}: {e}");
@@ -205,20 +639,35 @@ impl DebugConfig {
         });
 
         match custom_dp {
-            Some(path) if !path.is_empty() => match DebugPolicy::from_overlay(Path::new(&path)) {
-                Ok(dp) => {
-                    info!("Loaded custom debug policy overlay {path}: {dp:?}");
-                    Some(dp)
-                }
-                Err(err) => {
-                    warn!("Failed to load custom debug policy overlay {path}: {err:?}");
-                    None
+            Some(path) if !path.is_empty() => {
+                let paths = overlay_paths(&path);
+                warn_schema_violations(&paths);
+                let required_props_sysprop = read_required_props();
+                let required = required_props(&required_props_sysprop);
+                let dp = if required.is_empty() {
+                    DebugPolicy::from_overlays(&paths)
+                } else {
+                    DebugPolicy::from_overlays_strict(&paths, &required)
+                };
+                match dp {
+                    Ok(dp) => {
+                        info!("Loaded custom debug policy overlay {path}: {dp:?}");
+                        Some((dp, DebugPolicySource::CustomOverlay))
+                    }
+                    Err(DebugConfigError::NotFound) => {
+                        info!("Custom debug policy overlay {path} not found, using default policy");
+                        Some((Default::default(), DebugPolicySource::CustomOverlay))
+                    }
+                    Err(err) => {
+                        warn!("Failed to load custom debug policy overlay {path}: {err:?}");
+                        None
+                    }
                 }
-            },
+            }
             _ => match DebugPolicy::from_host() {
                 Ok(dp) => {
                     info!("Loaded debug policy from host OS: {dp:?}");
-                    Some(dp)
+                    Some((dp, DebugPolicySource::HostOs))
                 }
                 Err(err) => {
                     warn!("Failed to load debug policy from host OS: {err:?}");
@@ -228,26 +677,113 @@ impl DebugConfig {
         }
     }
 
+    /// Summarizes the debug level and policy in a single line, e.g.
+    /// `level=FULL policy[log=1 ramdump=0 adb=1 serial_shell=0] source=HostOs`.
+    pub fn debug_summary(&self) -> String {
+        let level = match self.debug_level {
+            DebugLevel::NONE => "NONE".to_owned(),
+            DebugLevel::FULL => "FULL".to_owned(),
+            level => format!("{level:?}"),
+        };
+        let DebugPolicy { log, ramdump, adb, serial_shell, .. } = self.debug_policy;
+        format!(
+            "level={level} policy[log={} ramdump={} adb={} serial_shell={}] source={:?}",
+            u8::from(log),
+            u8::from(ramdump),
+            u8::from(adb),
+            u8::from(serial_shell),
+            self.debug_policy_source,
+        )
+    }
+
     #[cfg(test)]
     /// Creates a new DebugConfig with debug level. Only use this for test purpose.
     pub(crate) fn new_with_debug_level(debug_level: DebugLevel) -> Self {
         Self { debug_level, ..Default::default() }
     }
 
+    /// Explains each debug decision with the inputs that led to it, e.g.
+    /// `ramdump=true because policy.ramdump`, so that it can be told why a VM had a debug feature.
+    pub fn debug_decisions(&self) -> Vec<String> {
+        let serial_shell = if self.should_start_serial_shell() {
+            "serial_shell=true because policy.serial_shell and console_output".to_owned()
+        } else {
+            "serial_shell=false: requires policy.serial_shell and console_output".to_owned()
+        };
+        vec![
+            explain_decision("kernel_log", &self.kernel_log_inputs()),
+            explain_decision("console_output", &self.console_output_inputs()),
+            serial_shell,
+            explain_decision("debug_apexes", &self.debug_apexes_inputs()),
+            explain_decision("ramdump", &self.ramdump_inputs()),
+        ]
+    }
+
+    fn is_debuggable(&self) -> bool {
+        self.debug_level != DebugLevel::NONE
+    }
+
+    fn kernel_log_inputs(&self) -> [DecisionInput; 2] {
+        [("debug_level", self.is_debuggable()), ("policy.log", self.debug_policy.log)]
+    }
+
+    fn console_output_inputs(&self) -> [DecisionInput; 3] {
+        let [level, log] = self.kernel_log_inputs();
+        [level, log, ("policy.adb", self.debug_policy.adb)]
+    }
+
+    fn debug_apexes_inputs(&self) -> [DecisionInput; 2] {
+        [("debug_level", self.is_debuggable()), ("policy.adb", self.debug_policy.adb)]
+    }
+
+    fn ramdump_inputs(&self) -> [DecisionInput; 2] {
+        [("debug_level", self.is_debuggable()), ("policy.ramdump", self.debug_policy.ramdump)]
+    }
+
+    /// Get whether the kernel log of the VM should be captured through its console.
+    #[allow(dead_code)] // TODO: Gate console=hvc0 on it once adb-only VMs get no console pipe.
+    pub fn should_capture_kernel_log(&self) -> bool {
+        any_input(&self.kernel_log_inputs())
+    }
+
     /// Get whether console output should be configred for VM to leave console and adb log.
     /// Caller should create pipe and prepare for receiving VM log with it.
     pub fn should_prepare_console_output(&self) -> bool {
-        self.debug_level != DebugLevel::NONE || self.debug_policy.log || self.debug_policy.adb
+        any_input(&self.console_output_inputs())
+    }
+
+    /// Get whether a shell should be started on the serial console. It requires the console
+    /// output to be prepared, so the policy alone isn't enough.
+    pub fn should_start_serial_shell(&self) -> bool {
+        self.debug_policy.serial_shell && self.should_prepare_console_output()
     }
 
     /// Get whether debug apexes (MICRODROID_REQUIRED_APEXES_DEBUG) are required.
     pub fn should_include_debug_apexes(&self) -> bool {
-        self.debug_level != DebugLevel::NONE || self.debug_policy.adb
+        any_input(&self.debug_apexes_inputs())
     }
 
     /// Decision to support ramdump
     pub fn is_ramdump_needed(&self) -> bool {
-        self.debug_level != DebugLevel::NONE || self.debug_policy.ramdump
+        any_input(&self.ramdump_inputs())
+    }
+}
+
+/// An input of a debug decision: its name and whether it asks for the debug feature.
+type DecisionInput = (&'static str, bool);
+
+/// Returns whether any of `inputs` asks for the debug feature.
+fn any_input(inputs: &[DecisionInput]) -> bool {
+    inputs.iter().any(|&(_, on)| on)
+}
+
+fn explain_decision(name: &str, inputs: &[DecisionInput]) -> String {
+    let on: Vec<_> = inputs.iter().filter(|&&(_, on)| on).map(|&(input, _)| input).collect();
+    if on.is_empty() {
+        let all: Vec<_> = inputs.iter().map(|&(input, _)| input).collect();
+        format!("{name}=false: none of {}", all.join(", "))
+    } else {
+        format!("{name}=true because {}", on.join(", "))
     }
 }
 
@@ -304,9 +840,279 @@ mod tests {
     }
 
     #[test]
-    fn test_invalid_sysprop_disables_debug_policy() -> Result<()> {
+    fn test_later_overlay_overrides_earlier_one() -> Result<()> {
+        let with_adb = Path::new("avf_debug_policy_with_adb.dtbo");
+        let without_adb = Path::new("avf_debug_policy_without_adb.dtbo");
+
+        assert!(!DebugPolicy::from_overlays(&[with_adb, without_adb])?.adb);
+        assert!(DebugPolicy::from_overlays(&[without_adb, with_adb])?.adb);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlays_are_merged() -> Result<()> {
+        let with_adb = Path::new("avf_debug_policy_with_adb.dtbo");
+        let with_ramdump = Path::new("avf_debug_policy_with_ramdump.dtbo");
+
+        let debug_policy = DebugPolicy::from_overlays(&[with_ramdump, with_adb])?;
+
+        assert!(debug_policy.ramdump);
+        assert!(debug_policy.adb);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_overlays_are_skipped() -> Result<()> {
+        let missing = Path::new("/a/does/not/exist/path.dtbo");
+        let with_adb = Path::new("avf_debug_policy_with_adb.dtbo");
+
+        assert!(DebugPolicy::from_overlays(&[missing, with_adb])?.adb);
+
+        let result = DebugPolicy::from_overlays(&[missing, missing]);
+        assert!(matches!(result, Err(DebugConfigError::NotFound)), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_overlay_with_required_properties() -> Result<()> {
+        let required = ["/avf/guest/common/ramdump", "/avf/guest/microdroid/adb"];
+
+        let debug_policy = DebugPolicy::from_overlays_strict(
+            &["avf_debug_policy_with_ramdump.dtbo".as_ref()],
+            &required,
+        )?;
+
+        assert!(!debug_policy.log);
+        assert!(debug_policy.ramdump);
+        assert!(debug_policy.adb);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_overlay_without_required_properties() -> Result<()> {
+        let path = Path::new("avf_debug_policy_with_adb.dtbo");
+        let required = ["/avf/guest/common/log", "/avf/guest/microdroid/adb"];
+
+        let result = DebugPolicy::from_overlays_strict(&[path], &required);
+
+        assert!(
+            matches!(&result, Err(DebugConfigError::Malformed(reason))
+                if reason.contains("/avf/guest/common/log") && !reason.contains("adb")),
+            "{result:?}"
+        );
+        // The lenient mode takes the missing property as false.
+        assert!(!DebugPolicy::from_overlay(path)?.log);
+
+        let result = DebugPolicy::from_overlays_strict(&[path], &["adb"]);
+        assert!(matches!(result, Err(DebugConfigError::Malformed(_))), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conformant_overlays_follow_schema() -> Result<()> {
+        for path in [
+            "avf_debug_policy_with_ramdump.dtbo",
+            "avf_debug_policy_without_adb.dtbo",
+            "avf_debug_policy_with_serial_shell.dtbo",
+        ] {
+            assert_eq!(check_debug_policy_schema(path.as_ref())?, vec![], "{path}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_violations_are_listed() -> Result<()> {
+        let path = Path::new("avf_debug_policy_with_schema_violations.dtbo");
+
+        let mut violations = check_debug_policy_schema(path)?;
+        violations.sort_by_key(|violation| violation.to_string());
+
+        assert_eq!(
+            violations,
+            vec![
+                SchemaViolation::WrongType("/avf/guest/common/log".to_owned()),
+                SchemaViolation::InvalidValue("/avf/guest/common/ramdump".to_owned()),
+                SchemaViolation::UnknownProperty("/avf/guest/microdroid/unknown".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_paths() {
+        assert_eq!(overlay_paths("/a.dtbo"), vec![Path::new("/a.dtbo")]);
+        assert_eq!(
+            overlay_paths("/a.dtbo::/b.dtbo:"),
+            vec![Path::new("/a.dtbo"), Path::new("/b.dtbo")]
+        );
+    }
+
+    #[test]
+    fn test_required_props() {
+        assert_eq!(required_props(""), Vec::<&str>::new());
+        assert_eq!(
+            required_props("/avf/guest/common/log, /avf/guest/microdroid/adb,"),
+            vec!["/avf/guest/common/log", "/avf/guest/microdroid/adb"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_sysprop_is_not_found() -> Result<()> {
+        let result = DebugPolicy::from_overlay("/a/does/not/exist/path.dtbo".as_ref());
+
+        assert!(matches!(result, Err(DebugConfigError::NotFound)), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_dtbo_overlay_is_malformed() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), b"not a device tree")?;
+
+        let result = DebugPolicy::from_overlay(file.path());
+
+        assert!(matches!(result, Err(DebugConfigError::Malformed(_))), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_property_with_bad_size_is_malformed() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), [0, 1])?;
+
+        let result = get_debug_policy_bool(file.path());
+
+        assert!(matches!(result, Err(DebugConfigError::Malformed(_))), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_property_with_invalid_value_is_malformed() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), 2u32.to_be_bytes())?;
+
+        let result = get_debug_policy_bool(file.path());
+
+        assert!(matches!(result, Err(DebugConfigError::Malformed(_))), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_host_property_is_false() -> Result<()> {
+        assert!(!get_debug_policy_bool("/a/does/not/exist/log".as_ref())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_host_property_is_io_error() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+
+        let result = get_debug_policy_bool(dir.path());
+
+        assert!(matches!(result, Err(DebugConfigError::Io(_))), "{result:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_avf_debug_policy_with_serial_shell() -> Result<()> {
+        let debug_policy =
+            DebugPolicy::from_overlay("avf_debug_policy_with_serial_shell.dtbo".as_ref()).unwrap();
+
+        assert!(!debug_policy.log);
+        assert!(!debug_policy.ramdump);
+        assert!(!debug_policy.adb);
+        assert!(debug_policy.serial_shell);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_avf_debug_policy_without_serial_shell() -> Result<()> {
+        let debug_policy =
+            DebugPolicy::from_overlay("avf_debug_policy_without_serial_shell.dtbo".as_ref())
+                .unwrap();
+
+        assert!(!debug_policy.log);
+        assert!(!debug_policy.ramdump);
+        assert!(!debug_policy.adb);
+        assert!(!debug_policy.serial_shell);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serial_shell_requires_console_output() -> Result<()> {
         let debug_policy =
-            DebugPolicy::from_overlay("/a/does/not/exist/path.dtbo".as_ref()).unwrap();
+            DebugPolicy::from_overlay("avf_debug_policy_with_serial_shell.dtbo".as_ref())?;
+        let config = DebugConfig { debug_policy: debug_policy.clone(), ..Default::default() };
+        assert!(!config.should_prepare_console_output());
+        assert!(!config.should_start_serial_shell());
+
+        let debug_policy = DebugPolicy { log: true, ..debug_policy };
+        let config = DebugConfig { debug_policy, ..Default::default() };
+        assert!(config.should_prepare_console_output());
+        assert!(config.should_start_serial_shell());
+
+        let config = DebugConfig { debug_level: DebugLevel::FULL, ..Default::default() };
+        assert!(config.should_prepare_console_output());
+        assert!(!config.should_start_serial_shell());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fdt_error_is_fdt_failure() {
+        let error = DebugConfigError::from(FdtError::NoSpace);
+
+        assert!(matches!(error, DebugConfigError::FdtFailure(FdtError::NoSpace)));
+    }
+
+    #[test]
+    fn test_app_debug_policy_restricts_device_policy() -> Result<()> {
+        let device_policy =
+            DebugPolicy::from_overlay("avf_debug_policy_with_ramdump.dtbo".as_ref()).unwrap();
+        let app_policy = AppDebugPolicy { ramdump: false, ..Default::default() };
+
+        let debug_policy = device_policy.restrict_to(Some(&app_policy));
+
+        assert!(!debug_policy.log);
+        assert!(!debug_policy.ramdump);
+        assert!(debug_policy.adb);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_debug_policy_restricts_serial_shell() -> Result<()> {
+        let device_policy =
+            DebugPolicy::from_overlay("avf_debug_policy_with_serial_shell.dtbo".as_ref()).unwrap();
+        let app_policy = AppDebugPolicy { serial_shell: false, ..Default::default() };
+
+        let debug_policy = device_policy.restrict_to(Some(&app_policy));
+
+        assert!(!debug_policy.serial_shell);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_debug_policy_cannot_expand_device_policy() -> Result<()> {
+        let device_policy =
+            DebugPolicy::from_overlay("avf_debug_policy_without_adb.dtbo".as_ref()).unwrap();
+        let app_policy = AppDebugPolicy { log: true, ramdump: true, adb: true, serial_shell: true };
+
+        let debug_policy = device_policy.restrict_to(Some(&app_policy));
 
         assert!(!debug_policy.log);
         assert!(!debug_policy.ramdump);
@@ -315,6 +1121,91 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_missing_app_debug_policy_keeps_device_policy() -> Result<()> {
+        let device_policy =
+            DebugPolicy::from_overlay("avf_debug_policy_with_ramdump.dtbo".as_ref()).unwrap();
+
+        let debug_policy = device_policy.restrict_to(None);
+
+        assert!(!debug_policy.log);
+        assert!(debug_policy.ramdump);
+        assert!(debug_policy.adb);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_lists_changed_fields() {
+        let old = DebugPolicy {
+            log: true,
+            ramdump: true,
+            adb: false,
+            serial_shell: false,
+            ..Default::default()
+        };
+        let new = DebugPolicy {
+            log: true,
+            ramdump: false,
+            adb: true,
+            serial_shell: false,
+            ..Default::default()
+        };
+
+        assert_eq!(old.diff(&new), vec![("ramdump", true, false), ("adb", false, true)]);
+        assert_eq!(new.diff(&old), vec![("ramdump", false, true), ("adb", true, false)]);
+    }
+
+    #[test]
+    fn test_diff_is_empty_for_same_policy() {
+        let policy = DebugPolicy {
+            log: true,
+            ramdump: false,
+            adb: true,
+            serial_shell: false,
+            ..Default::default()
+        };
+
+        assert!(policy.diff(&policy).is_empty());
+    }
+
+    #[test]
+    fn test_debug_summary() -> Result<()> {
+        let config = DebugConfig::new_with_debug_level(DebugLevel::NONE);
+        assert_eq!(
+            config.debug_summary(),
+            "level=NONE policy[log=0 ramdump=0 adb=0 serial_shell=0] source=Disabled"
+        );
+
+        let config = DebugConfig {
+            debug_level: DebugLevel::FULL,
+            debug_policy: DebugPolicy {
+                log: true,
+                ramdump: false,
+                adb: true,
+                serial_shell: false,
+                ..Default::default()
+            },
+            debug_policy_source: DebugPolicySource::HostOs,
+        };
+        assert_eq!(
+            config.debug_summary(),
+            "level=FULL policy[log=1 ramdump=0 adb=1 serial_shell=0] source=HostOs"
+        );
+
+        let config = DebugConfig {
+            debug_level: DebugLevel::NONE,
+            debug_policy: DebugPolicy::from_overlay("avf_debug_policy_with_ramdump.dtbo".as_ref())?,
+            debug_policy_source: DebugPolicySource::CustomOverlay,
+        };
+        assert_eq!(
+            config.debug_summary(),
+            "level=NONE policy[log=0 ramdump=1 adb=1 serial_shell=0] source=CustomOverlay"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_new_with_debug_level() -> Result<()> {
         assert_eq!(
@@ -328,4 +1219,243 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_debug_decisions() {
+        let debug_config = DebugConfig {
+            debug_level: DebugLevel::NONE,
+            debug_policy: DebugPolicy {
+                log: true,
+                ramdump: true,
+                adb: false,
+                serial_shell: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            debug_config.debug_decisions(),
+            vec![
+                "kernel_log=true because policy.log",
+                "console_output=true because policy.log",
+                "serial_shell=true because policy.serial_shell and console_output",
+                "debug_apexes=false: none of debug_level, policy.adb",
+                "ramdump=true because policy.ramdump",
+            ]
+        );
+
+        let debug_config = DebugConfig {
+            debug_level: DebugLevel::FULL,
+            debug_policy: DebugPolicy { adb: true, ..Default::default() },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            debug_config.debug_decisions(),
+            vec![
+                "kernel_log=true because debug_level",
+                "console_output=true because debug_level, policy.adb",
+                "serial_shell=false: requires policy.serial_shell and console_output",
+                "debug_apexes=true because debug_level, policy.adb",
+                "ramdump=true because debug_level",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_console_output_decisions() {
+        // (debug level, log, adb) -> (capture kernel log, prepare console output)
+        let cases = [
+            ((DebugLevel::NONE, false, false), (false, false)),
+            ((DebugLevel::NONE, false, true), (false, true)),
+            ((DebugLevel::NONE, true, false), (true, true)),
+            ((DebugLevel::NONE, true, true), (true, true)),
+            ((DebugLevel::FULL, false, false), (true, true)),
+            ((DebugLevel::FULL, false, true), (true, true)),
+            ((DebugLevel::FULL, true, false), (true, true)),
+            ((DebugLevel::FULL, true, true), (true, true)),
+        ];
+        for ((debug_level, log, adb), expected) in cases {
+            let config = DebugConfig {
+                debug_level,
+                debug_policy: DebugPolicy {
+                    log,
+                    ramdump: false,
+                    adb,
+                    serial_shell: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let actual =
+                (config.should_capture_kernel_log(), config.should_prepare_console_output());
+            assert_eq!(actual, expected, "level={debug_level:?} log={log} adb={adb}");
+        }
+    }
+
+    fn get_log_prop_bool(value: &[u8]) -> Result<bool> {
+        let mut buf = vec![0_u8; 1024];
+        let fdt = Fdt::create_empty_tree(&mut buf)?;
+        let mut node = fdt
+            .root_mut()
+            .add_subnode(cstr!("avf"))?
+            .add_subnode(cstr!("guest"))?
+            .add_subnode(cstr!("common"))?;
+        node.setprop(cstr!("log"), value)?;
+
+        get_fdt_prop_bool(fdt, &DP_LOG_PATH)
+    }
+
+    #[test]
+    fn test_fdt_prop_bool_u32() -> Result<()> {
+        assert!(get_log_prop_bool(&1u32.to_be_bytes())?);
+        assert!(!get_log_prop_bool(&0u32.to_be_bytes())?);
+        assert!(matches!(
+            get_log_prop_bool(&2u32.to_be_bytes()),
+            Err(DebugConfigError::Malformed(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fdt_prop_bool_strings() -> Result<()> {
+        assert!(get_log_prop_bool(b"true\0")?);
+        assert!(get_log_prop_bool(b"1\0")?);
+        assert!(!get_log_prop_bool(b"false\0")?);
+        assert!(!get_log_prop_bool(b"0\0")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fdt_prop_bool_invalid_string() -> Result<()> {
+        assert!(matches!(get_log_prop_bool(b"yes\0"), Err(DebugConfigError::Malformed(_))));
+        assert!(matches!(get_log_prop_bool(b"TRUE\0"), Err(DebugConfigError::Malformed(_))));
+
+        Ok(())
+    }
+
+    fn get_test_prop_string(value: &[u8], max_len: usize) -> Result<Option<String>> {
+        let mut buf = vec![0_u8; 1024];
+        let fdt = Fdt::create_empty_tree(&mut buf)?;
+        let mut node = fdt
+            .root_mut()
+            .add_subnode(cstr!("avf"))?
+            .add_subnode(cstr!("guest"))?
+            .add_subnode(cstr!("common"))?;
+        node.setprop(cstr!("test_string"), value)?;
+
+        get_fdt_prop_string_bounded(fdt, &DPPath::new("/avf/guest/common", "test_string")?, max_len)
+    }
+
+    #[test]
+    fn test_fdt_prop_string_within_bound() -> Result<()> {
+        assert_eq!(get_test_prop_string(b"console=hvc0\0", 12)?.as_deref(), Some("console=hvc0"));
+        assert_eq!(get_test_prop_string(b"\0", 0)?.as_deref(), Some(""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fdt_prop_string_over_bound() {
+        assert!(matches!(
+            get_test_prop_string(b"console=hvc0\0", 11),
+            Err(DebugConfigError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_fdt_prop_string_invalid() {
+        assert!(matches!(
+            get_test_prop_string(b"\xff\xfe\0", 16),
+            Err(DebugConfigError::Malformed(_))
+        ));
+        assert!(matches!(
+            get_test_prop_string(b"no terminator", 16),
+            Err(DebugConfigError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_fdt_prop_string_missing() -> Result<()> {
+        let mut buf = vec![0_u8; 1024];
+        let fdt = Fdt::create_empty_tree(&mut buf)?;
+        let path = DPPath::new("/avf/guest/common", "test_string")?;
+
+        assert_eq!(get_fdt_prop_string_bounded(fdt, &path, 16)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_phandle_targeted_fragments() -> Result<()> {
+        let mut buf = vec![0_u8; 1024];
+        let overlay = Fdt::create_empty_tree(&mut buf)?;
+        let mut fragment = overlay.root_mut().add_subnode(cstr!("fragment@0"))?;
+        fragment.setprop(cstr!("target"), &1u32.to_be_bytes())?;
+        fragment.add_subnode(cstr!("__overlay__"))?;
+        let mut fragment = overlay.root_mut().add_subnode(cstr!("fragment@1"))?;
+        fragment.setprop(cstr!("target-path"), b"/\0")?;
+        fragment.add_subnode(cstr!("__overlay__"))?;
+
+        assert_eq!(phandle_targeted_fragments(overlay)?, vec!["fragment@0".to_owned()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_policy_overlays_target_paths() -> Result<()> {
+        let mut buf = fs::read("avf_debug_policy_with_ramdump.dtbo")?;
+        let overlay = Fdt::from_mut_slice(&mut buf)?;
+
+        assert!(phandle_targeted_fragments(overlay)?.is_empty());
+
+        Ok(())
+    }
+
+    fn get_test_policy_with_ramdump_destination(path: &str) -> Result<DebugPolicy> {
+        let mut buf = vec![0_u8; 1024];
+        let fdt = Fdt::create_empty_tree(&mut buf)?;
+        let mut node = fdt
+            .root_mut()
+            .add_subnode(cstr!("avf"))?
+            .add_subnode(cstr!("guest"))?
+            .add_subnode(cstr!("common"))?;
+        node.setprop(cstr!("ramdump_path"), CString::new(path)?.as_bytes_with_nul())?;
+
+        DebugPolicy::from_fdt(fdt)
+    }
+
+    #[test]
+    fn test_ramdump_destination() -> Result<()> {
+        let policy = get_test_policy_with_ramdump_destination("/data/vendor/virt/ramdump")?;
+
+        assert_eq!(policy.ramdump_destination(), Some(PathBuf::from("/data/vendor/virt/ramdump")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ramdump_destination_out_of_allowlist_is_malformed() {
+        for path in ["/data/local/tmp", "data/vendor/virt", "/data/vendor/virt/../../local/tmp"] {
+            assert!(
+                matches!(
+                    get_test_policy_with_ramdump_destination(path),
+                    Err(DebugConfigError::Malformed(_))
+                ),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_missing_ramdump_destination() -> Result<()> {
+        let policy = DebugPolicy::from_overlay("avf_debug_policy_with_ramdump.dtbo".as_ref())?;
+
+        assert_eq!(policy.ramdump_destination(), None);
+
+        Ok(())
+    }
 }
@@ -14,6 +14,7 @@
 
 //! Payload disk image
 
+use crate::aidl::get_current_sdk;
 use crate::debug_config::DebugConfig;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     DiskImage::DiskImage,
@@ -22,30 +23,79 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
     VirtualMachineAppConfig::{Payload::Payload, VirtualMachineAppConfig},
     VirtualMachineRawConfig::VirtualMachineRawConfig,
 };
-use anyhow::{anyhow, bail, Context, Result};
-use binder::{wait_for_interface, ParcelFileDescriptor};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use apkverify::{get_apk_digest, V4Signature};
+use atrace::AtraceTag;
+use binder::{check_interface, ParcelFileDescriptor, Strong};
 use log::{info, warn};
-use microdroid_metadata::{ApexPayload, ApkPayload, Metadata, PayloadConfig, PayloadMetadata};
+use microdroid_metadata::{
+    ApexPayload, ApkPayload, Metadata, PayloadConfig, PayloadMetadata, VerityRootDigestSource,
+};
 use microdroid_payload_config::{ApexConfig, VmPayloadConfig};
 use once_cell::sync::OnceCell;
+use openssl::sha::Sha256;
 use packagemanager_aidl::aidl::android::content::pm::{
     IPackageManagerNative::IPackageManagerNative, StagedApexInfo::StagedApexInfo,
 };
 use regex::Regex;
+use rustutils::system_properties;
 use serde::Deserialize;
 use serde_xml_rs::from_reader;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::ffi::OsStr;
-use std::fs::{metadata, File, OpenOptions};
-use std::path::{Path, PathBuf};
+use std::fmt;
+use std::fs::{self, metadata, File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
-use std::time::SystemTime;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use vmconfig::open_parcel_file;
 
 const APEX_INFO_LIST_PATH: &str = "/apex/apex-info-list.xml";
 
 const PACKAGE_MANAGER_NATIVE_SERVICE: &str = "package_native";
 
+/// How long to wait for the package manager, when it's needed, before failing the VM launch.
+const PACKAGE_MANAGER_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SERVICE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Comma-separated names of the APEXes to pass writable to debuggable VMs, for developers
+/// patching them in place.
+const WRITABLE_APEXES_SYSPROP: &str = "debug.virtualizationmanager.writable_apexes";
+
+/// Wall time spent in each phase of assembling the payload disk, to track VM start latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PayloadTimings {
+    /// Loading the APEX list and selecting the APEXes of the payload.
+    pub resolve_apexes: Duration,
+    /// Building and writing the payload metadata.
+    pub build_metadata: Duration,
+    /// Opening the images and laying out the partitions.
+    pub assemble_partitions: Duration,
+}
+
+impl PayloadTimings {
+    pub fn total(&self) -> Duration {
+        self.resolve_apexes + self.build_metadata + self.assemble_partitions
+    }
+}
+
+impl fmt::Display for PayloadTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} (resolve_apexes={:?} build_metadata={:?} assemble_partitions={:?})",
+            self.total(),
+            self.resolve_apexes,
+            self.build_metadata,
+            self.assemble_partitions
+        )
+    }
+}
+
 /// Represents the list of APEXes
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 struct ApexInfoList {
@@ -80,6 +130,10 @@ struct ApexInfo {
 
     #[serde(rename = "preinstalledModulePath")]
     preinstalled_path: PathBuf,
+
+    /// Whether the APEX was overridden with the staged one.
+    #[serde(skip)]
+    is_staged: bool,
 }
 
 impl ApexInfoList {
@@ -128,6 +182,7 @@ impl ApexInfoList {
                     apex_info.path = PathBuf::from(&staged_apex_info.diskImagePath);
                     apex_info.has_classpath_jar = staged_apex_info.hasClassPathJars;
                     apex_info.last_update_seconds = last_updated(&apex_info.path)?;
+                    apex_info.is_staged = true;
                 }
             }
         }
@@ -138,6 +193,13 @@ impl ApexInfoList {
     }
 }
 
+/// Notified as the APEXes of a payload are resolved, so that callers can report the progress of
+/// payloads with many APEXes.
+pub trait ApexResolutionObserver {
+    /// Called once the image of the APEX `name` is opened, with whether it is a staged one.
+    fn apex_resolved(&mut self, name: &str, path: &Path, staged: bool);
+}
+
 fn last_updated<P: AsRef<Path>>(path: P) -> Result<u64> {
     let metadata = metadata(path)?;
     Ok(metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
@@ -167,7 +229,9 @@ impl PackageManager {
         Ok(Self { apex_info_list })
     }
 
-    fn get_apex_list(&self, prefer_staged: bool) -> Result<ApexInfoList> {
+    /// Returns the list of APEXes, where staged APEXes replace the active ones if
+    /// `prefer_staged`, except for those in `keep_activated`.
+    fn get_apex_list(&self, prefer_staged: bool, keep_activated: &[&str]) -> Result<ApexInfoList> {
         // get the list of active apexes
         let mut list = self.apex_info_list.clone();
         // When prefer_staged, we override ApexInfo by consulting "package_native"
@@ -175,28 +239,175 @@ impl PackageManager {
             if cfg!(early) {
                 return Err(anyhow!("Can't turn on prefer_staged on early boot VMs"));
             }
-            let pm =
-                wait_for_interface::<dyn IPackageManagerNative>(PACKAGE_MANAGER_NATIVE_SERVICE)
-                    .context("Failed to get service when prefer_staged is set.")?;
+            let pm = package_manager_native()
+                .context("Failed to get service when prefer_staged is set.")?;
             let staged =
                 pm.getStagedApexModuleNames().context("getStagedApexModuleNames failed")?;
-            for name in staged {
-                if let Some(staged_apex_info) =
-                    pm.getStagedApexInfo(&name).context("getStagedApexInfo failed")?
-                {
-                    list.override_staged_apex(&staged_apex_info)?;
-                }
-            }
+            override_staged_apexes(&mut list, &staged, &pm, keep_activated)?;
         }
         Ok(list)
     }
 }
 
+fn override_staged_apexes(
+    list: &mut ApexInfoList,
+    staged: &[String],
+    staged_apexes: &impl StagedApexSource,
+    keep_activated: &[&str],
+) -> Result<()> {
+    for name in staged {
+        if keep_activated.contains(&name.as_str()) {
+            info!("Using the activated {name} despite prefer_staged, as it's a required APEX");
+            continue;
+        }
+        if let Some(staged_apex_info) = staged_apexes.staged_apex_info(name)? {
+            list.override_staged_apex(&staged_apex_info)?;
+        }
+    }
+    Ok(())
+}
+
+fn package_manager_native() -> Result<Strong<dyn IPackageManagerNative>> {
+    wait_for_service_with_timeout(
+        PACKAGE_MANAGER_NATIVE_SERVICE,
+        PACKAGE_MANAGER_WAIT_TIMEOUT,
+        || check_interface::<dyn IPackageManagerNative>(PACKAGE_MANAGER_NATIVE_SERVICE),
+    )
+}
+
+/// Source of the staged versions of APEXes.
+trait StagedApexSource {
+    fn staged_apex_info(&self, name: &str) -> Result<Option<StagedApexInfo>>;
+}
+
+impl StagedApexSource for Strong<dyn IPackageManagerNative> {
+    fn staged_apex_info(&self, name: &str) -> Result<Option<StagedApexInfo>> {
+        self.getStagedApexInfo(name).context("getStagedApexInfo failed")
+    }
+}
+
+/// Returns the name, active path and staged path of each of `apexes` with a staged version at a
+/// different path than the active one, i.e. which would be replaced when `prefer_staged` is set.
+///
+/// This is only diagnostic: nothing is launched or modified.
+#[allow(dead_code)] // TODO: Expose this to update-readiness checks.
+pub fn staged_differences(apexes: &[String]) -> Result<Vec<(String, PathBuf, PathBuf)>> {
+    let pm = PackageManager::new()?;
+    let apexes = normalize_apex_names(apexes.to_vec());
+    find_staged_differences(pm.apex_info_list, &apexes, &package_manager_native()?)
+}
+
+fn find_staged_differences(
+    apex_list: &ApexInfoList,
+    apexes: &[String],
+    staged_apexes: &impl StagedApexSource,
+) -> Result<Vec<(String, PathBuf, PathBuf)>> {
+    let mut differences = Vec::new();
+    for name in apexes {
+        let Some(staged) = staged_apexes.staged_apex_info(name)? else {
+            continue;
+        };
+        let staged_path = PathBuf::from(&staged.diskImagePath);
+        let active = apex_list.list.iter().find(|apex| apex.is_active && &apex.name == name);
+        let Some(active) = active else {
+            warn!("Staged APEX {name} has no active version");
+            continue;
+        };
+        if active.path != staged_path {
+            differences.push((name.clone(), active.path.clone(), staged_path));
+        }
+    }
+    Ok(differences)
+}
+
+/// Sorts the APEX names and removes duplicates, the form expected of any list of APEX names.
+fn normalize_apex_names(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Calls `get_service` until it succeeds or `timeout` elapses, so that VM launches fail instead of
+/// hanging when the service isn't (yet) registered, e.g. during early boot.
+fn wait_for_service_with_timeout<T>(
+    name: &str,
+    timeout: Duration,
+    mut get_service: impl FnMut() -> binder::Result<T>,
+) -> Result<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match get_service() {
+            Ok(service) => return Ok(service),
+            Err(e) if Instant::now() >= deadline => {
+                bail!("Timed out after {timeout:?} waiting for service {name}: {e:?}")
+            }
+            Err(_) => thread::sleep(SERVICE_POLL_INTERVAL),
+        }
+    }
+}
+
+/// Checks that the payload config path is a plain path relative to the root of the APK, so that
+/// it can't refer to a file outside of /mnt/apk once mounted in the VM.
+fn validate_config_path(config_path: &str) -> Result<&str> {
+    let path = Path::new(config_path);
+    if config_path.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!(
+            "Invalid payload config path {config_path:?}: must be a relative path within the APK"
+        );
+    }
+    Ok(config_path)
+}
+
+/// Returns the labels of the partitions holding the APK and idsig of the `i`-th APK of the payload.
+/// The main APK keeps the labels used when payloads only had one.
+fn apk_partition_labels(i: usize) -> (String, String) {
+    if i == 0 {
+        ("microdroid-apk".to_owned(), "microdroid-apk-idsig".to_owned())
+    } else {
+        (format!("microdroid-apk-{i}"), format!("microdroid-apk-idsig-{i}"))
+    }
+}
+
+fn apk_payload(i: usize) -> ApkPayload {
+    let (payload_partition_name, idsig_partition_name) = apk_partition_labels(i);
+    let name = if i == 0 { "apk".to_owned() } else { format!("apk-{i}") };
+    ApkPayload {
+        name,
+        payload_partition_name,
+        idsig_partition_name,
+        // APKs are mounted with the hashtree of their idsig, so that tampered pages fail to load.
+        verity_expected: true,
+        root_digest_source: VerityRootDigestSource::VERITY_APK_IDSIG.into(),
+        ..Default::default()
+    }
+}
+
 fn make_metadata_file(
-    app_config: &VirtualMachineAppConfig,
-    apex_infos: &[&ApexInfo],
+    metadata: &Metadata,
     temporary_directory: &Path,
 ) -> Result<ParcelFileDescriptor> {
+    // Write metadata to file.
+    let metadata_path = temporary_directory.join("metadata");
+    let mut metadata_file = OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&metadata_path)
+        .with_context(|| format!("Failed to open metadata file {:?}", metadata_path))?;
+    microdroid_metadata::write_metadata(metadata, &mut metadata_file)?;
+
+    // Re-open the metadata file as read-only.
+    open_parcel_file(&metadata_path, false)
+}
+
+fn make_metadata(
+    app_config: &VirtualMachineAppConfig,
+    apex_infos: &[&ApexInfo],
+    apk_count: usize,
+) -> Result<Metadata> {
+    if apk_count == 0 {
+        bail!("Payload has no APK");
+    }
     let payload_metadata = match &app_config.payload {
         Payload::PayloadConfig(payload_config) => PayloadMetadata::Config(PayloadConfig {
             payload_binary_name: payload_config.payloadBinaryName.clone(),
@@ -204,6 +415,7 @@ fn make_metadata_file(
             special_fields: Default::default(),
         }),
         Payload::ConfigPath(config_path) => {
+            let config_path = validate_config_path(config_path)?;
             PayloadMetadata::ConfigPath(format!("/mnt/apk/{}", config_path))
         }
     };
@@ -219,33 +431,35 @@ fn make_metadata_file(
                     partition_name: format!("microdroid-apex-{}", i),
                     last_update_seconds: apex_info.last_update_seconds,
                     is_factory: apex_info.is_factory,
+                    version_code: apex_info.version,
+                    is_active: apex_info.is_active,
+                    // The payload image of APEXes carries its hashtree, described in its vbmeta.
+                    verity_expected: true,
+                    root_digest_source: VerityRootDigestSource::VERITY_APEX_VBMETA.into(),
                     ..Default::default()
                 })
             })
             .collect::<Result<_>>()?,
-        apk: Some(ApkPayload {
-            name: "apk".to_owned(),
-            payload_partition_name: "microdroid-apk".to_owned(),
-            idsig_partition_name: "microdroid-apk-idsig".to_owned(),
-            ..Default::default()
-        })
-        .into(),
+        apk: Some(apk_payload(0)).into(),
+        split_apks: (1..apk_count).map(apk_payload).collect(),
         payload: Some(payload_metadata),
         ..Default::default()
     };
 
-    // Write metadata to file.
-    let metadata_path = temporary_directory.join("metadata");
-    let mut metadata_file = OpenOptions::new()
-        .create_new(true)
-        .read(true)
-        .write(true)
-        .open(&metadata_path)
-        .with_context(|| format!("Failed to open metadata file {:?}", metadata_path))?;
-    microdroid_metadata::write_metadata(&metadata, &mut metadata_file)?;
+    Ok(metadata)
+}
 
-    // Re-open the metadata file as read-only.
-    open_parcel_file(&metadata_path, false)
+/// Returns the names of the partitions of the payload disk which `metadata` references: those of
+/// the APEXes, then the APK and idsig ones of the main and split APKs, in order.
+pub fn metadata_referenced_partitions(metadata: &Metadata) -> Vec<String> {
+    let apexes = metadata.apexes.iter().map(|apex| apex.partition_name.clone());
+    let apks = metadata
+        .apk
+        .as_ref()
+        .into_iter()
+        .chain(metadata.split_apks.iter())
+        .flat_map(|apk| [apk.payload_partition_name.clone(), apk.idsig_partition_name.clone()]);
+    apexes.chain(apks).filter(|name| !name.is_empty()).collect()
 }
 
 /// Creates a DiskImage with partitions:
@@ -255,19 +469,24 @@ fn make_metadata_file(
 ///   ..
 ///   microdroid-apk: apk
 ///   microdroid-apk-idsig: idsig
+///   microdroid-apk-1: split apk 1
+///   microdroid-apk-idsig-1: split idsig 1
+///   ..
 ///   extra-apk-0:   additional apk 0
 ///   extra-idsig-0: additional idsig 0
 ///   extra-apk-1:   additional apk 1
 ///   extra-idsig-1: additional idsig 1
 ///   ..
+#[allow(clippy::too_many_arguments)] // TODO: Fewer arguments
 fn make_payload_disk(
     app_config: &VirtualMachineAppConfig,
     debug_config: &DebugConfig,
-    apk_file: File,
-    idsig_file: File,
+    apks: Vec<(File, File)>,
     extra_apk_files: Vec<File>,
     vm_payload_config: &VmPayloadConfig,
     temporary_directory: &Path,
+    timings: &mut PayloadTimings,
+    observer: Option<&mut dyn ApexResolutionObserver>,
 ) -> Result<DiskImage> {
     if extra_apk_files.len() != app_config.extraIdsigs.len() {
         bail!(
@@ -277,57 +496,135 @@ fn make_payload_disk(
         );
     }
 
+    let current_sdk = get_current_sdk()?;
+    for (i, (apk_file, idsig_file)) in apks.iter().enumerate() {
+        check_idsig_matches_apk(apk_file, idsig_file, current_sdk)
+            .with_context(|| format!("Invalid idsig for APK #{i}"))?;
+    }
+
+    if vm_payload_config.factory_apexes_only && vm_payload_config.prefer_staged {
+        bail!("factory_apexes_only can't be combined with prefer_staged");
+    }
+
+    let trace = atrace::begin_scoped_event(AtraceTag::Always, "virtmgr::resolve_apexes");
+    let start = Instant::now();
     let pm = PackageManager::new()?;
-    let apex_list = pm.get_apex_list(vm_payload_config.prefer_staged)?;
+    let keep_activated = activated_only_apexes(&vm_payload_config.apexes, debug_config);
+    let apex_list = pm.get_apex_list(vm_payload_config.prefer_staged, &keep_activated)?;
 
     // collect APEXes from config
     let mut apex_infos = collect_apex_infos(&apex_list, &vm_payload_config.apexes, debug_config)?;
+    if vm_payload_config.factory_apexes_only {
+        apex_infos = factory_apex_infos(&apex_list, &apex_infos)?;
+    }
 
     // Pass sorted list of apexes. Sorting key shouldn't use `path` because it will change after
     // reboot with prefer_staged. `last_update_seconds` is added to distinguish "samegrade"
     // update.
     apex_infos.sort_by_key(|info| (&info.name, &info.version, &info.last_update_seconds));
-    info!("Microdroid payload APEXes: {:?}", apex_infos.iter().map(|ai| &ai.name));
+    info!(
+        "Microdroid payload APEXes: {:?}",
+        categorize_apexes(&apex_infos, &vm_payload_config.apexes, debug_config)
+    );
+    info!("Microdroid payload APEX set digest: {}", hex::encode(apex_set_digest(&apex_infos)));
+    timings.resolve_apexes = start.elapsed();
+    drop(trace);
+
+    let writable_apexes = get_writable_apexes(debug_config)?;
+    assemble_payload_disk(
+        app_config,
+        &apex_infos,
+        &writable_apexes,
+        apks,
+        extra_apk_files,
+        temporary_directory,
+        timings,
+        observer,
+    )
+}
 
-    let metadata_file = make_metadata_file(app_config, &apex_infos, temporary_directory)?;
+/// Returns the names of the APEXes which the developer asked to pass writable to the VM.
+///
+/// This is a development feature, so it's ignored unless both the VM and the build are
+/// debuggable.
+fn get_writable_apexes(debug_config: &DebugConfig) -> Result<Vec<String>> {
+    let names = system_properties::read(WRITABLE_APEXES_SYSPROP)?.unwrap_or_default();
+    let names = normalize_apex_names(
+        names.split(',').map(str::trim).filter(|n| !n.is_empty()).map(str::to_owned).collect(),
+    );
+    if names.is_empty() {
+        return Ok(names);
+    }
+    if debug_config.debug_level != DebugLevel::FULL
+        || !system_properties::read_bool("ro.debuggable", false)?
+    {
+        warn!("Ignoring {WRITABLE_APEXES_SYSPROP}: requires a debuggable VM and build");
+        return Ok(vec![]);
+    }
+    warn!("Passing writable APEXes to the VM: {names:?}");
+    Ok(names)
+}
+
+/// Builds the payload metadata and the partitions of the payload disk from resolved APEXes.
+///
+/// The APEXes named in `writable_apexes` are backed by copies in `temporary_directory`, so that
+/// the VM can modify them without affecting the APEXes of the host. `observer` is notified of
+/// each APEX as it is opened.
+#[allow(clippy::too_many_arguments)] // TODO: Fewer arguments
+fn assemble_payload_disk(
+    app_config: &VirtualMachineAppConfig,
+    apex_infos: &[&ApexInfo],
+    writable_apexes: &[String],
+    apks: Vec<(File, File)>,
+    extra_apk_files: Vec<File>,
+    temporary_directory: &Path,
+    timings: &mut PayloadTimings,
+    mut observer: Option<&mut dyn ApexResolutionObserver>,
+) -> Result<DiskImage> {
+    let trace = atrace::begin_scoped_event(AtraceTag::Always, "virtmgr::build_metadata");
+    let start = Instant::now();
+    let metadata = make_metadata(app_config, apex_infos, apks.len())?;
+    let metadata_file = make_metadata_file(&metadata, temporary_directory)?;
+    timings.build_metadata = start.elapsed();
+    drop(trace);
+
+    let trace = atrace::begin_scoped_event(AtraceTag::Always, "virtmgr::assemble_partitions");
+    let start = Instant::now();
     // put metadata at the first partition
     let mut partitions = vec![Partition {
-        label: "payload-metadata".to_owned(),
+        label: PAYLOAD_METADATA_LABEL.to_owned(),
         image: Some(metadata_file),
         writable: false,
         guid: None,
     }];
 
     for (i, apex_info) in apex_infos.iter().enumerate() {
-        let path = if cfg!(early) {
-            let path = &apex_info.preinstalled_path;
-            if path.extension().and_then(OsStr::to_str).unwrap_or("") != "apex" {
-                bail!("compressed APEX {} not supported", path.display());
-            }
-            path
+        let writable = writable_apexes.contains(&apex_info.name);
+        let apex_file = if writable {
+            make_apex_cow_file(apex_info, temporary_directory)
+                .and_then(|path| open_parcel_file(&path, true))
         } else {
-            &apex_info.path
-        };
-        let apex_file = open_parcel_file(path, false)?;
+            apex_image_path(apex_info).and_then(|path| open_parcel_file(path, false))
+        }
+        .with_context(|| {
+            format!("Failed to open APEX {} ({} of {})", apex_info.name, i + 1, apex_infos.len())
+        })?;
+        if let Some(observer) = observer.as_deref_mut() {
+            observer.apex_resolved(&apex_info.name, &apex_info.path, apex_info.is_staged);
+        }
         partitions.push(Partition {
             label: format!("microdroid-apex-{}", i),
             image: Some(apex_file),
-            writable: false,
+            writable,
             guid: None,
         });
     }
-    partitions.push(Partition {
-        label: "microdroid-apk".to_owned(),
-        image: Some(ParcelFileDescriptor::new(apk_file)),
-        writable: false,
-        guid: None,
-    });
-    partitions.push(Partition {
-        label: "microdroid-apk-idsig".to_owned(),
-        image: Some(ParcelFileDescriptor::new(idsig_file)),
-        writable: false,
-        guid: None,
-    });
+    for name in writable_apexes {
+        if !apex_infos.iter().any(|info| &info.name == name) {
+            warn!("Writable APEX {name} isn't part of the payload");
+        }
+    }
+    partitions.extend(make_apk_partitions(apks));
 
     // we've already checked that extra_apks and extraIdsigs are in the same size.
     let extra_idsigs = &app_config.extraIdsigs;
@@ -354,7 +651,122 @@ fn make_payload_disk(
         });
     }
 
-    Ok(DiskImage { image: None, partitions, writable: false })
+    timings.assemble_partitions = start.elapsed();
+    drop(trace);
+
+    let writable = partitions.iter().any(|partition| partition.writable);
+    let disk = DiskImage { image: None, partitions, writable };
+    check_payload_disk(&disk, &metadata_referenced_partitions(&metadata))?;
+    Ok(disk)
+}
+
+/// Label of the partition holding the payload metadata, which must come first.
+const PAYLOAD_METADATA_LABEL: &str = "payload-metadata";
+
+/// Longest partition label, as GPT partition names are at most 36 UTF-16 code units.
+const MAX_PARTITION_LABEL_LEN: usize = 36;
+
+/// Checks that the assembled payload disk is one the guest can use, with each of the partitions
+/// its metadata references, reporting all the problems found at once.
+fn check_payload_disk(disk: &DiskImage, referenced_partitions: &[String]) -> Result<()> {
+    let mut problems = Vec::new();
+    if disk.partitions.first().map(|p| p.label.as_str()) != Some(PAYLOAD_METADATA_LABEL) {
+        problems.push(format!("{PAYLOAD_METADATA_LABEL} isn't the first partition"));
+    }
+    let mut labels = HashSet::new();
+    for partition in &disk.partitions {
+        let label = &partition.label;
+        if !labels.insert(label) {
+            problems.push(format!("duplicate partition {label}"));
+        }
+        if partition.image.is_none() {
+            problems.push(format!("partition {label} has no image"));
+        }
+        if label.encode_utf16().count() > MAX_PARTITION_LABEL_LEN {
+            problems
+                .push(format!("partition label {label} is longer than {MAX_PARTITION_LABEL_LEN}"));
+        }
+    }
+    for name in referenced_partitions {
+        if !labels.contains(name) {
+            problems.push(format!("partition {name} referenced by the metadata is missing"));
+        }
+    }
+    if !problems.is_empty() {
+        bail!("Inconsistent payload disk: {}", problems.join(", "));
+    }
+    Ok(())
+}
+
+/// Copies the image of the APEX into `temporary_directory`, for the VM to write to instead of
+/// the original image.
+fn make_apex_cow_file(apex_info: &ApexInfo, temporary_directory: &Path) -> Result<PathBuf> {
+    let source = apex_image_path(apex_info)?;
+    let path = temporary_directory.join(format!("{}.apex.cow", apex_info.name));
+    fs::copy(source, &path)
+        .with_context(|| format!("Failed to copy {} to {}", source.display(), path.display()))?;
+    Ok(path)
+}
+
+/// Returns the path of the image of the APEX to pass to the VM.
+fn apex_image_path(apex_info: &ApexInfo) -> Result<&Path> {
+    let path = if cfg!(early) {
+        let path = &apex_info.preinstalled_path;
+        if path.extension().and_then(OsStr::to_str).unwrap_or("") != "apex" {
+            bail!("compressed APEX {} not supported", path.display());
+        }
+        path
+    } else {
+        &apex_info.path
+    };
+    // Flattened APEXes, still found on some old devices, are directories rather than images.
+    if path.is_dir() {
+        bail!(
+            "APEX {} at {} is flattened, which isn't supported for microdroid payloads",
+            apex_info.name,
+            path.display()
+        );
+    }
+    Ok(path)
+}
+
+/// Checks that the idsig was generated for the APK, by comparing the APK digest it holds with the
+/// one of the APK signing block, so that a swapped pair fails before the VM boots rather than
+/// when setting up dm-verity in the guest.
+fn check_idsig_matches_apk(apk_file: &File, idsig_file: &File, current_sdk: u32) -> Result<()> {
+    let (_, apk_digest) = get_apk_digest(apk_file, current_sdk, /* verify= */ false)
+        .context("Failed to read the APK digest")?;
+    let idsig = V4Signature::from_idsig(idsig_file).context("Failed to parse the idsig")?;
+    ensure!(
+        idsig.signing_info.apk_digest == apk_digest,
+        "The idsig was generated for a different APK"
+    );
+    // The files are shared with the VM, so leave them as they were passed.
+    for mut file in [apk_file, idsig_file] {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok(())
+}
+
+/// Creates the partitions of the APKs of the payload, given as (APK, idsig) pairs.
+fn make_apk_partitions(apks: Vec<(File, File)>) -> Vec<Partition> {
+    let mut partitions = Vec::with_capacity(2 * apks.len());
+    for (i, (apk_file, idsig_file)) in apks.into_iter().enumerate() {
+        let (apk_label, idsig_label) = apk_partition_labels(i);
+        partitions.push(Partition {
+            label: apk_label,
+            image: Some(ParcelFileDescriptor::new(apk_file)),
+            writable: false,
+            guid: None,
+        });
+        partitions.push(Partition {
+            label: idsig_label,
+            image: Some(ParcelFileDescriptor::new(idsig_file)),
+            writable: false,
+            guid: None,
+        });
+    }
+    partitions
 }
 
 fn run_derive_classpath() -> Result<String> {
@@ -404,31 +816,130 @@ fn check_apexes_are_from_allowed_partitions(requested_apexes: &Vec<&ApexInfo>) -
     Ok(())
 }
 
+/// APEXes which any Microdroid VM needs.
+fn required_apexes(debug_config: &DebugConfig) -> &'static [&'static str] {
+    // TODO(b/192200378) move this to microdroid.json?
+    if debug_config.should_include_debug_apexes() {
+        &["com.android.adbd"]
+    } else {
+        &[]
+    }
+}
+
+/// Returns the required APEXes which aren't requested by the VM config, and so which are always
+/// taken activated, even with prefer_staged, to keep the VM stable while testing staged APEXes.
+fn activated_only_apexes(apex_configs: &[ApexConfig], debug_config: &DebugConfig) -> Vec<&str> {
+    required_apexes(debug_config)
+        .iter()
+        .copied()
+        .filter(|name| !apex_configs.iter().any(|cfg| &cfg.name == name))
+        .collect()
+}
+
 // Collect ApexInfos from VM config
 fn collect_apex_infos<'a>(
     apex_list: &'a ApexInfoList,
     apex_configs: &[ApexConfig],
     debug_config: &DebugConfig,
 ) -> Result<Vec<&'a ApexInfo>> {
-    // APEXes which any Microdroid VM needs.
-    // TODO(b/192200378) move this to microdroid.json?
-    let required_apexes: &[_] =
-        if debug_config.should_include_debug_apexes() { &["com.android.adbd"] } else { &[] };
+    let required_apexes = required_apexes(debug_config);
 
     let apex_infos = apex_list
         .list
         .iter()
-        .filter(|ai| {
-            apex_configs.iter().any(|cfg| ai.matches(cfg) && ai.is_active)
-                || required_apexes.iter().any(|name| name == &ai.name && ai.is_active)
-                || ai.provide_shared_apex_libs
-        })
+        .filter(|ai| apex_source(ai, apex_configs, required_apexes).is_some())
         .collect();
 
     check_apexes_are_from_allowed_partitions(&apex_infos)?;
     Ok(apex_infos)
 }
 
+/// Replaces each of the resolved APEXes with its factory version from `apex_list`, for VMs which
+/// must not use APEXes updated since the device was built.
+///
+/// Fails if there is only an updated version of one of the APEXes.
+fn factory_apex_infos<'a>(
+    apex_list: &'a ApexInfoList,
+    apex_infos: &[&ApexInfo],
+) -> Result<Vec<&'a ApexInfo>> {
+    apex_infos
+        .iter()
+        .map(|apex_info| {
+            apex_list
+                .list
+                .iter()
+                .find(|candidate| candidate.name == apex_info.name && candidate.is_factory)
+                .with_context(|| {
+                    format!("Only an updated version of APEX {} is available", apex_info.name)
+                })
+        })
+        .collect()
+}
+
+/// Why an APEX is passed to the VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ApexSource {
+    /// Named in the VM config.
+    Config,
+    /// Needed by any Microdroid VM, but not named in the VM config.
+    Required,
+    /// Contributes to the classpath, which the VM config asked for with "{CLASSPATH}".
+    Classpath,
+    /// Provides shared libraries to other APEXes.
+    SharedLibs,
+}
+
+/// Returns why `apex_info` is passed to the VM, or None if it isn't.
+fn apex_source(
+    apex_info: &ApexInfo,
+    apex_configs: &[ApexConfig],
+    required_apexes: &[&str],
+) -> Option<ApexSource> {
+    if apex_info.is_active {
+        if apex_configs.iter().any(|cfg| cfg.name == apex_info.name) {
+            return Some(ApexSource::Config);
+        }
+        if required_apexes.contains(&apex_info.name.as_str()) {
+            return Some(ApexSource::Required);
+        }
+        if apex_configs.iter().any(|cfg| apex_info.matches(cfg)) {
+            return Some(ApexSource::Classpath);
+        }
+    }
+    apex_info.provide_shared_apex_libs.then_some(ApexSource::SharedLibs)
+}
+
+/// Returns a SHA-256 digest of the names and versions of the APEXes passed to the VM, which
+/// doesn't depend on their order, so that runs with the same APEXes can be compared.
+fn apex_set_digest(apex_infos: &[&ApexInfo]) -> [u8; 32] {
+    let mut apexes: Vec<_> = apex_infos.iter().map(|info| (&info.name, info.version)).collect();
+    apexes.sort();
+    let mut hasher = Sha256::new();
+    for (name, version) in apexes {
+        // Length-prefixed, so that names can't run into the following fields.
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(&version.to_le_bytes());
+    }
+    hasher.finish()
+}
+
+/// Groups the names of the APEXes passed to the VM by why they are passed, for logging.
+fn categorize_apexes<'a>(
+    apex_infos: &[&'a ApexInfo],
+    apex_configs: &[ApexConfig],
+    debug_config: &DebugConfig,
+) -> BTreeMap<ApexSource, BTreeSet<&'a str>> {
+    let required_apexes = required_apexes(debug_config);
+    let mut categories = BTreeMap::<_, BTreeSet<_>>::new();
+    for apex_info in apex_infos {
+        if let Some(source) = apex_source(apex_info, apex_configs, required_apexes) {
+            categories.entry(source).or_default().insert(apex_info.name.as_str());
+        }
+    }
+    categories
+}
+
 pub fn add_microdroid_vendor_image(vendor_image: File, vm_config: &mut VirtualMachineRawConfig) {
     vm_config.disks.push(DiskImage {
         image: None,
@@ -454,6 +965,7 @@ pub fn add_microdroid_system_images(
         DebugLevel::FULL => "debuggable",
         _ => return Err(anyhow!("unsupported debug level: {:?}", config.debugLevel)),
     };
+    check_instance_not_attached(&instance_file, storage_image.as_ref(), &vm_config.disks)?;
     let initrd = format!("/apex/com.android.virt/etc/{os_name}_initrd_{debug_suffix}.img");
     vm_config.initrd = Some(open_parcel_file(Path::new(&initrd), false)?);
 
@@ -482,28 +994,76 @@ pub fn add_microdroid_system_images(
     Ok(())
 }
 
+/// Checks that the instance image isn't also passed as another disk or partition, as the VM would
+/// then get two writable views of it which would race with each other.
+fn check_instance_not_attached(
+    instance_file: &File,
+    storage_image: Option<&File>,
+    disks: &[DiskImage],
+) -> Result<()> {
+    let file_id = |file: &File| -> Result<(u64, u64)> {
+        let metadata = file.metadata().context("Failed to stat disk image")?;
+        Ok((metadata.dev(), metadata.ino()))
+    };
+    let instance_id = file_id(instance_file)?;
+    if let Some(storage_image) = storage_image {
+        ensure!(
+            file_id(storage_image)? != instance_id,
+            "The instance image is also used as the encrypted storage image"
+        );
+    }
+    for (i, disk) in disks.iter().enumerate() {
+        if let Some(image) = &disk.image {
+            ensure!(
+                file_id(image.as_ref())? != instance_id,
+                "The instance image is also attached as disk #{i}"
+            );
+        }
+        for partition in &disk.partitions {
+            if let Some(image) = &partition.image {
+                ensure!(
+                    file_id(image.as_ref())? != instance_id,
+                    "The instance image is also attached as partition {} of disk #{i}",
+                    partition.label
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds the payload disk to `vm_config`, made of the APEXes of the payload and `apks`, its main
+/// APK followed by its split APKs, each with its idsig.
+///
+/// Only the main APK is accepted for now, as Microdroid doesn't mount split APKs yet.
 #[allow(clippy::too_many_arguments)] // TODO: Fewer arguments
 pub fn add_microdroid_payload_images(
     config: &VirtualMachineAppConfig,
     debug_config: &DebugConfig,
     temporary_directory: &Path,
-    apk_file: File,
-    idsig_file: File,
+    apks: Vec<(File, File)>,
     extra_apk_files: Vec<File>,
     vm_payload_config: &VmPayloadConfig,
     vm_config: &mut VirtualMachineRawConfig,
-) -> Result<()> {
+    observer: Option<&mut dyn ApexResolutionObserver>,
+) -> Result<PayloadTimings> {
+    if apks.len() != 1 {
+        bail!("Payload has {} APKs, but only a single APK is supported", apks.len());
+    }
+    let mut timings = PayloadTimings::default();
     vm_config.disks.push(make_payload_disk(
         config,
         debug_config,
-        apk_file,
-        idsig_file,
+        apks,
         extra_apk_files,
         vm_payload_config,
         temporary_directory,
+        &mut timings,
+        observer,
     )?);
+    info!("Microdroid payload assembled in {timings}");
 
-    Ok(())
+    Ok(timings)
 }
 
 #[cfg(test)]
@@ -512,6 +1072,30 @@ mod tests {
     use std::collections::HashMap;
     use tempfile::NamedTempFile;
 
+    const TEST_APK_PATH: &str = "v4-digest-v3-Sha256withEC.apk";
+    const TEST_IDSIG_PATH: &str = "v4-digest-v3-Sha256withEC.apk.idsig";
+    const OTHER_APK_PATH: &str = "v3-only-unknown-additional-attr.apk";
+    /// SDK level at which the test APKs are read.
+    const TEST_SDK: u32 = 31;
+
+    #[test]
+    fn test_idsig_matches_apk() -> Result<()> {
+        let apk = File::open(TEST_APK_PATH)?;
+        let idsig = File::open(TEST_IDSIG_PATH)?;
+
+        check_idsig_matches_apk(&apk, &idsig, TEST_SDK)
+    }
+
+    #[test]
+    fn test_idsig_of_other_apk_is_rejected() -> Result<()> {
+        let apk = File::open(OTHER_APK_PATH)?;
+        let idsig = File::open(TEST_IDSIG_PATH)?;
+
+        let e = check_idsig_matches_apk(&apk, &idsig, TEST_SDK).unwrap_err();
+        assert!(e.to_string().contains("different APK"), "{e:?}");
+        Ok(())
+    }
+
     #[test]
     fn test_find_apex_names_in_classpath() {
         let vars = r#"
@@ -526,6 +1110,426 @@ export OTHER /foo/bar:/baz:/apex/second.valid.apex/:gibberish:"#;
         assert_eq!(find_apex_names_in_classpath(vars).unwrap(), expected);
     }
 
+    #[test]
+    fn test_validate_config_path() {
+        assert!(validate_config_path("assets/vm_config.json").is_ok());
+        assert!(validate_config_path("vm_config.json").is_ok());
+    }
+
+    #[test]
+    fn test_validate_config_path_rejects_traversal() {
+        assert!(validate_config_path("../vm_config.json").is_err());
+        assert!(validate_config_path("assets/../../vm_config.json").is_err());
+        assert!(validate_config_path("./assets/vm_config.json").is_err());
+    }
+
+    #[test]
+    fn test_validate_config_path_rejects_absolute_path() {
+        assert!(validate_config_path("/assets/vm_config.json").is_err());
+        assert!(validate_config_path("//assets/vm_config.json").is_err());
+        assert!(validate_config_path("").is_err());
+    }
+
+    fn app_config_for_test() -> VirtualMachineAppConfig {
+        VirtualMachineAppConfig {
+            payload: Payload::PayloadConfig(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    fn apks_for_test(count: usize) -> Result<Vec<(File, File)>> {
+        (0..count).map(|_| Ok((tempfile::tempfile()?, tempfile::tempfile()?))).collect()
+    }
+
+    /// Returns the (APK, idsig) partition names of the metadata, in order.
+    fn metadata_apk_partitions(metadata: &Metadata) -> Vec<(String, String)> {
+        metadata
+            .apk
+            .as_ref()
+            .into_iter()
+            .chain(metadata.split_apks.iter())
+            .map(|apk| (apk.payload_partition_name.clone(), apk.idsig_partition_name.clone()))
+            .collect()
+    }
+
+    fn disk_apk_partitions(partitions: &[Partition]) -> Vec<(String, String)> {
+        partitions.chunks(2).map(|p| (p[0].label.clone(), p[1].label.clone())).collect()
+    }
+
+    #[test]
+    fn test_single_apk_payload() -> Result<()> {
+        let metadata = make_metadata(&app_config_for_test(), &[], 1)?;
+        let partitions = make_apk_partitions(apks_for_test(1)?);
+
+        assert_eq!(
+            metadata_apk_partitions(&metadata),
+            vec![("microdroid-apk".to_owned(), "microdroid-apk-idsig".to_owned())]
+        );
+        assert_eq!(disk_apk_partitions(&partitions), metadata_apk_partitions(&metadata));
+        assert!(metadata.split_apks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_apks_payload() -> Result<()> {
+        let metadata = make_metadata(&app_config_for_test(), &[], 3)?;
+        let partitions = make_apk_partitions(apks_for_test(3)?);
+
+        assert_eq!(
+            metadata_apk_partitions(&metadata),
+            vec![
+                ("microdroid-apk".to_owned(), "microdroid-apk-idsig".to_owned()),
+                ("microdroid-apk-1".to_owned(), "microdroid-apk-idsig-1".to_owned()),
+                ("microdroid-apk-2".to_owned(), "microdroid-apk-idsig-2".to_owned()),
+            ]
+        );
+        assert_eq!(disk_apk_partitions(&partitions), metadata_apk_partitions(&metadata));
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_without_apk() {
+        assert!(make_metadata(&app_config_for_test(), &[], 0).is_err());
+    }
+
+    #[test]
+    fn test_payload_timings_are_recorded() -> Result<()> {
+        let temporary_directory = tempfile::TempDir::new()?;
+        let mut timings = PayloadTimings::default();
+
+        let disk = assemble_payload_disk(
+            &app_config_for_test(),
+            &[],
+            &[],
+            apks_for_test(1)?,
+            vec![],
+            temporary_directory.path(),
+            &mut timings,
+            None,
+        )?;
+
+        assert_eq!(disk.partitions.len(), 3);
+        assert!(timings.build_metadata > Duration::ZERO, "{timings}");
+        assert!(timings.assemble_partitions > Duration::ZERO, "{timings}");
+        assert_eq!(timings.total(), timings.build_metadata + timings.assemble_partitions);
+        Ok(())
+    }
+
+    #[test]
+    fn test_writable_apex_is_backed_by_cow_file() -> Result<()> {
+        let temporary_directory = tempfile::TempDir::new()?;
+        let apexes = tempfile::TempDir::new()?;
+        let mut apex_infos = vec![];
+        for name in ["com.android.foo", "com.android.bar"] {
+            let path = apexes.path().join(format!("{name}.apex"));
+            fs::write(&path, name)?;
+            apex_infos.push(ApexInfo {
+                name: name.to_owned(),
+                path: path.clone(),
+                preinstalled_path: path,
+                ..Default::default()
+            });
+        }
+        let apex_infos: Vec<_> = apex_infos.iter().collect();
+
+        let disk = assemble_payload_disk(
+            &app_config_for_test(),
+            &apex_infos,
+            &["com.android.bar".to_owned()],
+            apks_for_test(1)?,
+            vec![],
+            temporary_directory.path(),
+            &mut PayloadTimings::default(),
+            None,
+        )?;
+
+        let apex_partitions: Vec<_> = disk.partitions[1..3].iter().map(|p| p.writable).collect();
+        assert_eq!(apex_partitions, vec![false, true]);
+        assert!(disk.writable);
+        let cow_file = temporary_directory.path().join("com.android.bar.apex.cow");
+        assert_eq!(fs::read_to_string(cow_file)?, "com.android.bar");
+        assert!(!temporary_directory.path().join("com.android.foo.apex.cow").exists());
+        Ok(())
+    }
+
+    /// Records the APEXes it is notified of.
+    #[derive(Default)]
+    struct RecordingObserver(Vec<(String, PathBuf, bool)>);
+
+    impl ApexResolutionObserver for RecordingObserver {
+        fn apex_resolved(&mut self, name: &str, path: &Path, staged: bool) {
+            self.0.push((name.to_owned(), path.to_owned(), staged));
+        }
+    }
+
+    #[test]
+    fn test_observer_is_notified_of_each_apex() -> Result<()> {
+        let temporary_directory = tempfile::TempDir::new()?;
+        let apexes = tempfile::TempDir::new()?;
+        let mut apex_infos = vec![];
+        for name in ["com.android.foo", "com.android.bar", "com.android.baz"] {
+            let path = apexes.path().join(format!("{name}.apex"));
+            fs::write(&path, name)?;
+            apex_infos.push(ApexInfo {
+                name: name.to_owned(),
+                path: path.clone(),
+                preinstalled_path: path,
+                is_staged: name == "com.android.bar",
+                ..Default::default()
+            });
+        }
+        let apex_infos: Vec<_> = apex_infos.iter().collect();
+        let mut observer = RecordingObserver::default();
+
+        assemble_payload_disk(
+            &app_config_for_test(),
+            &apex_infos,
+            &[],
+            apks_for_test(1)?,
+            vec![],
+            temporary_directory.path(),
+            &mut PayloadTimings::default(),
+            Some(&mut observer),
+        )?;
+
+        let expected: Vec<_> = apex_infos
+            .iter()
+            .map(|info| (info.name.clone(), info.path.clone(), info.is_staged))
+            .collect();
+        assert_eq!(observer.0, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apex_open_failure_reports_progress() -> Result<()> {
+        let temporary_directory = tempfile::TempDir::new()?;
+        let apexes = tempfile::TempDir::new()?;
+        let present = apexes.path().join("com.android.foo.apex");
+        fs::write(&present, "foo")?;
+        let missing = apexes.path().join("com.android.bar.apex");
+        let apex_infos =
+            [("com.android.foo", present), ("com.android.bar", missing)].map(|(name, path)| {
+                ApexInfo {
+                    name: name.to_owned(),
+                    path: path.clone(),
+                    preinstalled_path: path,
+                    ..Default::default()
+                }
+            });
+        let apex_infos: Vec<_> = apex_infos.iter().collect();
+        let mut observer = RecordingObserver::default();
+
+        let err = assemble_payload_disk(
+            &app_config_for_test(),
+            &apex_infos,
+            &[],
+            apks_for_test(1)?,
+            vec![],
+            temporary_directory.path(),
+            &mut PayloadTimings::default(),
+            Some(&mut observer),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "Failed to open APEX com.android.bar (2 of 2)");
+        assert_eq!(observer.0.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_image_attached_twice_is_rejected() -> Result<()> {
+        let instance = NamedTempFile::new()?;
+        let mut vm_config = VirtualMachineRawConfig::default();
+        add_microdroid_vendor_image(instance.reopen()?, &mut vm_config);
+
+        let err = add_microdroid_system_images(
+            &app_config_for_test(),
+            instance.reopen()?,
+            None,
+            "microdroid",
+            &mut vm_config,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "The instance image is also attached as partition microdroid-vendor of disk #0"
+        );
+        assert_eq!(vm_config.disks.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_image_used_as_storage_is_rejected() -> Result<()> {
+        let instance = NamedTempFile::new()?;
+
+        let err = check_instance_not_attached(instance.as_file(), Some(&instance.reopen()?), &[])
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "The instance image is also used as the encrypted storage image"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_distinct_images_are_accepted() -> Result<()> {
+        let (instance, storage, vendor) =
+            (NamedTempFile::new()?, NamedTempFile::new()?, NamedTempFile::new()?);
+        let mut vm_config = VirtualMachineRawConfig::default();
+        add_microdroid_vendor_image(vendor.reopen()?, &mut vm_config);
+
+        check_instance_not_attached(instance.as_file(), Some(storage.as_file()), &vm_config.disks)
+    }
+
+    #[test]
+    fn test_payload_disk_is_read_only_without_writable_apexes() -> Result<()> {
+        let temporary_directory = tempfile::TempDir::new()?;
+
+        let disk = assemble_payload_disk(
+            &app_config_for_test(),
+            &[],
+            &["com.android.foo".to_owned()],
+            apks_for_test(1)?,
+            vec![],
+            temporary_directory.path(),
+            &mut PayloadTimings::default(),
+            None,
+        )?;
+
+        assert!(disk.partitions.iter().all(|p| !p.writable));
+        assert!(!disk.writable);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flattened_apex_is_rejected() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let apex_info = ApexInfo {
+            name: "com.android.foo".to_owned(),
+            path: dir.path().to_owned(),
+            preinstalled_path: dir.path().to_owned(),
+            ..Default::default()
+        };
+
+        let error = apex_image_path(&apex_info).unwrap_err().to_string();
+
+        assert!(error.contains("com.android.foo"), "{error}");
+        assert!(error.contains("flattened"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apex_image_path() -> Result<()> {
+        let file = tempfile::Builder::new().suffix(".apex").tempfile()?;
+        let apex_info = ApexInfo {
+            name: "com.android.foo".to_owned(),
+            path: file.path().to_owned(),
+            preinstalled_path: file.path().to_owned(),
+            ..Default::default()
+        };
+
+        assert_eq!(apex_image_path(&apex_info)?, file.path());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apex_info_flows_into_metadata() -> Result<()> {
+        let apex_info_list: ApexInfoList = from_reader(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <apex-info-list>
+                <apex-info moduleName="com.android.foo"
+                    modulePath="/data/apex/active/com.android.foo@2.apex"
+                    preinstalledModulePath="/system/apex/com.android.foo.apex"
+                    versionCode="2"
+                    versionName="2"
+                    isFactory="false"
+                    isActive="true"
+                    lastUpdateMillis="1700000000"
+                    provideSharedApexLibs="false" />
+            </apex-info-list>"#
+                .as_bytes(),
+        )?;
+        let apex_info = &apex_info_list.list[0];
+
+        let metadata = make_metadata(&app_config_for_test(), &[apex_info], 1)?;
+
+        assert_eq!(
+            metadata.apexes,
+            vec![ApexPayload {
+                name: "com.android.foo".to_owned(),
+                partition_name: "microdroid-apex-0".to_owned(),
+                last_update_seconds: 1700000000,
+                is_factory: false,
+                version_code: 2,
+                is_active: true,
+                verity_expected: true,
+                root_digest_source: VerityRootDigestSource::VERITY_APEX_VBMETA.into(),
+                ..Default::default()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_flags_verity_protected_partitions() -> Result<()> {
+        let apex_info = ApexInfo { name: "com.android.foo".to_owned(), ..Default::default() };
+
+        let metadata = make_metadata(&app_config_for_test(), &[&apex_info], 2)?;
+
+        let apex = &metadata.apexes[0];
+        assert!(apex.verity_expected);
+        assert_eq!(
+            apex.root_digest_source.enum_value(),
+            Ok(VerityRootDigestSource::VERITY_APEX_VBMETA)
+        );
+        for apk in metadata.apk.as_ref().into_iter().chain(metadata.split_apks.iter()) {
+            assert!(apk.verity_expected, "{}", apk.name);
+            assert_eq!(
+                apk.root_digest_source.enum_value(),
+                Ok(VerityRootDigestSource::VERITY_APK_IDSIG),
+                "{}",
+                apk.name
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_service_times_out() {
+        let timeout = Duration::from_millis(300);
+        let start = Instant::now();
+
+        let result = wait_for_service_with_timeout("never.registered", timeout, || {
+            Err::<(), _>(binder::StatusCode::NAME_NOT_FOUND)
+        });
+
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("Timed out"), "{err}");
+        assert!(err.contains("never.registered"), "{err}");
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn test_wait_for_service_retries_until_registered() -> Result<()> {
+        let mut attempts = 0;
+
+        let service =
+            wait_for_service_with_timeout("late.service", Duration::from_secs(10), || {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(binder::StatusCode::NAME_NOT_FOUND)
+                } else {
+                    Ok("service")
+                }
+            })?;
+
+        assert_eq!(service, "service");
+        assert_eq!(attempts, 3);
+        Ok(())
+    }
+
     #[test]
     fn test_collect_apexes() -> Result<()> {
         let apex_infos_for_test = [
@@ -671,6 +1675,283 @@ export OTHER /foo/bar:/baz:/apex/second.valid.apex/:gibberish:"#;
         Ok(())
     }
 
+    impl StagedApexSource for HashMap<&str, &str> {
+        fn staged_apex_info(&self, name: &str) -> Result<Option<StagedApexInfo>> {
+            Ok(self.get(name).map(|path| StagedApexInfo {
+                moduleName: name.to_owned(),
+                diskImagePath: path.to_string(),
+                ..Default::default()
+            }))
+        }
+    }
+
+    fn payload_disk(labels: &[&str]) -> Result<DiskImage> {
+        let mut partitions = Vec::new();
+        for label in labels {
+            partitions.push(Partition {
+                label: label.to_string(),
+                image: Some(ParcelFileDescriptor::new(tempfile::tempfile()?)),
+                writable: false,
+                guid: None,
+            });
+        }
+        Ok(DiskImage { image: None, partitions, writable: false })
+    }
+
+    #[test]
+    fn test_check_payload_disk() -> Result<()> {
+        let disk = payload_disk(&["payload-metadata", "microdroid-apex-0", "microdroid-apk"])?;
+        let referenced = ["microdroid-apex-0".to_owned(), "microdroid-apk".to_owned()];
+
+        check_payload_disk(&disk, &referenced)
+    }
+
+    #[test]
+    fn test_check_payload_disk_reports_all_problems() -> Result<()> {
+        let mut disk = payload_disk(&[
+            "microdroid-apex-0",
+            "payload-metadata",
+            "microdroid-apex-0",
+            "microdroid-apk-with-a-label-much-too-long-for-gpt",
+        ])?;
+        disk.partitions[1].image = None;
+        let referenced = ["microdroid-apex-0".to_owned(), "microdroid-apk-idsig".to_owned()];
+
+        let e = check_payload_disk(&disk, &referenced).unwrap_err().to_string();
+
+        assert!(e.contains("payload-metadata isn't the first partition"), "{e}");
+        assert!(e.contains("duplicate partition microdroid-apex-0"), "{e}");
+        assert!(e.contains("partition payload-metadata has no image"), "{e}");
+        assert!(e.contains("microdroid-apk-with-a-label-much-too-long-for-gpt is longer"), "{e}");
+        assert!(e.contains("partition microdroid-apk-idsig referenced by the metadata"), "{e}");
+        assert!(!e.contains("partition microdroid-apex-0 referenced"), "{e}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apex_set_digest() {
+        let apex =
+            |name: &str, version| ApexInfo { name: name.to_owned(), version, ..Default::default() };
+        let (foo, bar) = (apex("com.android.foo", 1), apex("com.android.bar", 2));
+        let foo_updated = apex("com.android.foo", 3);
+
+        let digest = apex_set_digest(&[&foo, &bar]);
+
+        assert_eq!(apex_set_digest(&[&bar, &foo]), digest);
+        assert_ne!(apex_set_digest(&[&foo_updated, &bar]), digest);
+        assert_ne!(apex_set_digest(&[&foo]), digest);
+    }
+
+    #[test]
+    fn test_factory_apex_is_used_over_update() -> Result<()> {
+        let apex_info_list = ApexInfoList {
+            list: vec![
+                ApexInfo {
+                    name: "com.android.foo".to_owned(),
+                    version: 2,
+                    path: PathBuf::from("/data/apex/active/com.android.foo@2.apex"),
+                    preinstalled_path: PathBuf::from("/system/apex/com.android.foo.apex"),
+                    is_active: true,
+                    ..Default::default()
+                },
+                ApexInfo {
+                    name: "com.android.foo".to_owned(),
+                    version: 1,
+                    path: PathBuf::from("/system/apex/com.android.foo.apex"),
+                    preinstalled_path: PathBuf::from("/system/apex/com.android.foo.apex"),
+                    is_factory: true,
+                    ..Default::default()
+                },
+            ],
+        };
+        let apex_configs = vec![ApexConfig { name: "com.android.foo".to_owned() }];
+        let debug_config = DebugConfig::new_with_debug_level(DebugLevel::NONE);
+        let apex_infos = collect_apex_infos(&apex_info_list, &apex_configs, &debug_config)?;
+        assert_eq!(apex_infos, vec![&apex_info_list.list[0]]);
+
+        let factory_apex_infos = factory_apex_infos(&apex_info_list, &apex_infos)?;
+
+        assert_eq!(factory_apex_infos, vec![&apex_info_list.list[1]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_only_apex_is_rejected_for_factory_apexes() {
+        let apex_info_list = ApexInfoList {
+            list: vec![ApexInfo {
+                name: "com.android.foo".to_owned(),
+                path: PathBuf::from("/data/apex/active/com.android.foo@2.apex"),
+                preinstalled_path: PathBuf::from("/system/apex/com.android.foo.apex"),
+                is_active: true,
+                ..Default::default()
+            }],
+        };
+
+        let result = factory_apex_infos(&apex_info_list, &[&apex_info_list.list[0]]);
+
+        assert!(result.is_err_and(
+            |e| e.to_string() == "Only an updated version of APEX com.android.foo is available"
+        ));
+    }
+
+    #[test]
+    fn test_categorize_apexes() -> Result<()> {
+        let apex = |name: &str, has_classpath_jar, provide_shared_apex_libs| ApexInfo {
+            name: name.to_owned(),
+            preinstalled_path: PathBuf::from("/system/apex").join(name),
+            has_classpath_jar,
+            provide_shared_apex_libs,
+            is_active: true,
+            ..Default::default()
+        };
+        let apex_info_list = ApexInfoList {
+            list: vec![
+                apex("com.android.adbd", false, false),
+                apex("com.android.art", true, false),
+                apex("com.android.foo", true, false),
+                apex("com.android.bar", false, false),
+                apex("com.android.sharedlibs", false, true),
+            ],
+        };
+        let debug_config = DebugConfig::new_with_debug_level(DebugLevel::FULL);
+        let categorize = |apex_configs: &[&str]| -> Result<_> {
+            let apex_configs: Vec<_> =
+                apex_configs.iter().map(|&name| ApexConfig { name: name.to_owned() }).collect();
+            let apex_infos = collect_apex_infos(&apex_info_list, &apex_configs, &debug_config)?;
+            let categories = categorize_apexes(&apex_infos, &apex_configs, &debug_config);
+            Ok(categories
+                .into_iter()
+                .map(|(source, names)| (source, names.into_iter().map(str::to_owned).collect()))
+                .collect::<Vec<(_, Vec<_>)>>())
+        };
+
+        assert_eq!(
+            categorize(&["com.android.foo", "{CLASSPATH}"])?,
+            vec![
+                (ApexSource::Config, vec!["com.android.foo".to_owned()]),
+                (ApexSource::Required, vec!["com.android.adbd".to_owned()]),
+                (ApexSource::Classpath, vec!["com.android.art".to_owned()]),
+                (ApexSource::SharedLibs, vec!["com.android.sharedlibs".to_owned()]),
+            ]
+        );
+        // APEXes named in the config aren't reported as required or from the classpath.
+        assert_eq!(
+            categorize(&["com.android.adbd", "com.android.art", "{CLASSPATH}"])?,
+            vec![
+                (
+                    ApexSource::Config,
+                    vec!["com.android.adbd".to_owned(), "com.android.art".to_owned()]
+                ),
+                (ApexSource::Classpath, vec!["com.android.foo".to_owned()]),
+                (ApexSource::SharedLibs, vec!["com.android.sharedlibs".to_owned()]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_apexes_stay_activated_with_prefer_staged() -> Result<()> {
+        let staged_dir = tempfile::TempDir::new()?;
+        let staged_path = |name: &str| {
+            let path = staged_dir.path().join(format!("{name}.apex"));
+            fs::write(&path, name).unwrap();
+            path.to_str().unwrap().to_owned()
+        };
+        let active_apex = |name: &str, path: &str| ApexInfo {
+            name: name.to_owned(),
+            path: PathBuf::from(path),
+            preinstalled_path: PathBuf::from("/system/apex").join(name),
+            is_active: true,
+            ..Default::default()
+        };
+        let mut apex_info_list = ApexInfoList {
+            list: vec![
+                active_apex("com.android.adbd", "/data/apex/active/adbd@1.apex"),
+                active_apex("com.android.foo", "/data/apex/active/foo@1.apex"),
+            ],
+        };
+        let (adbd_staged, foo_staged) = (staged_path("adbd"), staged_path("foo"));
+        let staged_apexes = HashMap::from([
+            ("com.android.adbd", adbd_staged.as_str()),
+            ("com.android.foo", foo_staged.as_str()),
+        ]);
+        let apex_configs = vec![ApexConfig { name: "com.android.foo".to_owned() }];
+        let debug_config = DebugConfig::new_with_debug_level(DebugLevel::FULL);
+        let keep_activated = activated_only_apexes(&apex_configs, &debug_config);
+        assert_eq!(keep_activated, vec!["com.android.adbd"]);
+
+        let staged = ["com.android.adbd", "com.android.foo"].map(String::from);
+        override_staged_apexes(&mut apex_info_list, &staged, &staged_apexes, &keep_activated)?;
+
+        let apex_infos = collect_apex_infos(&apex_info_list, &apex_configs, &debug_config)?;
+        let paths: Vec<_> =
+            apex_infos.iter().map(|ai| (ai.name.as_str(), ai.path.clone())).collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("com.android.adbd", PathBuf::from("/data/apex/active/adbd@1.apex")),
+                ("com.android.foo", PathBuf::from(foo_staged)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_apexes_requested_by_config_can_be_staged() {
+        let apex_configs = vec![ApexConfig { name: "com.android.adbd".to_owned() }];
+        let debug_config = DebugConfig::new_with_debug_level(DebugLevel::FULL);
+
+        assert!(activated_only_apexes(&apex_configs, &debug_config).is_empty());
+    }
+
+    #[test]
+    fn test_staged_differences() -> Result<()> {
+        let active_apex = |name: &str, path: &str| ApexInfo {
+            name: name.to_owned(),
+            path: PathBuf::from(path),
+            is_active: true,
+            ..Default::default()
+        };
+        let apex_info_list = ApexInfoList {
+            list: vec![
+                ApexInfo { is_active: false, ..active_apex("apex-foo", "/system/apex/foo.apex") },
+                active_apex("apex-foo", "/data/apex/active/foo@1.apex"),
+                active_apex("apex-bar", "/data/apex/active/bar@1.apex"),
+                active_apex("apex-baz", "/data/apex/active/baz@1.apex"),
+                active_apex("apex-qux", "/system/apex/qux.apex"),
+            ],
+        };
+        let staged_apexes = HashMap::from([
+            ("apex-foo", "/data/app-staging/session_1/foo.apex"),
+            ("apex-bar", "/data/apex/active/bar@1.apex"),
+            ("apex-qux", "/data/app-staging/session_1/qux.apex"),
+        ]);
+        let apexes = ["apex-foo", "apex-bar", "apex-baz"].map(String::from);
+
+        assert_eq!(
+            find_staged_differences(&apex_info_list, &apexes, &staged_apexes)?,
+            vec![(
+                "apex-foo".to_owned(),
+                PathBuf::from("/data/apex/active/foo@1.apex"),
+                PathBuf::from("/data/app-staging/session_1/foo.apex"),
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_apex_names() {
+        let names = ["com.android.foo", "com.android.bar", "com.android.foo", "com.android.baz"]
+            .map(String::from)
+            .to_vec();
+
+        assert_eq!(
+            normalize_apex_names(names),
+            vec!["com.android.bar", "com.android.baz", "com.android.foo"]
+        );
+        assert!(normalize_apex_names(vec![]).is_empty());
+    }
+
     #[test]
     fn test_check_allowed_partitions_vendor_not_allowed() -> Result<()> {
         let apex_info_list = ApexInfoList {
@@ -807,4 +2088,31 @@ export OTHER /foo/bar:/baz:/apex/second.valid.apex/:gibberish:"#;
             }
         );
     }
+
+    #[test]
+    fn test_metadata_referenced_partitions() {
+        let apex_payload = |name: &str, partition_name: &str| ApexPayload {
+            name: name.to_owned(),
+            partition_name: partition_name.to_owned(),
+            ..Default::default()
+        };
+        let metadata = Metadata {
+            apexes: vec![
+                apex_payload("com.android.os.statsd", "microdroid-apex-0"),
+                apex_payload("com.android.adbd", "microdroid-apex-1"),
+            ],
+            apk: Some(apk_payload(0)).into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metadata_referenced_partitions(&metadata),
+            vec![
+                "microdroid-apex-0",
+                "microdroid-apex-1",
+                "microdroid-apk",
+                "microdroid-apk-idsig"
+            ]
+        );
+    }
 }
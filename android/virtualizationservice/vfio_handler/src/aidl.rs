@@ -20,11 +20,14 @@ use android_system_virtualizationservice_internal::aidl::android::system::virtua
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVfioHandler::VfioDev::VfioDev;
 use android_system_virtualizationservice_internal::binder::ParcelFileDescriptor;
 use binder::{self, BinderFeatures, ExceptionCode, Interface, IntoBinderResult, Strong};
+use cstr::cstr;
 use log::error;
-use std::fs::{read_link, write, File};
-use std::io::{Read, Seek, SeekFrom, Write};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use std::fmt;
+use std::fs::{self, read_link, write, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::path::{Path, PathBuf};
 use rustutils::system_properties;
 use zerocopy::{
@@ -33,10 +36,15 @@ use zerocopy::{
     FromBytes,
 };
 
+/// The VM DTBO most recently written by the handler, kept for debugging.
+type DtboCache = Arc<Mutex<Option<Vec<u8>>>>;
+
 // Device bound to VFIO driver.
 struct BoundDevice {
+    sysfs: Arc<Sysfs>,
     sysfs_path: String,
     dtbo_label: String,
+    last_dtbo: DtboCache,
 }
 
 impl Interface for BoundDevice {}
@@ -53,24 +61,240 @@ impl IBoundDevice for BoundDevice {
 
 impl Drop for BoundDevice {
     fn drop(&mut self) {
-        unbind_device(Path::new(&self.sysfs_path)).unwrap_or_else(|e| {
+        self.sysfs.unbind_device(Path::new(&self.sysfs_path)).unwrap_or_else(|e| {
             error!("did not restore {} driver: {}", self.sysfs_path, e);
         });
+        *self.last_dtbo.lock().unwrap() = None;
     }
 }
 
 impl BoundDevice {
-    fn new_binder(sysfs_path: String, dtbo_label: String) -> Strong<dyn IBoundDevice> {
-        BnBoundDevice::new_binder(BoundDevice { sysfs_path, dtbo_label }, BinderFeatures::default())
+    fn new_binder(
+        sysfs: Arc<Sysfs>,
+        sysfs_path: String,
+        dtbo_label: String,
+        last_dtbo: DtboCache,
+    ) -> Strong<dyn IBoundDevice> {
+        let device = BoundDevice { sysfs, sysfs_path, dtbo_label, last_dtbo };
+        BnBoundDevice::new_binder(device, BinderFeatures::default())
     }
 }
 
-#[derive(Debug, Default)]
-pub struct VfioHandler {}
+pub struct VfioHandler {
+    sysfs: Arc<Sysfs>,
+    last_dtbo: DtboCache,
+}
 
 impl VfioHandler {
     pub fn init() -> VfioHandler {
-        VfioHandler::default()
+        VfioHandler::new(Sysfs::new(PathBuf::from("/")))
+    }
+
+    fn new(sysfs: Sysfs) -> VfioHandler {
+        VfioHandler { sysfs: Arc::new(sysfs), last_dtbo: Default::default() }
+    }
+
+    fn bind_devices(&self, devices: &[VfioDev]) -> binder::Result<Vec<Strong<dyn IBoundDevice>>> {
+        devices
+            .iter()
+            .map(|d| {
+                self.sysfs.bind_device(Path::new(&d.sysfsPath))?;
+                Ok(BoundDevice::new_binder(
+                    self.sysfs.clone(),
+                    d.sysfsPath.clone(),
+                    d.dtboLabel.clone(),
+                    self.last_dtbo.clone(),
+                ))
+            })
+            .collect::<binder::Result<Vec<_>>>()
+    }
+
+    /// Writes the VM DTBO at `vm_dtbo_idx` in `dtbo_img` to `dtbo_fd`, and keeps a copy of it.
+    fn write_vm_dtbo(
+        &self,
+        dtbo_img: &mut File,
+        vm_dtbo_idx: u32,
+        dtbo_fd: &ParcelFileDescriptor,
+    ) -> binder::Result<()> {
+        let dt_table_header = get_dt_table_header(dtbo_img)?;
+        let dt_table_entry = get_dt_table_entry(dtbo_img, &dt_table_header, vm_dtbo_idx)?;
+        let dtbo = write_vm_full_dtbo_from_img(dtbo_img, &dt_table_entry, dtbo_fd)?;
+        *self.last_dtbo.lock().unwrap() = Some(dtbo);
+        Ok(())
+    }
+
+    fn last_generated_dtbo(&self) -> binder::Result<File> {
+        let Some(dtbo) = self.last_dtbo.lock().unwrap().clone() else {
+            return Err(anyhow!("No VM DTBO was generated"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        };
+        let mut file = File::from(
+            memfd_create(cstr!("vm_dtbo"), MemFdCreateFlag::MFD_CLOEXEC)
+                .context("Failed to create memfd")
+                .or_service_specific_exception(-1)?,
+        );
+        file.write_all(&dtbo)
+            .and_then(|_| file.rewind())
+            .context("Failed to write VM DTBO to memfd")
+            .or_service_specific_exception(-1)?;
+        Ok(file)
+    }
+}
+
+/// A step of (un)binding a device to a driver, named in the error of the step that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BindStep {
+    Unbind,
+    SetOverride,
+    Probe,
+    VerifyBound,
+    VerifyGroup,
+}
+
+impl fmt::Display for BindStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl BindStep {
+    /// Returns `error` with this step named in its message. The whole chain is flattened into a
+    /// single message, as binder statuses only keep the outermost one.
+    fn failed(self, error: anyhow::Error) -> anyhow::Error {
+        anyhow!("bind step {self} failed: {error:#}")
+    }
+}
+
+type SysfsWriteFn = dyn Fn(&Path, &[u8]) -> io::Result<()> + Send + Sync;
+
+/// The sysfs nodes used to bind devices to drivers, found under `root`, which is "/" except in
+/// tests where a fake sysfs tree is used.
+struct Sysfs {
+    root: PathBuf,
+    write: Box<SysfsWriteFn>,
+}
+
+impl Sysfs {
+    fn new(root: PathBuf) -> Self {
+        Self { root, write: Box::new(|path, value| write(path, value)) }
+    }
+
+    /// Returns the path of the absolute sysfs `path` under the root.
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn check_platform_device(&self, path: &Path) -> binder::Result<()> {
+        if !path.exists() {
+            return Err(anyhow!("no such device {path:?}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+
+        if self.is_pci_device(path) {
+            return Err(anyhow!(
+                "{path:?} is a PCI device, but PCI passthrough isn't handled by the platform VFIO \
+                 handler"
+            ))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+
+        if !path.starts_with(self.path(SYSFS_PLATFORM_DEVICES_PATH)) {
+            return Err(anyhow!("{path:?} is not a platform device"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether `path` looks like a PCI device, i.e. is under the PCI bus or contains a PCI
+    /// address (e.g. `0000:00:1f.0`).
+    fn is_pci_device(&self, path: &Path) -> bool {
+        path.starts_with(self.path(SYSFS_PCI_DEVICES_PATH))
+            || path.components().any(|c| c.as_os_str().to_str().is_some_and(is_pci_bdf))
+    }
+
+    // Try to bind device driver by writing its name to driver_override and triggering driver probe.
+    fn try_bind_driver(&self, path: &Path, driver: &str) -> binder::Result<()> {
+        if Some(driver) == current_driver(path).as_deref() {
+            // already bound
+            return Ok(());
+        }
+
+        // unbind
+        let Some(device) = path.file_name() else {
+            return Err(anyhow!("can't get device name from {path:?}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        };
+        let Some(device_str) = device.to_str() else {
+            return Err(anyhow!("invalid filename {device:?}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        };
+        let unbind_path = path.join("driver/unbind");
+        if unbind_path.exists() {
+            (self.write)(&unbind_path, device_str.as_bytes())
+                .with_context(|| format!("could not unbind {device_str}"))
+                .map_err(|e| BindStep::Unbind.failed(e))
+                .or_service_specific_exception(-1)?;
+        }
+        if path.join("driver").exists() {
+            return Err(BindStep::Unbind.failed(anyhow!("could not unbind {device_str}")))
+                .or_service_specific_exception(-1);
+        }
+
+        // bind to new driver
+        (self.write)(&path.join("driver_override"), driver.as_bytes())
+            .with_context(|| format!("could not bind {device_str} to '{driver}' driver"))
+            .map_err(|e| BindStep::SetOverride.failed(e))
+            .or_service_specific_exception(-1)?;
+
+        (self.write)(&self.path(SYSFS_PLATFORM_DRIVERS_PROBE_PATH), device_str.as_bytes())
+            .with_context(|| format!("could not write {device_str} to drivers-probe"))
+            .map_err(|e| BindStep::Probe.failed(e))
+            .or_service_specific_exception(-1)?;
+
+        // final check
+        let new_driver = current_driver(path);
+        if new_driver.is_none() || Some(driver) != new_driver.as_deref() && driver != DEFAULT_DRIVER
+        {
+            let e = anyhow!("{path:?} still not bound to '{driver}' driver");
+            return Err(BindStep::VerifyBound.failed(e)).or_service_specific_exception(-1);
+        }
+
+        Ok(())
+    }
+
+    fn bind_device(&self, path: &Path) -> binder::Result<()> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("can't canonicalize {path:?}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+
+        self.check_platform_device(&path)?;
+        self.try_bind_driver(&path, VFIO_PLATFORM_DRIVER_NAME)?;
+
+        if get_device_iommu_group(&path).is_none() {
+            let e = anyhow!("can't get iommu group for {path:?}");
+            Err(BindStep::VerifyGroup.failed(e)).or_service_specific_exception(-1)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unbind_device(&self, path: &Path) -> binder::Result<()> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("can't canonicalize {path:?}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+
+        self.check_platform_device(&path)?;
+        self.try_bind_driver(&path, DEFAULT_DRIVER)?;
+
+        if is_bound_to_vfio_driver(&path) {
+            let e = anyhow!("{path:?} still bound to vfio driver");
+            Err(BindStep::VerifyBound.failed(e)).or_service_specific_exception(-1)
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -86,13 +310,7 @@ impl IVfioHandler for VfioHandler {
             return Err(anyhow!("VFIO-platform not supported"))
                 .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
         }
-        devices
-            .iter()
-            .map(|d| {
-                bind_device(Path::new(&d.sysfsPath))?;
-                Ok(BoundDevice::new_binder(d.sysfsPath.clone(), d.dtboLabel.clone()))
-            })
-            .collect::<binder::Result<Vec<_>>>()
+        self.bind_devices(devices)
     }
 
     fn writeVmDtbo(&self, dtbo_fd: &ParcelFileDescriptor) -> binder::Result<()> {
@@ -101,7 +319,6 @@ impl IVfioHandler for VfioHandler {
             .context("Failed to open DTBO partition")
             .or_service_specific_exception(-1)?;
 
-        let dt_table_header = get_dt_table_header(&mut dtbo_img)?;
         let vm_dtbo_idx = system_properties::read("ro.boot.hypervisor.vm_dtbo_idx")
             .context("Failed to read vm_dtbo_idx")
             .or_service_specific_exception(-1)?
@@ -111,14 +328,35 @@ impl IVfioHandler for VfioHandler {
             .parse()
             .context("vm_dtbo_idx is not an integer")
             .or_service_specific_exception(-1)?;
-        let dt_table_entry = get_dt_table_entry(&mut dtbo_img, &dt_table_header, vm_dtbo_idx)?;
-        write_vm_full_dtbo_from_img(&mut dtbo_img, &dt_table_entry, dtbo_fd)?;
-        Ok(())
+        self.write_vm_dtbo(&mut dtbo_img, vm_dtbo_idx, dtbo_fd)
     }
+
+    fn getLastGeneratedDtbo(&self) -> binder::Result<ParcelFileDescriptor> {
+        Ok(ParcelFileDescriptor::new(self.last_generated_dtbo()?))
+    }
+}
+
+/// Returns whether `name` is a PCI address in the `domain:bus:device.function` form.
+fn is_pci_bdf(name: &str) -> bool {
+    let is_hex = |s: &str, len| s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit());
+    let mut parts = name.split(':');
+    let (Some(domain), Some(bus), Some(dev_fn), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let Some((dev, func)) = dev_fn.split_once('.') else {
+        return false;
+    };
+    is_hex(domain, 4)
+        && is_hex(bus, 2)
+        && is_hex(dev, 2)
+        && matches!(func.as_bytes(), [b'0'..=b'7'])
 }
 
 const DEV_VFIO_PATH: &str = "/dev/vfio/vfio";
 const SYSFS_PLATFORM_DEVICES_PATH: &str = "/sys/devices/platform/";
+const SYSFS_PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices/";
 const VFIO_PLATFORM_DRIVER_PATH: &str = "/sys/bus/platform/drivers/vfio-platform";
 const SYSFS_PLATFORM_DRIVERS_PROBE_PATH: &str = "/sys/bus/platform/drivers_probe";
 const DT_TABLE_MAGIC: u32 = 0xd7b7ab1e;
@@ -173,20 +411,6 @@ static IS_VFIO_SUPPORTED: LazyLock<bool> = LazyLock::new(|| {
     Path::new(DEV_VFIO_PATH).exists() && Path::new(VFIO_PLATFORM_DRIVER_PATH).exists()
 });
 
-fn check_platform_device(path: &Path) -> binder::Result<()> {
-    if !path.exists() {
-        return Err(anyhow!("no such device {path:?}"))
-            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
-    }
-
-    if !path.starts_with(SYSFS_PLATFORM_DEVICES_PATH) {
-        return Err(anyhow!("{path:?} is not a platform device"))
-            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
-    }
-
-    Ok(())
-}
-
 fn get_device_iommu_group(path: &Path) -> Option<u64> {
     let group_path = read_link(path.join("iommu_group")).ok()?;
     let group = group_path.file_name()?;
@@ -199,81 +423,95 @@ fn current_driver(path: &Path) -> Option<String> {
     bound_driver.to_str().map(str::to_string)
 }
 
-// Try to bind device driver by writing its name to driver_override and triggering driver probe.
-fn try_bind_driver(path: &Path, driver: &str) -> binder::Result<()> {
-    if Some(driver) == current_driver(path).as_deref() {
-        // already bound
-        return Ok(());
-    }
+fn is_bound_to_vfio_driver(path: &Path) -> bool {
+    Some(VFIO_PLATFORM_DRIVER_NAME) == current_driver(path).as_deref()
+}
 
-    // unbind
-    let Some(device) = path.file_name() else {
-        return Err(anyhow!("can't get device name from {path:?}"))
-            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
-    };
-    let Some(device_str) = device.to_str() else {
-        return Err(anyhow!("invalid filename {device:?}"))
-            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+/// Reads the DT property at `path`, as exposed by sysfs, as big-endian 32-bit cells, or returns
+/// None if the property doesn't exist.
+fn read_dt_cells(path: &Path) -> binder::Result<Option<Vec<u32>>> {
+    let value = match fs::read(path) {
+        Ok(value) => value,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("can't read {path:?}"))
+                .or_service_specific_exception(-1)
+        }
     };
-    let unbind_path = path.join("driver/unbind");
-    if unbind_path.exists() {
-        write(&unbind_path, device_str.as_bytes())
-            .with_context(|| format!("could not unbind {device_str}"))
-            .or_service_specific_exception(-1)?;
-    }
-    if path.join("driver").exists() {
-        return Err(anyhow!("could not unbind {device_str}")).or_service_specific_exception(-1);
-    }
-
-    // bind to new driver
-    write(path.join("driver_override"), driver.as_bytes())
-        .with_context(|| format!("could not bind {device_str} to '{driver}' driver"))
-        .or_service_specific_exception(-1)?;
-
-    write(SYSFS_PLATFORM_DRIVERS_PROBE_PATH, device_str.as_bytes())
-        .with_context(|| format!("could not write {device_str} to drivers-probe"))
-        .or_service_specific_exception(-1)?;
-
-    // final check
-    let new_driver = current_driver(path);
-    if new_driver.is_none() || Some(driver) != new_driver.as_deref() && driver != DEFAULT_DRIVER {
-        return Err(anyhow!("{path:?} still not bound to '{driver}' driver"))
+    if value.len() % size_of::<u32>() != 0 {
+        return Err(anyhow!("{path:?} isn't made of 32-bit cells"))
             .or_service_specific_exception(-1);
     }
-
-    Ok(())
+    Ok(Some(
+        value
+            .chunks_exact(size_of::<u32>())
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect(),
+    ))
 }
 
-fn bind_device(path: &Path) -> binder::Result<()> {
-    let path = path
-        .canonicalize()
-        .with_context(|| format!("can't canonicalize {path:?}"))
-        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
-
-    check_platform_device(&path)?;
-    try_bind_driver(&path, VFIO_PLATFORM_DRIVER_NAME)?;
+/// Reads the interrupt specifiers of the bound platform device at `path`, from the `interrupts`
+/// property of its DT node.
+///
+/// Returns no cells if the device has no interrupts.
+#[allow(dead_code)] // TODO: Use it once the VM DTBO is generated rather than read from dtbo.img.
+fn read_device_interrupts(path: &Path) -> binder::Result<Vec<u32>> {
+    Ok(read_dt_cells(&path.join("of_node/interrupts"))?.unwrap_or_default())
+}
 
-    if get_device_iommu_group(&path).is_none() {
-        Err(anyhow!("can't get iommu group for {path:?}")).or_service_specific_exception(-1)
-    } else {
-        Ok(())
+/// Reads the `#address-cells` or `#size-cells` property of the DT node at `path`, or `default`.
+///
+/// Counts above 2 are rejected, as the cells of an address or size must fit in a u64.
+fn read_dt_cell_count(path: &Path, default: usize) -> binder::Result<usize> {
+    match read_dt_cells(path)?.as_deref() {
+        None => Ok(default),
+        Some(&[n]) if n <= 2 => Ok(n.try_into().unwrap()),
+        Some(_) => Err(anyhow!("unsupported {path:?}")).or_service_specific_exception(-1),
     }
 }
 
-fn unbind_device(path: &Path) -> binder::Result<()> {
-    let path = path
-        .canonicalize()
-        .with_context(|| format!("can't canonicalize {path:?}"))
-        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+/// Reads the MMIO ranges of the bound platform device at `path`, as (address, size) pairs, from
+/// the `reg` property of its DT node, interpreted with the cell counts of its parent node, which
+/// mustn't be above 2.
+#[allow(dead_code)] // TODO: Use it once the VM DTBO is generated rather than read from dtbo.img.
+fn read_device_reg(path: &Path) -> binder::Result<Vec<(u64, u64)>> {
+    // Dereference the of_node link before walking up the DT.
+    let parent = path.join("of_node/..");
+    let address_cells = read_dt_cell_count(&parent.join("#address-cells"), 2)?;
+    let size_cells = read_dt_cell_count(&parent.join("#size-cells"), 1)?;
 
-    check_platform_device(&path)?;
-    try_bind_driver(&path, DEFAULT_DRIVER)?;
+    let reg_path = path.join("of_node/reg");
+    let Some(cells) = read_dt_cells(&reg_path)? else {
+        return Ok(Vec::new());
+    };
+    let entry_cells = address_cells + size_cells;
+    if entry_cells == 0 || cells.len() % entry_cells != 0 {
+        return Err(anyhow!("{reg_path:?} doesn't match #address-cells and #size-cells"))
+            .or_service_specific_exception(-1);
+    }
+    let to_u64 = |cells: &[u32]| cells.iter().fold(0, |acc, &c| (acc << 32) | u64::from(c));
+    let ranges: Vec<_> = cells
+        .chunks_exact(entry_cells)
+        .map(|entry| {
+            let (address, size) = entry.split_at(address_cells);
+            (to_u64(address), to_u64(size))
+        })
+        .collect();
 
-    if Some(VFIO_PLATFORM_DRIVER_NAME) == current_driver(&path).as_deref() {
-        Err(anyhow!("{path:?} still bound to vfio driver")).or_service_specific_exception(-1)
-    } else {
-        Ok(())
+    if ranges.iter().any(|&(addr, size)| size == 0 || addr.checked_add(size).is_none()) {
+        return Err(anyhow!("{reg_path:?} has an invalid range")).or_service_specific_exception(-1);
+    }
+    let mut sorted = ranges.clone();
+    sorted.sort_unstable();
+    for pair in sorted.windows(2) {
+        let ((addr, size), (next, _)) = (pair[0], pair[1]);
+        if addr + size > next {
+            return Err(anyhow!("{reg_path:?} has overlapping ranges"))
+                .or_service_specific_exception(-1);
+        }
     }
+    Ok(ranges)
 }
 
 fn get_dtbo_img_path() -> binder::Result<PathBuf> {
@@ -334,11 +572,12 @@ fn get_dt_table_entry(
     Ok(dt_table_entry)
 }
 
+/// Writes the DT of `entry` to `dtbo_fd`, returning its contents.
 fn write_vm_full_dtbo_from_img(
     dtbo_img_file: &mut File,
     entry: &DtTableEntry,
     dtbo_fd: &ParcelFileDescriptor,
-) -> binder::Result<()> {
+) -> binder::Result<Vec<u8>> {
     let dt_size = entry
         .dt_size
         .get()
@@ -359,5 +598,450 @@ fn write_vm_full_dtbo_from_img(
         .write_all(&buffer)
         .context("Failed to write dtbo file")
         .or_service_specific_exception(-1)?;
-    Ok(())
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string, remove_file};
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    const DEVICE: &str = "fake-device";
+    const DEVICE_DRIVER: &str = "fake-driver";
+
+    /// A fake sysfs tree with a single platform device, bound to `DEVICE_DRIVER`.
+    struct FakeSysfs {
+        _dir: TempDir,
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let root = dir.path().canonicalize().unwrap();
+            let fake = Self { _dir: dir, root };
+            for driver in [DEVICE_DRIVER, VFIO_PLATFORM_DRIVER_NAME] {
+                let driver_dir = fake.driver_dir(driver);
+                create_dir_all(&driver_dir).unwrap();
+                write(driver_dir.join("unbind"), "").unwrap();
+            }
+            write(fake.sysfs().path(SYSFS_PLATFORM_DRIVERS_PROBE_PATH), "").unwrap();
+            create_dir_all(fake.device()).unwrap();
+            write(fake.device().join("driver_override"), "").unwrap();
+            symlink("../../../kernel/iommu_groups/3", fake.device().join("iommu_group")).unwrap();
+            symlink(fake.driver_dir(DEVICE_DRIVER), fake.device().join("driver")).unwrap();
+            fake
+        }
+
+        fn device(&self) -> PathBuf {
+            self.sysfs().path(SYSFS_PLATFORM_DEVICES_PATH).join(DEVICE)
+        }
+
+        fn driver_dir(&self, driver: &str) -> PathBuf {
+            self.root.join("sys/bus/platform/drivers").join(driver)
+        }
+
+        /// Returns a `Sysfs` whose writes behave like the kernel's for the fake tree.
+        fn sysfs(&self) -> Sysfs {
+            self.sysfs_with_hook(|_| None)
+        }
+
+        /// Returns a `Sysfs` like `sysfs()` but where `hook` may handle writes first, e.g. to
+        /// inject failures.
+        fn sysfs_with_hook(
+            &self,
+            hook: impl Fn(&Path) -> Option<io::Result<()>> + Send + Sync + 'static,
+        ) -> Sysfs {
+            let mut sysfs = Sysfs::new(self.root.clone());
+            let device = sysfs.path(SYSFS_PLATFORM_DEVICES_PATH).join(DEVICE);
+            let probe = sysfs.path(SYSFS_PLATFORM_DRIVERS_PROBE_PATH);
+            let drivers = self.root.join("sys/bus/platform/drivers");
+            sysfs.write = Box::new(move |path, value| {
+                if let Some(result) = hook(path) {
+                    result
+                } else if path.ends_with("driver/unbind") {
+                    remove_file(device.join("driver"))
+                } else if path == probe {
+                    let driver = match read_to_string(device.join("driver_override"))?.as_str() {
+                        DEFAULT_DRIVER => DEVICE_DRIVER.to_owned(),
+                        driver => driver.to_owned(),
+                    };
+                    symlink(drivers.join(driver), device.join("driver"))
+                } else {
+                    write(path, value)
+                }
+            });
+            sysfs
+        }
+    }
+
+    #[test]
+    fn sysfs_paths_are_under_root() {
+        let sysfs = Sysfs::new(PathBuf::from("/tmp/fake"));
+
+        assert_eq!(
+            sysfs.path(SYSFS_PLATFORM_DRIVERS_PROBE_PATH),
+            Path::new("/tmp/fake/sys/bus/platform/drivers_probe")
+        );
+        assert_eq!(
+            Sysfs::new(PathBuf::from("/")).path(SYSFS_PLATFORM_DRIVERS_PROBE_PATH),
+            Path::new(SYSFS_PLATFORM_DRIVERS_PROBE_PATH)
+        );
+    }
+
+    #[test]
+    fn check_platform_device_accepts_device_under_root() {
+        let fake = FakeSysfs::new();
+
+        assert!(fake.sysfs().check_platform_device(&fake.device()).is_ok());
+    }
+
+    #[test]
+    fn check_platform_device_rejects_missing_device() {
+        let fake = FakeSysfs::new();
+        let path = fake.device().with_file_name("missing-device");
+
+        let e = fake.sysfs().check_platform_device(&path).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+
+    #[test]
+    fn check_platform_device_rejects_non_platform_device() {
+        let fake = FakeSysfs::new();
+        let path = fake.root.join("sys/devices/virtual/fake-device");
+        create_dir_all(&path).unwrap();
+
+        let e = fake.sysfs().check_platform_device(&path).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+
+    #[test]
+    fn check_platform_device_rejects_pci_device() {
+        let fake = FakeSysfs::new();
+        let path = fake.root.join("sys/bus/pci/devices/0000:00:1f.0");
+        create_dir_all(&path).unwrap();
+
+        let e = fake.sysfs().check_platform_device(&path).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT);
+        assert!(e.get_description().contains("PCI passthrough"), "{}", e.get_description());
+    }
+
+    #[test]
+    fn check_platform_device_rejects_pci_device_by_address() {
+        let fake = FakeSysfs::new();
+        let path = fake.root.join("sys/devices/pci0000:00/0000:00:1f.0");
+        create_dir_all(&path).unwrap();
+
+        let e = fake.sysfs().check_platform_device(&path).unwrap_err();
+
+        assert!(e.get_description().contains("PCI passthrough"), "{}", e.get_description());
+    }
+
+    #[test]
+    fn pci_bdf_is_recognized() {
+        assert!(is_pci_bdf("0000:00:1f.0"));
+        assert!(is_pci_bdf("0001:af:00.7"));
+        assert!(!is_pci_bdf("pci0000:00"));
+        assert!(!is_pci_bdf("0000:00:1f.8"));
+        assert!(!is_pci_bdf("fake-device"));
+    }
+
+    #[test]
+    fn bind_device_binds_to_vfio_driver() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs();
+        assert!(!is_bound_to_vfio_driver(&fake.device()));
+
+        sysfs.bind_device(&fake.device()).unwrap();
+
+        assert!(is_bound_to_vfio_driver(&fake.device()));
+        assert_eq!(get_device_iommu_group(&fake.device()), Some(3));
+        assert_eq!(read_to_string(fake.device().join("driver_override")).unwrap(), "vfio-platform");
+    }
+
+    #[test]
+    fn bind_device_is_noop_when_already_bound() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs();
+        sysfs.bind_device(&fake.device()).unwrap();
+
+        sysfs.bind_device(&fake.device()).unwrap();
+
+        assert!(is_bound_to_vfio_driver(&fake.device()));
+    }
+
+    #[test]
+    fn unbind_device_restores_default_driver() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs();
+        sysfs.bind_device(&fake.device()).unwrap();
+
+        sysfs.unbind_device(&fake.device()).unwrap();
+
+        assert!(!is_bound_to_vfio_driver(&fake.device()));
+        assert_eq!(current_driver(&fake.device()).as_deref(), Some(DEVICE_DRIVER));
+    }
+
+    #[test]
+    fn bind_device_fails_without_iommu_group() {
+        let fake = FakeSysfs::new();
+        remove_file(fake.device().join("iommu_group")).unwrap();
+
+        let e = fake.sysfs().bind_device(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+        assert_failed_at(&e, BindStep::VerifyGroup);
+    }
+
+    fn assert_failed_at(e: &binder::Status, step: BindStep) {
+        let description = e.get_description();
+        assert!(description.contains(&format!("bind step {step} failed")), "{description}");
+    }
+
+    fn failing_write(
+        suffix: &'static str,
+    ) -> impl Fn(&Path) -> Option<io::Result<()>> + Send + Sync {
+        move |path| path.ends_with(suffix).then(|| Err(io::Error::other("injected failure")))
+    }
+
+    #[test]
+    fn bind_device_reports_failed_unbind_write() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs_with_hook(failing_write("driver/unbind"));
+
+        let e = sysfs.bind_device(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+        assert_failed_at(&e, BindStep::Unbind);
+    }
+
+    #[test]
+    fn bind_device_reports_driver_still_bound_after_unbind() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs_with_hook(|path| path.ends_with("driver/unbind").then_some(Ok(())));
+
+        let e = sysfs.bind_device(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+        assert_failed_at(&e, BindStep::Unbind);
+    }
+
+    #[test]
+    fn bind_device_reports_failed_driver_override_write() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs_with_hook(failing_write("driver_override"));
+
+        let e = sysfs.bind_device(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+        assert_failed_at(&e, BindStep::SetOverride);
+    }
+
+    #[test]
+    fn bind_device_reports_failed_probe_write() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs_with_hook(failing_write("drivers_probe"));
+
+        let e = sysfs.bind_device(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+        assert_failed_at(&e, BindStep::Probe);
+    }
+
+    #[test]
+    fn bind_device_reports_device_not_bound_after_probe() {
+        let fake = FakeSysfs::new();
+        let sysfs = fake.sysfs_with_hook(|path| path.ends_with("drivers_probe").then_some(Ok(())));
+
+        let e = sysfs.bind_device(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+        assert_failed_at(&e, BindStep::VerifyBound);
+    }
+
+    /// A DT blob, which only needs a valid FDT magic for these tests.
+    const VM_DTBO: &[u8] = &[0xd0, 0x0d, 0xfe, 0xed, 0x00, 0x00, 0x00, 0x08];
+
+    /// Returns a dtbo.img made of the given DTs.
+    fn dtbo_img(dts: &[&[u8]]) -> File {
+        let entries_offset = size_of::<DtTableHeader>();
+        let mut dt_offset = entries_offset + dts.len() * size_of::<DtTableEntry>();
+        let total_size = dt_offset + dts.iter().map(|dt| dt.len()).sum::<usize>();
+        let header = [
+            DT_TABLE_MAGIC,
+            total_size.try_into().unwrap(),
+            size_of::<DtTableHeader>().try_into().unwrap(),
+            size_of::<DtTableEntry>().try_into().unwrap(),
+            dts.len().try_into().unwrap(),
+            entries_offset.try_into().unwrap(),
+            4096,
+            0,
+        ];
+        let mut img: Vec<u8> = header.iter().flat_map(|v: &u32| v.to_be_bytes()).collect();
+        for dt in dts {
+            let entry =
+                [dt.len().try_into().unwrap(), dt_offset.try_into().unwrap(), 0, 0, 0, 0, 0, 0];
+            img.extend(entry.iter().flat_map(|v: &u32| v.to_be_bytes()));
+            dt_offset += dt.len();
+        }
+        img.extend(dts.concat());
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&img).unwrap();
+        file
+    }
+
+    #[test]
+    fn last_generated_dtbo_is_kept_until_unbind() {
+        let fake = FakeSysfs::new();
+        let handler = VfioHandler::new(fake.sysfs());
+        let devices = [VfioDev {
+            sysfsPath: fake.device().to_str().unwrap().to_owned(),
+            dtboLabel: "fake_device".to_owned(),
+        }];
+        let bound = handler.bind_devices(&devices).unwrap();
+        let mut img = dtbo_img(&[b"other DT", VM_DTBO]);
+        let mut written = tempfile::tempfile().unwrap();
+
+        handler
+            .write_vm_dtbo(&mut img, 1, &ParcelFileDescriptor::new(written.try_clone().unwrap()))
+            .unwrap();
+
+        let mut dtbo = Vec::new();
+        handler.last_generated_dtbo().unwrap().read_to_end(&mut dtbo).unwrap();
+        assert_eq!(dtbo, VM_DTBO);
+        assert_eq!(u32::from_be_bytes(dtbo[..4].try_into().unwrap()), 0xd00dfeed);
+        let mut written_dtbo = Vec::new();
+        written.rewind().unwrap();
+        written.read_to_end(&mut written_dtbo).unwrap();
+        assert_eq!(written_dtbo, dtbo);
+
+        drop(bound);
+
+        assert!(!is_bound_to_vfio_driver(&fake.device()));
+        assert!(handler.last_generated_dtbo().is_err());
+    }
+
+    #[test]
+    fn last_generated_dtbo_is_unavailable_before_write() {
+        let fake = FakeSysfs::new();
+        let handler = VfioHandler::new(fake.sysfs());
+
+        let error = handler.last_generated_dtbo().unwrap_err();
+
+        assert_eq!(error.exception_code(), ExceptionCode::ILLEGAL_STATE);
+    }
+
+    #[test]
+    fn device_interrupts_are_read_from_of_node() {
+        let fake = FakeSysfs::new();
+        let of_node = fake.device().join("of_node");
+        create_dir_all(&of_node).unwrap();
+        let cells: [u32; 6] = [0, 0x2a, 4, 0, 0x2b, 1];
+        write(of_node.join("interrupts"), cells.map(u32::to_be_bytes).concat()).unwrap();
+
+        assert_eq!(read_device_interrupts(&fake.device()).unwrap(), cells);
+    }
+
+    #[test]
+    fn device_interrupts_are_empty_without_interrupts() {
+        let fake = FakeSysfs::new();
+
+        assert_eq!(read_device_interrupts(&fake.device()).unwrap(), Vec::<u32>::new());
+
+        create_dir_all(fake.device().join("of_node")).unwrap();
+
+        assert_eq!(read_device_interrupts(&fake.device()).unwrap(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn device_interrupts_are_rejected_when_malformed() {
+        let fake = FakeSysfs::new();
+        let of_node = fake.device().join("of_node");
+        create_dir_all(&of_node).unwrap();
+        write(of_node.join("interrupts"), [0, 0, 0, 0x2a, 0]).unwrap();
+
+        let e = read_device_interrupts(&fake.device()).unwrap_err();
+
+        assert_eq!(e.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+    }
+
+    /// Places the DT node of the fake device under a parent node with the given cell counts.
+    fn create_device_dt_node(fake: &FakeSysfs, cell_counts: Option<(u32, u32)>) -> PathBuf {
+        let parent = fake.root.join("firmware/devicetree/base/bus");
+        let node = parent.join("device");
+        create_dir_all(&node).unwrap();
+        if let Some((address_cells, size_cells)) = cell_counts {
+            write(parent.join("#address-cells"), address_cells.to_be_bytes()).unwrap();
+            write(parent.join("#size-cells"), size_cells.to_be_bytes()).unwrap();
+        }
+        symlink(&node, fake.device().join("of_node")).unwrap();
+        node
+    }
+
+    #[test]
+    fn device_reg_is_read_with_single_range() {
+        let fake = FakeSysfs::new();
+        let node = create_device_dt_node(&fake, Some((1, 1)));
+        write(node.join("reg"), [0x9000_0000u32, 0x1000].map(u32::to_be_bytes).concat()).unwrap();
+
+        assert_eq!(read_device_reg(&fake.device()).unwrap(), [(0x9000_0000, 0x1000)]);
+    }
+
+    #[test]
+    fn device_reg_is_read_with_multiple_ranges() {
+        let fake = FakeSysfs::new();
+        let node = create_device_dt_node(&fake, Some((2, 2)));
+        let cells: [u32; 8] = [0x1, 0x0, 0x0, 0x2000, 0x0, 0x9000_0000, 0x0, 0x1000];
+        write(node.join("reg"), cells.map(u32::to_be_bytes).concat()).unwrap();
+
+        assert_eq!(
+            read_device_reg(&fake.device()).unwrap(),
+            [(0x1_0000_0000, 0x2000), (0x9000_0000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn device_reg_uses_default_cell_counts() {
+        let fake = FakeSysfs::new();
+        let node = create_device_dt_node(&fake, None);
+        write(node.join("reg"), [0x0u32, 0x9000_0000, 0x1000].map(u32::to_be_bytes).concat())
+            .unwrap();
+
+        assert_eq!(read_device_reg(&fake.device()).unwrap(), [(0x9000_0000, 0x1000)]);
+    }
+
+    #[test]
+    fn device_reg_is_rejected_with_overlapping_ranges() {
+        let fake = FakeSysfs::new();
+        let node = create_device_dt_node(&fake, Some((1, 1)));
+        let cells: [u32; 4] = [0x9000_0000, 0x2000, 0x9000_1000, 0x1000];
+        write(node.join("reg"), cells.map(u32::to_be_bytes).concat()).unwrap();
+
+        assert!(read_device_reg(&fake.device()).is_err());
+    }
+
+    #[test]
+    fn device_reg_is_rejected_with_more_than_two_address_cells() {
+        let fake = FakeSysfs::new();
+        let node = create_device_dt_node(&fake, Some((3, 1)));
+        write(node.join("reg"), [0, 0, 0x9000_0000u32, 0x1000].map(u32::to_be_bytes).concat())
+            .unwrap();
+
+        assert!(read_device_reg(&fake.device()).is_err());
+    }
+
+    #[test]
+    fn device_reg_is_rejected_with_empty_range() {
+        let fake = FakeSysfs::new();
+        let node = create_device_dt_node(&fake, Some((1, 1)));
+        write(node.join("reg"), [0x9000_0000u32, 0].map(u32::to_be_bytes).concat()).unwrap();
+
+        assert!(read_device_reg(&fake.device()).is_err());
+    }
 }
@@ -188,6 +188,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         encryptedStorageImage: storage,
         payload,
         debugLevel: config.debug.debug,
+        debugPolicy: None,
         protectedVm: config.common.protected,
         memoryMib: config.common.mem.unwrap_or(0) as i32, // 0 means use the VM default
         cpuTopology: config.common.cpu_topology,
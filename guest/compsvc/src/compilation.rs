@@ -17,11 +17,14 @@
 use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, info, warn};
 use minijail::{self, Minijail};
+use nix::unistd::{access, AccessFlags};
 use regex::Regex;
 use rustutils::system_properties;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
 use std::path::{self, Path, PathBuf};
 use std::process::Command;
 
@@ -38,7 +41,13 @@ use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
 };
 use compos_common::odrefresh::ExitCode;
 
-const FD_SERVER_PORT: i32 = 3264; // TODO: support dynamic port
+pub const FD_SERVER_PORT: i32 = 3264; // TODO: support dynamic port
+
+const DEX2OAT_PATH: &str = "/apex/com.android.art/bin/dex2oat64";
+const BOOT_PROFILE_PATH: &str = "/apex/com.android.art/etc/boot-image.prof";
+
+/// Directory under which alternate output directories must be. It is a tmpfs in Microdroid.
+pub const ALLOWED_OUTPUT_ROOT: &str = "/data";
 
 fn validate_args(args: &OdrefreshArgs) -> Result<()> {
     if args.compilationMode != CompilationMode::NORMAL_COMPILE {
@@ -69,6 +78,18 @@ fn validate_args(args: &OdrefreshArgs) -> Result<()> {
     Ok(())
 }
 
+/// Pulls the files needed by every compilation into the page cache, so that the first odrefresh
+/// run doesn't have to. The files are streamed rather than read whole, as dex2oat is large.
+pub fn warm_up() -> Result<()> {
+    for path in [DEX2OAT_PATH, BOOT_PROFILE_PATH] {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {path}"))?;
+        let size = io::copy(&mut file, &mut io::sink())
+            .with_context(|| format!("Failed to read {path}"))?;
+        debug!("Loaded {path} ({size} bytes)");
+    }
+    Ok(())
+}
+
 pub fn odrefresh<F>(
     odrefresh_path: &Path,
     args: &OdrefreshArgs,
@@ -127,7 +148,11 @@ where
         debug!("SYSTEM_EXT_ROOT={:?}", &system_ext_root);
     }
 
-    let art_apex_data = mountpoint.join(args.outputDirFd.to_string());
+    let art_apex_data = resolve_output_dir(
+        &args.outputDirPath,
+        Path::new(ALLOWED_OUTPUT_ROOT),
+        mountpoint.join(args.outputDirFd.to_string()),
+    )?;
     odrefresh_vars.set("ART_APEX_DATA", path_to_str(&art_apex_data)?);
     debug!("ART_APEX_DATA={:?}", &art_apex_data);
 
@@ -176,6 +201,29 @@ where
     Ok(exit_code)
 }
 
+/// Returns the requested output directory, once validated, or the default one if none was
+/// requested.
+fn resolve_output_dir(requested: &str, allowed_root: &Path, default: PathBuf) -> Result<PathBuf> {
+    if requested.is_empty() {
+        return Ok(default);
+    }
+    // Resolve symlinks and "..", to check where the artifacts would really end up.
+    let path = Path::new(requested)
+        .canonicalize()
+        .with_context(|| format!("Invalid output directory {requested}"))?;
+    let allowed_root = allowed_root.canonicalize().context("Invalid allowed output root")?;
+    if !path.starts_with(&allowed_root) {
+        bail!("Output directory {path:?} isn't under {allowed_root:?}");
+    }
+    if !path.is_dir() {
+        bail!("Output directory {path:?} isn't a directory");
+    }
+    access(&path, AccessFlags::W_OK)
+        .with_context(|| format!("Output directory {path:?} isn't writable"))?;
+    info!("Using output directory {path:?}");
+    Ok(path)
+}
+
 fn path_to_str(path: &Path) -> Result<&str> {
     path.to_str().ok_or_else(|| anyhow!("Bad path {:?}", path))
 }
@@ -251,3 +299,66 @@ impl EnvMap {
         self.0.into_iter().map(|(k, v)| k + "=" + &v).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DEFAULT_OUTPUT_DIR: &str = "/default/output";
+
+    fn resolve(requested: &Path, allowed_root: &Path) -> Result<PathBuf> {
+        resolve_output_dir(requested.to_str().unwrap(), allowed_root, DEFAULT_OUTPUT_DIR.into())
+    }
+
+    #[test]
+    fn output_dir_defaults_when_not_requested() -> Result<()> {
+        let root = TempDir::new()?;
+
+        let output_dir = resolve_output_dir("", root.path(), DEFAULT_OUTPUT_DIR.into())?;
+
+        assert_eq!(output_dir, Path::new(DEFAULT_OUTPUT_DIR));
+        Ok(())
+    }
+
+    #[test]
+    fn output_dir_under_allowed_root_is_accepted() -> Result<()> {
+        let root = TempDir::new()?;
+        let requested = root.path().join("out");
+        fs::create_dir(&requested)?;
+
+        let output_dir = resolve(&requested, root.path())?;
+
+        assert_eq!(output_dir, requested.canonicalize()?);
+        Ok(())
+    }
+
+    #[test]
+    fn output_dir_outside_allowed_root_is_rejected() -> Result<()> {
+        let root = TempDir::new()?;
+        let other = TempDir::new()?;
+        fs::create_dir(root.path().join("out"))?;
+
+        assert!(resolve(other.path(), root.path()).is_err());
+        assert!(resolve(&root.path().join("out/../.."), root.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_output_dir_is_rejected() -> Result<()> {
+        let root = TempDir::new()?;
+
+        assert!(resolve(&root.path().join("missing"), root.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn output_file_is_rejected() -> Result<()> {
+        let root = TempDir::new()?;
+        let file = root.path().join("file");
+        fs::write(&file, b"")?;
+
+        assert!(resolve(&file, root.path()).is_err());
+        Ok(())
+    }
+}
@@ -14,14 +14,77 @@
  * limitations under the License.
  */
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
 use std::io::Write;
-use std::process::{Command, Stdio};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
 
 const COMPOS_KEY_HELPER_PATH: &str = "/apex/com.android.compos/bin/compos_key_helper";
 
-pub fn get_public_key() -> Result<Vec<u8>> {
-    get_data_from_helper("public_key")
+/// Length of an Ed25519 public key, see https://datatracker.ietf.org/doc/html/rfc8032.
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+/// Reasons why the signing key material isn't usable.
+#[derive(Debug)]
+pub enum KeyError {
+    /// The helper was killed before it could derive the key. This is how it fails when the VM
+    /// instance secret the key is derived from can't be obtained, as the VM payload API aborts.
+    Missing,
+    /// The key couldn't be obtained from the helper, e.g. because the helper itself is missing or
+    /// failed, or is malformed.
+    Invalid(anyhow::Error),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "Signing key material is missing"),
+            Self::Invalid(e) => write!(f, "Signing key material is invalid: {e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Returns the public key of the signing key, which is all that's needed to verify the
+/// signatures of the artifacts. The private key never leaves the helper.
+pub fn get_public_key() -> Result<Vec<u8>, KeyError> {
+    get_public_key_with_helper(Path::new(COMPOS_KEY_HELPER_PATH))
+}
+
+/// Checks that the signing key can be loaded, so that we don't start serving requests which
+/// would fail later on.
+pub fn check_key_material() -> Result<(), KeyError> {
+    check_key_material_with_helper(Path::new(COMPOS_KEY_HELPER_PATH))
+}
+
+fn check_key_material_with_helper(helper_path: &Path) -> Result<(), KeyError> {
+    get_public_key_with_helper(helper_path).map(|_| ())
+}
+
+fn get_public_key_with_helper(helper_path: &Path) -> Result<Vec<u8>, KeyError> {
+    let result = run_helper(helper_path, "public_key").map_err(KeyError::Invalid)?;
+    if result.status.code().is_none() {
+        // Terminated by a signal rather than exiting with an error.
+        return Err(KeyError::Missing);
+    }
+    if !result.status.success() {
+        return Err(KeyError::Invalid(anyhow!("Helper failed: {:?}", result)));
+    }
+    check_public_key(&result.stdout)?;
+    Ok(result.stdout)
+}
+
+fn check_public_key(public_key: &[u8]) -> Result<(), KeyError> {
+    if public_key.len() != ED25519_PUBLIC_KEY_LEN {
+        return Err(KeyError::Invalid(anyhow!(
+            "Unexpected public key length: {} (expected {})",
+            public_key.len(),
+            ED25519_PUBLIC_KEY_LEN
+        )));
+    }
+    Ok(())
 }
 
 pub fn get_attestation_chain() -> Result<Vec<u8>> {
@@ -29,19 +92,28 @@ pub fn get_attestation_chain() -> Result<Vec<u8>> {
 }
 
 fn get_data_from_helper(command: &str) -> Result<Vec<u8>> {
-    let child = Command::new(COMPOS_KEY_HELPER_PATH)
-        .arg(command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-    let result = child.wait_with_output()?;
+    get_data_from_helper_at(Path::new(COMPOS_KEY_HELPER_PATH), command)
+}
+
+fn get_data_from_helper_at(helper_path: &Path, command: &str) -> Result<Vec<u8>> {
+    let result = run_helper(helper_path, command)?;
     if !result.status.success() {
         bail!("Helper failed: {:?}", result);
     }
     Ok(result.stdout)
 }
 
+fn run_helper(helper_path: &Path, command: &str) -> Result<Output> {
+    let child = Command::new(helper_path)
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", helper_path.display()))?;
+    Ok(child.wait_with_output()?)
+}
+
 pub fn sign(data: &[u8]) -> Result<Vec<u8>> {
     let mut child = Command::new(COMPOS_KEY_HELPER_PATH)
         .arg("sign")
@@ -70,3 +142,90 @@ pub fn sign(data: &[u8]) -> Result<Vec<u8>> {
     });
     Err(result.unwrap_err())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn missing_helper_is_classified_as_invalid() {
+        // A helper missing from the APEX is a broken build, not an unprovisioned VM.
+        let result = check_key_material_with_helper(Path::new("/does/not/exist/key_helper"));
+
+        assert!(matches!(result, Err(KeyError::Invalid(_))));
+    }
+
+    #[test]
+    fn aborted_helper_is_classified_as_missing() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let helper_path = write_helper(dir.path(), "kill -ABRT $$\n");
+
+        let result = check_key_material_with_helper(&helper_path);
+
+        assert!(matches!(result, Err(KeyError::Missing)));
+    }
+
+    #[test]
+    fn failing_helper_is_classified_as_invalid() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let helper_path = failing_helper(dir.path(), 1);
+
+        let result = check_key_material_with_helper(&helper_path);
+
+        assert!(matches!(result, Err(KeyError::Invalid(_))));
+    }
+
+    #[test]
+    fn malformed_public_key_is_classified_as_invalid() {
+        assert!(matches!(check_public_key(&[]), Err(KeyError::Invalid(_))));
+        assert!(matches!(check_public_key(&[0; 64]), Err(KeyError::Invalid(_))));
+    }
+
+    #[test]
+    fn well_formed_public_key_is_accepted() {
+        assert!(check_public_key(&[0; ED25519_PUBLIC_KEY_LEN]).is_ok());
+    }
+
+    /// Writes a helper running `script` and returns its path.
+    fn write_helper(dir: &Path, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let helper_path = dir.join("compos_key_helper");
+        std::fs::write(&helper_path, format!("#!/system/bin/sh\n{script}")).unwrap();
+        std::fs::set_permissions(&helper_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        helper_path
+    }
+
+    /// Writes a helper which prints `public_key` for the "public_key" command.
+    fn fake_helper(dir: &Path, public_key: &[u8]) -> PathBuf {
+        let key_path = dir.join("public_key");
+        std::fs::write(&key_path, public_key).unwrap();
+        write_helper(
+            dir,
+            &format!("[ \"$1\" = public_key ] && exec cat {}\nexit 1\n", key_path.display()),
+        )
+    }
+
+    /// Writes a helper which fails with `exit_code` for any command.
+    fn failing_helper(dir: &Path, exit_code: i32) -> PathBuf {
+        write_helper(dir, &format!("exit {exit_code}\n"))
+    }
+
+    #[test]
+    fn public_key_is_extracted_from_helper() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let public_key = [0x5a; ED25519_PUBLIC_KEY_LEN];
+        let helper_path = fake_helper(dir.path(), &public_key);
+
+        assert_eq!(get_public_key_with_helper(&helper_path).unwrap(), public_key);
+    }
+
+    #[test]
+    fn public_key_of_wrong_length_is_not_returned() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let helper_path = fake_helper(dir.path(), &[0x5a; 64]);
+
+        assert!(matches!(get_public_key_with_helper(&helper_path), Err(KeyError::Invalid(_))));
+    }
+}
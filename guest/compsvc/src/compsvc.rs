@@ -25,10 +25,10 @@ use std::default::Default;
 use std::fs::read_dir;
 use std::iter::zip;
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::{Condvar, Mutex, RwLock};
 
 use crate::artifact_signer::ArtifactSigner;
-use crate::compilation::odrefresh;
+use crate::compilation::{self, odrefresh};
 use crate::compos_key;
 use authfs_aidl_interface::aidl::com::android::virt::fs::IAuthFsService::{
     IAuthFsService, AUTHFS_SERVICE_SOCKET_NAME,
@@ -44,10 +44,15 @@ use compos_common::odrefresh::{is_system_property_interesting, ODREFRESH_PATH};
 use rpcbinder::RpcSession;
 
 /// Constructs a binder object that implements ICompOsService.
+///
+/// Fails with a [`compos_key::KeyError`] if the signing key material isn't usable.
 pub fn new_binder() -> Result<Strong<dyn ICompOsService>> {
+    compos_key::check_key_material()?;
+
     let service = CompOsService {
         odrefresh_path: PathBuf::from(ODREFRESH_PATH),
         initialized: RwLock::new(None),
+        warm_up: WarmUpTracker::default(),
     };
     Ok(BnCompOsService::new_binder(service, BinderFeatures::default()))
 }
@@ -60,6 +65,76 @@ struct CompOsService {
     ///  * Some(true): initialized successfully
     ///  * Some(false): failed to initialize
     initialized: RwLock<Option<bool>>,
+
+    warm_up: WarmUpTracker,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum WarmState {
+    #[default]
+    Cold,
+    WarmingUp,
+    Warm,
+}
+
+/// Tracks whether the compilation toolchain is warm and whether compilations are in progress, so
+/// that it is warmed up at most once and never concurrently with a compilation.
+#[derive(Debug, Default)]
+struct WarmUpTracker {
+    inner: Mutex<WarmUpTrackerInner>,
+    /// Notified when a warm-up finishes.
+    warmed_up: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct WarmUpTrackerInner {
+    state: WarmState,
+    running_jobs: usize,
+}
+
+impl WarmUpTracker {
+    /// Returns whether the caller should warm the toolchain up, in which case it must then call
+    /// `finish_warm_up`.
+    ///
+    /// If another caller is already warming it up, waits until it's done, so that the toolchain is
+    /// warm when this returns false, unless a compilation is in progress or that warm-up failed.
+    /// In the latter case, the caller is asked to retry it.
+    fn try_begin_warm_up(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let mut inner =
+            self.warmed_up.wait_while(inner, |inner| inner.state == WarmState::WarmingUp).unwrap();
+        if inner.state != WarmState::Cold || inner.running_jobs > 0 {
+            return false;
+        }
+        inner.state = WarmState::WarmingUp;
+        true
+    }
+
+    fn finish_warm_up(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = if success { WarmState::Warm } else { WarmState::Cold };
+        self.warmed_up.notify_all();
+    }
+
+    /// Marks a compilation as running until the returned guard is dropped.
+    fn begin_job(&self) -> JobGuard<'_> {
+        self.inner.lock().unwrap().running_jobs += 1;
+        JobGuard { tracker: self }
+    }
+
+    fn state(&self) -> WarmState {
+        self.inner.lock().unwrap().state
+    }
+}
+
+struct JobGuard<'a> {
+    tracker: &'a WarmUpTracker,
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.inner.lock().unwrap().running_jobs -= 1;
+    }
 }
 
 impl Interface for CompOsService {}
@@ -103,11 +178,27 @@ impl ICompOsService for CompOsService {
                 .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
         }
 
+        let _job = self.warm_up.begin_job();
         to_binder_result(self.do_odrefresh(args))
     }
 
+    fn warmUp(&self) -> BinderResult<()> {
+        if !self.warm_up.try_begin_warm_up() {
+            info!("Skipping warm-up, toolchain is {:?}", self.warm_up.state());
+            return Ok(());
+        }
+        let result = self.do_warm_up();
+        self.warm_up.finish_warm_up(result.is_ok());
+        to_binder_result(result)
+    }
+
     fn getPublicKey(&self) -> BinderResult<Vec<u8>> {
-        to_binder_result(compos_key::get_public_key())
+        match compos_key::get_public_key() {
+            Err(e @ compos_key::KeyError::Missing) => {
+                Err(e).or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+            }
+            result => to_binder_result(result),
+        }
     }
 
     fn getAttestationChain(&self) -> BinderResult<Vec<u8>> {
@@ -122,6 +213,14 @@ impl ICompOsService for CompOsService {
 }
 
 impl CompOsService {
+    fn do_warm_up(&self) -> Result<()> {
+        compilation::warm_up()?;
+        // Make sure the signing key is loaded, as every successful compilation needs it.
+        compos_key::get_public_key()?;
+        info!("Compilation toolchain is warm");
+        Ok(())
+    }
+
     fn do_odrefresh(&self, args: &OdrefreshArgs) -> Result<i8> {
         log::debug!("Prepare to connect to {}", AUTHFS_SERVICE_SOCKET_NAME);
         let authfs_service: Strong<dyn IAuthFsService> = RpcSession::new()
@@ -157,3 +256,81 @@ fn add_artifacts(target_dir: &Path, artifact_signer: &mut ArtifactSigner) -> Res
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn warm_up_happens_once() {
+        let tracker = WarmUpTracker::default();
+        assert_eq!(tracker.state(), WarmState::Cold);
+
+        assert!(tracker.try_begin_warm_up());
+        assert_eq!(tracker.state(), WarmState::WarmingUp);
+
+        tracker.finish_warm_up(true);
+        assert_eq!(tracker.state(), WarmState::Warm);
+        assert!(!tracker.try_begin_warm_up());
+    }
+
+    #[test]
+    fn concurrent_warm_up_waits_until_warm() {
+        let tracker = WarmUpTracker::default();
+        assert!(tracker.try_begin_warm_up());
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let began = tracker.try_begin_warm_up();
+                (began, tracker.state())
+            });
+            thread::sleep(Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+
+            tracker.finish_warm_up(true);
+
+            assert_eq!(waiter.join().unwrap(), (false, WarmState::Warm));
+        });
+    }
+
+    #[test]
+    fn concurrent_warm_up_retries_failed_one() {
+        let tracker = WarmUpTracker::default();
+        assert!(tracker.try_begin_warm_up());
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| tracker.try_begin_warm_up());
+            thread::sleep(Duration::from_millis(100));
+
+            tracker.finish_warm_up(false);
+
+            assert!(waiter.join().unwrap());
+            assert_eq!(tracker.state(), WarmState::WarmingUp);
+        });
+    }
+
+    #[test]
+    fn failed_warm_up_can_be_retried() {
+        let tracker = WarmUpTracker::default();
+
+        assert!(tracker.try_begin_warm_up());
+        tracker.finish_warm_up(false);
+
+        assert_eq!(tracker.state(), WarmState::Cold);
+        assert!(tracker.try_begin_warm_up());
+    }
+
+    #[test]
+    fn warm_up_is_skipped_while_busy() {
+        let tracker = WarmUpTracker::default();
+
+        let job = tracker.begin_job();
+        assert!(!tracker.try_begin_warm_up());
+        assert_eq!(tracker.state(), WarmState::Cold);
+
+        drop(job);
+        assert!(tracker.try_begin_warm_up());
+    }
+}
@@ -24,21 +24,71 @@ mod fsverity;
 
 use anyhow::Result;
 use compos_common::COMPOS_VSOCK_PORT;
-use log::{debug, error};
+use compos_key::KeyError;
+use log::{debug, error, info, LevelFilter};
+use std::fmt;
 use std::panic;
 
+/// Exit code for generic startup failures.
+const EXIT_CODE_FAILURE: i32 = 1;
+/// Exit code when the signing key can't be derived, as the VM instance secret is unavailable.
+const EXIT_CODE_KEY_MISSING: i32 = 2;
+/// Exit code when the key helper fails or returns a malformed key.
+const EXIT_CODE_KEY_INVALID: i32 = 3;
+
+/// The effective configuration of compsvc, logged once at startup.
+struct Config {
+    port: u32,
+    log_level: LevelFilter,
+    fd_server_port: i32,
+    output_root: &'static str,
+}
+
+impl Config {
+    fn new() -> Self {
+        Self {
+            port: COMPOS_VSOCK_PORT,
+            log_level: LevelFilter::Debug,
+            fd_server_port: compilation::FD_SERVER_PORT,
+            output_root: compilation::ALLOWED_OUTPUT_ROOT,
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "port={} log_level={} fd_server_port={} output_root={}",
+            self.port, self.log_level, self.fd_server_port, self.output_root
+        )
+    }
+}
+
 fn main() {
     if let Err(e) = try_main() {
-        error!("failed with {:?}", e);
-        std::process::exit(1);
+        let exit_code = match e.downcast_ref::<KeyError>() {
+            Some(KeyError::Missing) => {
+                error!("Refusing to start, signing key can't be derived: {:?}", e);
+                EXIT_CODE_KEY_MISSING
+            }
+            Some(KeyError::Invalid(_)) => {
+                error!("Refusing to start, signing key is unusable: {:?}", e);
+                EXIT_CODE_KEY_INVALID
+            }
+            None => {
+                error!("failed with {:?}", e);
+                EXIT_CODE_FAILURE
+            }
+        };
+        std::process::exit(exit_code);
     }
 }
 
 fn try_main() -> Result<()> {
+    let config = Config::new();
     android_logger::init_once(
-        android_logger::Config::default()
-            .with_tag("compsvc")
-            .with_max_level(log::LevelFilter::Debug),
+        android_logger::Config::default().with_tag("compsvc").with_max_level(config.log_level),
     );
     // Redirect panic messages to logcat.
     panic::set_hook(Box::new(|panic_info| {
@@ -46,5 +96,34 @@ fn try_main() -> Result<()> {
     }));
 
     debug!("compsvc is starting as a rpc service.");
-    vm_payload::run_single_vsock_service(compsvc::new_binder()?, COMPOS_VSOCK_PORT)
+    info!("compsvc config: {config}");
+    vm_payload::run_single_vsock_service(compsvc::new_binder()?, config.port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_is_formatted_as_one_line() {
+        let config = Config {
+            port: 6432,
+            log_level: LevelFilter::Info,
+            fd_server_port: 3264,
+            output_root: "/data",
+        };
+
+        assert_eq!(
+            config.to_string(),
+            "port=6432 log_level=INFO fd_server_port=3264 output_root=/data"
+        );
+    }
+
+    #[test]
+    fn default_config_uses_compos_port() {
+        let config = Config::new();
+
+        assert_eq!(config.port, COMPOS_VSOCK_PORT);
+        assert!(config.to_string().starts_with(&format!("port={COMPOS_VSOCK_PORT} ")));
+    }
 }
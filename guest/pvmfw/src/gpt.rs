@@ -22,13 +22,16 @@ use core::slice;
 use static_assertions::const_assert;
 use static_assertions::const_assert_eq;
 use uuid::Uuid;
-use virtio_drivers::device::blk::SECTOR_SIZE;
-use vmbase::util::ceiling_div;
-use vmbase::virtio::{pci, HalImpl};
+use virtio_drivers::device::blk::{self, SECTOR_SIZE};
+use virtio_drivers::transport::pci::PciTransport;
+use virtio_drivers::Hal;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
-type VirtIOBlk = pci::VirtIOBlk<HalImpl>;
+type VirtIOBlk<H> = blk::VirtIOBlk<H, PciTransport>;
+
+/// Size of the logical blocks of the disks.
+pub const LBA_SIZE: usize = SECTOR_SIZE;
 
 pub enum Error {
     /// VirtIO error during read operation.
@@ -54,17 +57,17 @@ impl fmt::Display for Error {
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-pub struct Partition {
-    partitions: Partitions,
+pub struct Partition<H: Hal> {
+    partitions: Partitions<H>,
     indices: RangeInclusive<usize>,
 }
 
-impl Partition {
-    pub fn get_by_name(device: VirtIOBlk, name: &str) -> Result<Option<Self>> {
+impl<H: Hal> Partition<H> {
+    pub fn get_by_name(device: VirtIOBlk<H>, name: &str) -> Result<Option<Self>> {
         Partitions::new(device)?.get_partition_by_name(name)
     }
 
-    fn new(partitions: Partitions, entry: &Entry) -> Self {
+    fn new(partitions: Partitions<H>, entry: &Entry) -> Self {
         let first = entry.first_lba().try_into().unwrap();
         let last = entry.last_lba().try_into().unwrap();
 
@@ -94,16 +97,14 @@ impl Partition {
     }
 }
 
-pub struct Partitions {
-    device: VirtIOBlk,
+pub struct Partitions<H: Hal> {
+    device: VirtIOBlk<H>,
     entries_count: usize,
 }
 
-impl Partitions {
-    pub const LBA_SIZE: usize = SECTOR_SIZE;
-
-    fn new(mut device: VirtIOBlk) -> Result<Self> {
-        let mut blk = [0; Self::LBA_SIZE];
+impl<H: Hal> Partitions<H> {
+    fn new(mut device: VirtIOBlk<H>) -> Result<Self> {
+        let mut blk = [0; LBA_SIZE];
         device.read_blocks(Header::LBA, &mut blk).map_err(Error::FailedRead)?;
         let header = Header::read_from_prefix(blk.as_slice()).unwrap();
         if !header.is_valid() {
@@ -114,9 +115,9 @@ impl Partitions {
         Ok(Self { device, entries_count })
     }
 
-    fn get_partition_by_name(mut self, name: &str) -> Result<Option<Partition>> {
-        const_assert_eq!(Partitions::LBA_SIZE.rem_euclid(size_of::<Entry>()), 0);
-        let entries_per_blk = Partitions::LBA_SIZE.checked_div(size_of::<Entry>()).unwrap();
+    fn get_partition_by_name(mut self, name: &str) -> Result<Option<Partition<H>>> {
+        const_assert_eq!(LBA_SIZE.rem_euclid(size_of::<Entry>()), 0);
+        let entries_per_blk = LBA_SIZE.checked_div(size_of::<Entry>()).unwrap();
 
         // Create a UTF-16 reference against which we'll compare partition names. Note that unlike
         // the C99 wcslen(), this comparison will cover bytes past the first L'\0' character.
@@ -125,9 +126,9 @@ impl Partitions {
             *dest = src;
         }
 
-        let mut blk = [0; Self::LBA_SIZE];
+        let mut blk = [0; LBA_SIZE];
         let mut rem = self.entries_count;
-        let num_blocks = ceiling_div(self.entries_count, entries_per_blk).unwrap();
+        let num_blocks = self.entries_count.div_ceil(entries_per_blk);
         for i in Header::ENTRIES_LBA..Header::ENTRIES_LBA.checked_add(num_blocks).unwrap() {
             self.read_block(i, &mut blk)?;
             let entries = blk.as_ptr().cast::<Entry>();
@@ -175,7 +176,7 @@ struct Header {
     entry_size: u32,
     entries_crc32: u32,
 }
-const_assert!(size_of::<Header>() < Partitions::LBA_SIZE);
+const_assert!(size_of::<Header>() < LBA_SIZE);
 
 impl Header {
     const SIGNATURE: u64 = u64::from_le_bytes(*b"EFI PART");
@@ -14,37 +14,71 @@
 
 //! Support for reading and writing to the instance.img.
 
+#[cfg(test)]
+extern crate alloc;
+
+// The tests are built from this file alone, but can use the GPT support which doesn't need vmbase.
+#[cfg(test)]
+mod gpt;
+
+#[cfg(not(test))]
 use crate::dice::PartialInputs;
+#[cfg(not(test))]
 use crate::gpt;
 use crate::gpt::Partition;
-use crate::gpt::Partitions;
+use alloc::vec;
+use alloc::vec::Vec;
 use bssl_avf::{self, hkdf, Aead, AeadContext, Digester};
 use core::fmt;
 use core::mem::size_of;
+use core::ops::RangeInclusive;
 use diced_open_dice::DiceMode;
 use diced_open_dice::Hash;
 use diced_open_dice::Hidden;
 use log::trace;
+#[cfg(test)]
+use tests::{rand, PartialInputs, PciTransportIterator, VirtIOBlk};
 use uuid::Uuid;
 use virtio_drivers::transport::{pci::bus::PciRoot, DeviceType, Transport};
-use vmbase::util::ceiling_div;
+use virtio_drivers::Hal;
+#[cfg(not(test))]
+use vmbase::rand;
+#[cfg(not(test))]
 use vmbase::virtio::pci::{PciTransportIterator, VirtIOBlk};
-use vmbase::virtio::HalImpl;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
+use zeroize::Zeroizing;
 
 pub enum Error {
     /// Unexpected I/O error while accessing the underlying disk.
     FailedIo(gpt::Error),
+    /// Failed to generate random data.
+    FailedRandom(rand::Error),
+    /// The instance.img already contains a pvmfw entry.
+    InstanceEntryExists,
     /// Impossible to create a new instance.img entry.
     InstanceImageFull,
+    /// The instance.img slot at the given block is already used by another entry.
+    SlotOccupied(usize),
+    /// The instance.img slot at the given block doesn't start at an entry boundary before the first
+    /// free slot, so that an entry recorded there couldn't be found.
+    UnreachableSlot(usize),
+    /// Size of an instance.img entry that can't be represented in memory.
+    InvalidEntrySize(u64),
+    /// Badly formatted instance.img entry being imported.
+    #[cfg(test)]
+    InvalidImportedEntry,
     /// Badly formatted instance.img header block.
     InvalidInstanceImageHeader,
-    /// No instance.img ("vm-instance") partition found.
-    MissingInstanceImage,
+    /// The payload of a new entry would overwrite the header of the entry at the given block.
+    EntryOverlap(usize),
+    /// No instance.img ("vm-instance") partition found, with the status of each disk examined.
+    MissingInstanceImage(Vec<DiskStatus>),
     /// The instance.img doesn't contain a header.
     MissingInstanceImageHeader,
+    /// The pvmfw entry just recorded can't be found back in the instance.img.
+    MissingRecordedEntry,
     /// Authority hash found in the pvmfw instance.img entry doesn't match the trusted public key.
     RecordedAuthHashMismatch,
     /// Code hash found in the pvmfw instance.img entry doesn't match the inputs.
@@ -53,30 +87,84 @@ pub enum Error {
     RecordedDiceModeMismatch,
     /// Size of the instance.img entry being read or written is not supported.
     UnsupportedEntrySize(usize),
+    /// The instance.img header has a version newer than the ones supported.
+    UnsupportedInstanceImageVersion(u16),
+    /// The cipher recorded in the instance.img entry header isn't supported.
+    UnsupportedEntryCipher(u16),
+    /// Length of the key derived for the instance.img entry isn't supported by its cipher.
+    UnsupportedKeyLength(usize),
     /// Failed to create VirtIO Block device.
     VirtIOBlkCreationFailed(virtio_drivers::Error),
     /// An error happened during the interaction with BoringSSL.
     BoringSslFailed(bssl_avf::Error),
+    /// The digest of the whole instance.img doesn't match the expected one.
+    InstanceImageIntegrityMismatch,
+    /// The secret protecting the instance.img entry is too short or all zeros.
+    WeakSecret,
+    /// The instance.img entry is protected by another secret than the one given.
+    EntrySecretMismatch,
+    /// The running pvmfw is older than the minimum version recorded in the instance.img entry.
+    PvmfwDowngrade { recorded: u32, running: u32 },
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self}")
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::FailedIo(e) => write!(f, "Failed I/O to disk: {e}"),
+            Self::FailedRandom(e) => write!(f, "Failed to generate random data: {e}"),
+            Self::InstanceEntryExists => write!(f, "instance.img already contains a pvmfw entry"),
             Self::InstanceImageFull => write!(f, "Failed to obtain a free instance.img partition"),
+            Self::SlotOccupied(i) => write!(f, "instance.img slot at block {i} is already in use"),
+            Self::UnreachableSlot(i) => {
+                write!(f, "instance.img slot at block {i} isn't reachable from the first entry")
+            }
+            Self::InvalidEntrySize(sz) => write!(f, "Entry size too large: {sz}"),
+            #[cfg(test)]
+            Self::InvalidImportedEntry => write!(f, "Imported instance.img entry is invalid"),
             Self::InvalidInstanceImageHeader => write!(f, "instance.img header is invalid"),
-            Self::MissingInstanceImage => write!(f, "Failed to find the instance.img partition"),
+            Self::EntryOverlap(i) => {
+                write!(f, "New instance.img entry would overlap the entry at block {i}")
+            }
+            Self::MissingInstanceImage(disks) => {
+                write!(f, "Failed to find the instance.img partition in {} disk(s)", disks.len())?;
+                for (i, status) in disks.iter().enumerate() {
+                    write!(f, "; disk #{i}: {status}")?;
+                }
+                Ok(())
+            }
             Self::MissingInstanceImageHeader => write!(f, "instance.img header is missing"),
+            Self::MissingRecordedEntry => write!(f, "Recorded instance.img entry is missing"),
             Self::RecordedAuthHashMismatch => write!(f, "Recorded authority hash doesn't match"),
             Self::RecordedCodeHashMismatch => write!(f, "Recorded code hash doesn't match"),
             Self::RecordedDiceModeMismatch => write!(f, "Recorded DICE mode doesn't match"),
             Self::UnsupportedEntrySize(sz) => write!(f, "Invalid entry size: {sz}"),
+            Self::UnsupportedInstanceImageVersion(v) => {
+                write!(f, "Unsupported instance.img version: {v}")
+            }
+            Self::UnsupportedEntryCipher(id) => write!(f, "Unsupported entry cipher: {id}"),
+            Self::UnsupportedKeyLength(len) => write!(f, "Unsupported key length: {len}"),
             Self::VirtIOBlkCreationFailed(e) => {
                 write!(f, "Failed to create VirtIO Block device: {e}")
             }
             Self::BoringSslFailed(e) => {
                 write!(f, "An error happened during the interaction with BoringSSL: {e}")
             }
+            Self::InstanceImageIntegrityMismatch => {
+                write!(f, "instance.img doesn't match its expected digest")
+            }
+            Self::WeakSecret => write!(f, "Secret for the instance.img entry is too weak"),
+            Self::EntrySecretMismatch => {
+                write!(f, "instance.img entry is protected by another secret")
+            }
+            Self::PvmfwDowngrade { recorded, running } => {
+                write!(f, "pvmfw version {running} is older than the recorded minimum {recorded}")
+            }
         }
     }
 }
@@ -89,202 +177,1021 @@ impl From<bssl_avf::Error> for Error {
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Why a block device was rejected while looking for the instance.img partition.
+pub enum DiskStatus {
+    /// The disk has a valid GPT but no instance.img partition.
+    NoPartition,
+    /// The GPT of the disk couldn't be read or is corrupt.
+    Unreadable(gpt::Error),
+}
+
+impl fmt::Display for DiskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoPartition => write!(f, "no instance.img partition"),
+            Self::Unreadable(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// AEAD algorithms which can protect instance.img entries, identified in the entry header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+enum EntryCipher {
+    /// Identified by 0, so that it is also the cipher of the entries recorded before the header
+    /// identified one.
+    Aes256GcmRandNonce = 0,
+    Aes128GcmRandNonce = 1,
+}
+
+impl EntryCipher {
+    fn from_id(id: u16) -> Result<Self> {
+        match id {
+            0 => Ok(Self::Aes256GcmRandNonce),
+            1 => Ok(Self::Aes128GcmRandNonce),
+            _ => Err(Error::UnsupportedEntryCipher(id)),
+        }
+    }
+
+    fn id(self) -> u16 {
+        self as u16
+    }
+
+    fn aead(self) -> Aead {
+        match self {
+            Self::Aes128GcmRandNonce => Aead::aes_128_gcm_randnonce(),
+            Self::Aes256GcmRandNonce => Aead::aes_256_gcm_randnonce(),
+        }
+    }
+
+    /// Largest plaintext that fits, once sealed, in the single payload block of an entry.
+    fn max_plaintext_size(self) -> usize {
+        BLK_SIZE - self.aead().max_overhead()
+    }
+}
+
+/// Cipher of new pvmfw entries. Existing entries are opened with the cipher their header records.
+const ENTRY_CIPHER: EntryCipher = EntryCipher::Aes256GcmRandNonce;
+
+/// Returns the largest plaintext that can be recorded in the pvmfw entry.
+pub(crate) fn max_plaintext_size() -> usize {
+    ENTRY_CIPHER.max_plaintext_size()
+}
+
+/// Shortest secret accepted to protect the instance.img entry, the size of a DICE CDI.
+const MIN_SECRET_SIZE: usize = 32;
+
+/// Rejects secrets which would weaken the keys derived from them, such as an uninitialized
+/// buffer, before any of them is derived.
+fn check_secret_strength(secret: &[u8]) -> Result<()> {
+    if secret.len() < MIN_SECRET_SIZE || secret.iter().all(|&b| b == 0) {
+        return Err(Error::WeakSecret);
+    }
+    Ok(())
+}
+
 fn aead_ctx_from_secret(secret: &[u8]) -> Result<AeadContext> {
-    let key = hkdf::<32>(secret, /* salt= */ &[], b"vm-instance", Digester::sha512())?;
-    Ok(AeadContext::new(Aead::aes_256_gcm_randnonce(), key.as_slice(), /* tag_len */ None)?)
+    aead_ctx_for_cipher(secret, ENTRY_CIPHER)
+}
+
+/// Derives a key of the length expected by `cipher` from the secret.
+fn aead_ctx_for_cipher(secret: &[u8], cipher: EntryCipher) -> Result<AeadContext> {
+    let aead = cipher.aead();
+    match aead.key_length() {
+        16 => new_aead_ctx(aead, derive_key::<16>(secret)?.as_slice()),
+        32 => new_aead_ctx(aead, derive_key::<32>(secret)?.as_slice()),
+        key_len => Err(Error::UnsupportedKeyLength(key_len)),
+    }
+}
+
+const CHECK_VALUE_SIZE: usize = 16;
+
+type CheckValue = [u8; CHECK_VALUE_SIZE];
+
+/// Derives the value recorded in the entry header to check a secret without decrypting the
+/// payload. As it's derived with its own info, it doesn't reveal anything about the key.
+fn derive_check_value(secret: &[u8]) -> Result<CheckValue> {
+    Ok(*hkdf::<CHECK_VALUE_SIZE>(
+        secret,
+        /* salt= */ &[],
+        b"vm-instance-check",
+        Digester::sha512(),
+    )?)
+}
+
+fn derive_key<const N: usize>(secret: &[u8]) -> Result<Zeroizing<[u8; N]>> {
+    Ok(hkdf::<N>(secret, /* salt= */ &[], b"vm-instance", Digester::sha512())?)
+}
+
+fn new_aead_ctx(aead: Aead, key: &[u8]) -> Result<AeadContext> {
+    Ok(AeadContext::new(aead, key, /* tag_len */ None)?)
 }
 
 /// Get the entry from instance.img. This method additionally returns Partition corresponding to
 /// pvmfw in the instance.img as well as index corresponding to empty header which can be used to
 /// record instance data with `record_instance_entry`.
-pub(crate) fn get_recorded_entry(
+///
+/// The VirtIO HAL `H` is generic so that other HAL implementations can be used.
+pub(crate) fn get_recorded_entry<H: Hal>(
     pci_root: &mut PciRoot,
     secret: &[u8],
-) -> Result<(Option<EntryBody>, Partition, usize)> {
-    let mut instance_img = find_instance_img(pci_root)?;
+) -> Result<(Option<EntryBody>, Partition<H>, usize)> {
+    let mut instance_img = find_instance_img::<H>(pci_root)?;
+    let (body, header_index) = read_recorded_entry(&mut instance_img, secret)?;
+    Ok((body, instance_img, header_index))
+}
 
-    let entry = locate_entry(&mut instance_img)?;
+/// Returns the pvmfw entry of the instance.img, if any, with the index of its header or of the
+/// free slot where it can be recorded.
+fn read_recorded_entry(
+    storage: &mut impl InstanceStorage,
+    secret: &[u8],
+) -> Result<(Option<EntryBody>, usize)> {
+    check_secret_strength(secret)?;
+    let entry = locate_entry(storage)?;
     trace!("Found pvmfw instance.img entry: {entry:?}");
 
     match entry {
         PvmfwEntry::Existing { header_index, payload_size } => {
-            let aead_ctx = aead_ctx_from_secret(secret)?;
-            let mut blk = [0; BLK_SIZE];
-            if payload_size > blk.len() {
-                // We currently only support single-blk entries.
-                return Err(Error::UnsupportedEntrySize(payload_size));
+            if !entry_matches_secret(storage, header_index, payload_size, secret)? {
+                return Err(Error::EntrySecretMismatch);
             }
-            let payload_index = header_index + 1;
-            instance_img.read_block(payload_index, &mut blk).map_err(Error::FailedIo)?;
+            let body = read_entry_body(storage, header_index, payload_size, secret)?;
+            Ok((Some(body), header_index))
+        }
+        PvmfwEntry::New { header_index } => Ok((None, header_index)),
+    }
+}
+
+fn read_entry_body(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    payload_size: usize,
+    secret: &[u8],
+) -> Result<EntryBody> {
+    let mut entry = [0; size_of::<EntryBody>()];
+    let decrypted = open_entry(storage, header_index, payload_size, secret, &mut entry)?;
+    EntryBody::parse(decrypted)
+}
+
+/// Returns a human-readable dump of the measurements recorded in the pvmfw entry, or None if
+/// there is no entry, to debug measured boot mismatches. Only built for userdebug and eng.
+///
+/// The salt is secret material so it is never part of the dump.
+#[cfg(all(debuggable_build, not(test)))]
+pub(crate) fn debug_dump_entry<H: Hal>(
+    instance_img: &mut Partition<H>,
+    secret: &[u8],
+) -> Result<Option<alloc::string::String>> {
+    dump_entry(instance_img, secret)
+}
+
+#[cfg(debuggable_build)]
+fn dump_entry(
+    storage: &mut impl InstanceStorage,
+    secret: &[u8],
+) -> Result<Option<alloc::string::String>> {
+    use alloc::format;
+    use alloc::string::String;
+
+    check_secret_strength(secret)?;
+    let PvmfwEntry::Existing { header_index, payload_size } = locate_entry(storage)? else {
+        return Ok(None);
+    };
+    let body = read_entry_body(storage, header_index, payload_size, secret)?;
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    Ok(Some(format!(
+        "code_hash: {}, auth_hash: {}, mode: {:?}",
+        hex(body.code_hash()),
+        hex(body.auth_hash()),
+        body.mode()
+    )))
+}
+
+/// Checks the whole instance.img against `expected_digest`, to be done before trusting any of
+/// its entries, so that tampering with any part of the image is detected.
+///
+/// Returns `Error::InstanceImageIntegrityMismatch` if the digest doesn't match.
+#[allow(dead_code)] // TODO: Run it before locate_entry() once the host keeps a digest up to date.
+fn verify_instance_img_integrity(
+    storage: &mut impl InstanceStorage,
+    expected_digest: &[u8],
+) -> Result<()> {
+    if instance_img_digest(storage)? != expected_digest {
+        return Err(Error::InstanceImageIntegrityMismatch);
+    }
+    Ok(())
+}
 
-            let payload = &blk[..payload_size];
+/// Returns the SHA-256 digest chained over the blocks of the instance.img, in order: starting
+/// from 32 zero bytes, each step hashes the previous digest followed by the next block. This
+/// only needs a block of memory, however large the image.
+fn instance_img_digest(storage: &mut impl InstanceStorage) -> Result<Vec<u8>> {
+    let digester = Digester::sha256();
+    let size = digester.size();
+    let mut input = vec![0; size + BLK_SIZE];
+    for index in storage.indices() {
+        storage.read_block(index, &mut input[size..]).map_err(Error::FailedIo)?;
+        let digest = digester.digest(&input)?;
+        input[..size].copy_from_slice(&digest);
+    }
+    input.truncate(size);
+    Ok(input)
+}
+
+/// Returns whether the instance.img has a pvmfw entry, checking that it can be decrypted with
+/// `secret` but without parsing it.
+///
+/// Fails if the entry exists but can't be decrypted, e.g. because `secret` is wrong.
+#[allow(dead_code)] // The boot flow decrypts the entry while reading it instead.
+pub(crate) fn has_valid_recorded_entry<H: Hal>(
+    pci_root: &mut PciRoot,
+    secret: &[u8],
+) -> Result<bool> {
+    let mut instance_img = find_instance_img::<H>(pci_root)?;
+    has_valid_entry(&mut instance_img, secret)
+}
+
+fn has_valid_entry(storage: &mut impl InstanceStorage, secret: &[u8]) -> Result<bool> {
+    check_secret_strength(secret)?;
+    match locate_entry(storage)? {
+        PvmfwEntry::Existing { header_index, payload_size } => {
             let mut entry = [0; size_of::<EntryBody>()];
-            // The nonce is generated internally for `aes_256_gcm_randnonce`, so no additional
-            // nonce is required.
-            let decrypted =
-                aead_ctx.open(payload, /* nonce */ &[], /* ad */ &[], &mut entry)?;
-            let body = EntryBody::read_from(decrypted).unwrap();
-            Ok((Some(body), instance_img, header_index))
+            open_entry(storage, header_index, payload_size, secret, &mut entry)?;
+            Ok(true)
+        }
+        PvmfwEntry::New { .. } => Ok(false),
+    }
+}
+
+/// Returns whether `secret` is the one protecting the pvmfw entry of the instance.img, without
+/// decrypting it unless it was recorded without a check value, or false if there is no entry.
+#[allow(dead_code)] // The boot flow checks the secret while reading the entry instead.
+pub(crate) fn secret_matches_entry<H: Hal>(pci_root: &mut PciRoot, secret: &[u8]) -> Result<bool> {
+    let mut instance_img = find_instance_img::<H>(pci_root)?;
+    secret_matches_recorded_entry(&mut instance_img, secret)
+}
+
+fn secret_matches_recorded_entry(
+    storage: &mut impl InstanceStorage,
+    secret: &[u8],
+) -> Result<bool> {
+    check_secret_strength(secret)?;
+    match locate_entry(storage)? {
+        PvmfwEntry::Existing { header_index, payload_size } => {
+            entry_matches_secret(storage, header_index, payload_size, secret)
         }
-        PvmfwEntry::New { header_index } => Ok((None, instance_img, header_index)),
+        PvmfwEntry::New { .. } => Ok(false),
+    }
+}
+
+/// Returns whether `secret` is the one protecting the entry at `header_index`, by comparing the
+/// check value recorded in the entry header, which is faster than decrypting it.
+///
+/// Entries recorded without a check value are decrypted instead.
+fn entry_matches_secret(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    payload_size: usize,
+    secret: &[u8],
+) -> Result<bool> {
+    let mut blk = [0; BLK_SIZE];
+    storage.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+    let header = EntryHeader::read_from_prefix(blk.as_slice()).unwrap();
+    if header.check_value == [0; CHECK_VALUE_SIZE] {
+        let mut entry = [0; size_of::<EntryBody>()];
+        return match open_entry(storage, header_index, payload_size, secret, &mut entry) {
+            Ok(_) => Ok(true),
+            // The payload can't be decrypted with the key derived from another secret.
+            Err(Error::BoringSslFailed(_)) => Ok(false),
+            Err(e) => Err(e),
+        };
+    }
+    Ok(header.check_value == derive_check_value(secret)?)
+}
+
+/// Reads and decrypts the payload of the entry into `out`, returning the plaintext.
+fn open_entry<'a>(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    payload_size: usize,
+    secret: &[u8],
+    out: &'a mut [u8],
+) -> Result<&'a [u8]> {
+    let mut blk = [0; BLK_SIZE];
+    if payload_size > blk.len() {
+        // We currently only support single-blk entries.
+        return Err(Error::UnsupportedEntrySize(payload_size));
+    }
+    storage.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+    let cipher = EntryHeader::read_from_prefix(blk.as_slice()).unwrap().cipher()?;
+    let aead_ctx = aead_ctx_for_cipher(secret, cipher)?;
+    let payload_index = header_index + 1;
+    storage.read_block(payload_index, &mut blk).map_err(Error::FailedIo)?;
+
+    let payload = &blk[..payload_size];
+    // The nonce is generated internally by the `*_randnonce` ciphers, so no additional nonce is
+    // required.
+    Ok(aead_ctx.open(payload, /* nonce */ &[], /* ad */ &[], out)?)
+}
+
+#[cfg(not(test))]
+pub(crate) fn record_instance_entry<H: Hal>(
+    body: &EntryBody,
+    secret: &[u8],
+    instance_img: &mut Partition<H>,
+    header_index: usize,
+) -> Result<()> {
+    check_secret_strength(secret)?;
+    let mut blk = [0; BLK_SIZE];
+    let payload_size = seal_entry(body, secret, &mut blk)?;
+    let check_value = derive_check_value(secret)?;
+    let token = new_claim_token()?;
+    let result = write_entry(
+        instance_img,
+        header_index,
+        &blk,
+        payload_size,
+        check_value,
+        ENTRY_CIPHER,
+        token,
+    );
+    if let Err(Error::InstanceImageFull) = result {
+        let max_entries = max_single_block_entries(instance_img.indices().count());
+        log::warn!("instance.img can't hold more than {max_entries} entries");
+    }
+    result?;
+    // Read the entry back, so that a failed write is reported now rather than on the next boot.
+    if !has_valid_entry(instance_img, secret)? {
+        return Err(Error::MissingRecordedEntry);
     }
+    Ok(())
 }
 
-pub(crate) fn record_instance_entry(
+/// Records the entry in the slot at `header_index` exactly, e.g. to restore an entry to its
+/// original slot, instead of the first free slot from there.
+///
+/// Fails with `Error::SlotOccupied` if the slot isn't free. As entries are only looked up until the
+/// first free slot, fails with `Error::UnreachableSlot` if the slot is past it.
+#[allow(dead_code)] // The boot flow records the entry in the first free slot instead.
+#[cfg(not(test))]
+pub(crate) fn record_instance_entry_at<H: Hal>(
     body: &EntryBody,
     secret: &[u8],
-    instance_img: &mut Partition,
+    instance_img: &mut Partition<H>,
     header_index: usize,
 ) -> Result<()> {
-    // We currently only support single-blk entries.
+    check_secret_strength(secret)?;
+    record_entry_at(instance_img, body, secret, header_index, new_claim_token()?)
+}
+
+fn record_entry_at(
+    storage: &mut impl InstanceStorage,
+    body: &EntryBody,
+    secret: &[u8],
+    header_index: usize,
+    token: u128,
+) -> Result<()> {
     let mut blk = [0; BLK_SIZE];
+    let payload_size = seal_entry(body, secret, &mut blk)?;
+    let check_value = derive_check_value(secret)?;
+    write_entry_at(storage, header_index, &blk, payload_size, check_value, ENTRY_CIPHER, token)
+}
+
+/// Encrypts `body` into the single payload block `blk`, returning the size of the payload.
+fn seal_entry(body: &EntryBody, secret: &[u8], blk: &mut [u8; BLK_SIZE]) -> Result<usize> {
+    // We currently only support single-blk entries.
     let plaintext = body.as_bytes();
+    if plaintext.len() > max_plaintext_size() {
+        return Err(Error::UnsupportedEntrySize(plaintext.len()));
+    }
     let aead_ctx = aead_ctx_from_secret(secret)?;
-    assert!(plaintext.len() + aead_ctx.aead().max_overhead() < blk.len());
-    let encrypted = aead_ctx.seal(plaintext, /* nonce */ &[], /* ad */ &[], &mut blk)?;
-    let payload_size = encrypted.len();
-    let payload_index = header_index + 1;
-    instance_img.write_block(payload_index, &blk).map_err(Error::FailedIo)?;
+    let encrypted = aead_ctx.seal(plaintext, /* nonce */ &[], /* ad */ &[], blk)?;
+    Ok(encrypted.len())
+}
 
-    let header = EntryHeader::new(PvmfwEntry::UUID, payload_size);
-    header.write_to_prefix(blk.as_mut_slice()).unwrap();
-    blk[header.as_bytes().len()..].fill(0);
-    instance_img.write_block(header_index, &blk).map_err(Error::FailedIo)?;
+// TODO: Build the export and import of entries into pvmfw once the host has a way to request them;
+// until then, nothing outside of the tests calls them.
 
-    Ok(())
+/// Returns the raw header and (encrypted) payload blocks of the pvmfw entry of the instance.img,
+/// e.g. to back it up. As the payload is only exported as ciphertext, no secret is required.
+#[cfg(test)]
+pub(crate) fn export_instance_entry<H: Hal>(pci_root: &mut PciRoot) -> Result<Option<Vec<u8>>> {
+    let mut instance_img = find_instance_img::<H>(pci_root)?;
+    export_entry(&mut instance_img)
 }
 
-#[derive(FromZeroes, FromBytes)]
-#[repr(C, packed)]
-struct Header {
-    magic: [u8; Header::MAGIC.len()],
-    version: u16,
+/// Writes an entry previously obtained with `export_instance_entry` to the first free slot of the
+/// instance.img, which must not already contain a pvmfw entry.
+#[cfg(test)]
+pub(crate) fn import_instance_entry<H: Hal>(
+    pci_root: &mut PciRoot,
+    blob: &[u8],
+    token: u128,
+) -> Result<()> {
+    let mut instance_img = find_instance_img::<H>(pci_root)?;
+    import_entry(&mut instance_img, blob, token)
 }
 
-impl Header {
-    const MAGIC: &'static [u8] = b"Android-VM-instance";
-    const VERSION_1: u16 = 1;
+/// Size of an exported entry: a header block followed by a single payload block.
+#[cfg(test)]
+const EXPORTED_ENTRY_SIZE: usize = 2 * BLK_SIZE;
 
-    pub fn is_valid(&self) -> bool {
-        self.magic == Self::MAGIC && self.version() == Self::VERSION_1
+#[cfg(test)]
+fn export_entry(storage: &mut impl InstanceStorage) -> Result<Option<Vec<u8>>> {
+    let PvmfwEntry::Existing { header_index, payload_size } = locate_entry(storage)? else {
+        return Ok(None);
+    };
+    if payload_size > BLK_SIZE {
+        // We currently only support single-blk entries.
+        return Err(Error::UnsupportedEntrySize(payload_size));
     }
+    let mut blob = vec![0; EXPORTED_ENTRY_SIZE];
+    let (header_blk, payload_blk) = blob.split_at_mut(BLK_SIZE);
+    storage.read_block(header_index, header_blk).map_err(Error::FailedIo)?;
+    storage.read_block(header_index + 1, payload_blk).map_err(Error::FailedIo)?;
 
-    fn version(&self) -> u16 {
-        u16::from_le(self.version)
-    }
+    Ok(Some(blob))
 }
 
-fn find_instance_img(pci_root: &mut PciRoot) -> Result<Partition> {
-    for transport in PciTransportIterator::<HalImpl>::new(pci_root)
-        .filter(|t| DeviceType::Block == t.device_type())
-    {
-        let device =
-            VirtIOBlk::<HalImpl>::new(transport).map_err(Error::VirtIOBlkCreationFailed)?;
-        match Partition::get_by_name(device, "vm-instance") {
-            Ok(Some(p)) => return Ok(p),
-            Ok(None) => {}
-            Err(e) => log::warn!("error while reading from disk: {e}"),
-        };
+#[cfg(test)]
+fn import_entry(storage: &mut impl InstanceStorage, blob: &[u8], token: u128) -> Result<()> {
+    if blob.len() != EXPORTED_ENTRY_SIZE {
+        return Err(Error::UnsupportedEntrySize(blob.len()));
+    }
+    let (header_blk, payload_blk) = blob.split_at(BLK_SIZE);
+    let header = EntryHeader::read_from_prefix(header_blk).unwrap();
+    if header.uuid() != PvmfwEntry::UUID {
+        return Err(Error::InvalidImportedEntry);
     }
+    let payload_size = header.payload_size()?;
+    if payload_size > BLK_SIZE {
+        return Err(Error::UnsupportedEntrySize(payload_size));
+    }
+    let cipher = header.cipher()?;
 
-    Err(Error::MissingInstanceImage)
+    match locate_entry(storage)? {
+        PvmfwEntry::Existing { .. } => Err(Error::InstanceEntryExists),
+        PvmfwEntry::New { header_index } => {
+            let check_value = header.check_value;
+            write_entry(
+                storage,
+                header_index,
+                payload_blk,
+                payload_size,
+                check_value,
+                cipher,
+                token,
+            )
+        }
+    }
 }
 
-#[derive(Debug)]
-enum PvmfwEntry {
-    Existing { header_index: usize, payload_size: usize },
-    New { header_index: usize },
+/// Writes an entry made of a single payload block, followed by its header, so that the entry
+/// only becomes visible once its payload is in place.
+///
+/// The entry is written to the first free slot from `header_index` that could be claimed with
+/// `token`, see `claim_slot`.
+fn write_entry(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    payload_blk: &[u8],
+    payload_size: usize,
+    check_value: CheckValue,
+    cipher: EntryCipher,
+    token: u128,
+) -> Result<()> {
+    let header_index = claim_slot(storage, header_index, token)?;
+    fill_claimed_slot(storage, header_index, payload_blk, payload_size, check_value, cipher)
 }
 
-const BLK_SIZE: usize = Partitions::LBA_SIZE;
+/// Writes an entry made of a single payload block to the slot at `header_index` exactly, instead
+/// of the first free slot from there, failing with `Error::SlotOccupied` if it can't be claimed.
+fn write_entry_at(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    payload_blk: &[u8],
+    payload_size: usize,
+    check_value: CheckValue,
+    cipher: EntryCipher,
+    token: u128,
+) -> Result<()> {
+    check_slot_is_reachable(storage, header_index)?;
+    if !try_claim_slot(storage, header_index, token)? {
+        return Err(Error::SlotOccupied(header_index));
+    }
+    fill_claimed_slot(storage, header_index, payload_blk, payload_size, check_value, cipher)
+}
 
-impl PvmfwEntry {
-    const UUID: Uuid = Uuid::from_u128(0x90d2174a038a4bc6adf3824848fc5825);
+/// Checks that `locate_entry` would find an entry recorded at `header_index`: the slot must start
+/// at an entry boundary, not be past the first free slot, and not follow the pvmfw entry.
+fn check_slot_is_reachable(storage: &mut impl InstanceStorage, header_index: usize) -> Result<()> {
+    read_header(storage)?.check()?;
+    let indices = storage.indices();
+    let mut blk = [0; BLK_SIZE];
+    let mut index = indices.start() + 1;
+    while index < header_index && indices.contains(&index) {
+        storage.read_block(index, &mut blk).map_err(Error::FailedIo)?;
+        let header = EntryHeader::read_from_prefix(blk.as_slice()).unwrap();
+        match header.uuid() {
+            uuid if uuid.is_nil() => break,
+            uuid if uuid == PvmfwEntry::UUID => return Err(Error::InstanceEntryExists),
+            _ => index += 1 + payload_blocks(header.payload_size()?)?,
+        }
+    }
+    if index != header_index || !indices.contains(&index) {
+        return Err(Error::UnreachableSlot(header_index));
+    }
+    Ok(())
 }
 
-fn locate_entry(partition: &mut Partition) -> Result<PvmfwEntry> {
+/// Writes the payload block then the header of an entry to the slot claimed at `header_index`.
+fn fill_claimed_slot(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    payload_blk: &[u8],
+    payload_size: usize,
+    check_value: CheckValue,
+    cipher: EntryCipher,
+) -> Result<()> {
+    let payload_index = header_index + 1;
+    storage.write_block(payload_index, payload_blk).map_err(Error::FailedIo)?;
+
     let mut blk = [0; BLK_SIZE];
-    let mut indices = partition.indices();
-    let header_index = indices.next().ok_or(Error::MissingInstanceImageHeader)?;
-    partition.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
-    // The instance.img header is only used for discovery/validation.
-    let header = Header::read_from_prefix(blk.as_slice()).unwrap();
-    if !header.is_valid() {
-        return Err(Error::InvalidInstanceImageHeader);
+    let header = EntryHeader::for_entry(PvmfwEntry::UUID, payload_size, check_value, cipher)?;
+    header.write_to_prefix(blk.as_mut_slice()).unwrap();
+    storage.write_block(header_index, &blk).map_err(Error::FailedIo)?;
+
+    Ok(())
+}
+
+/// Returns a token identifying this writer in the claims it makes.
+#[cfg(not(test))]
+fn new_claim_token() -> Result<u128> {
+    Ok(u128::from_ne_bytes(rand::random_array().map_err(Error::FailedRandom)?))
+}
+
+/// Claims the first free slot from `header_index` for a new single-block entry.
+///
+/// As another writer sharing the instance.img could pick the same free slot, a claim marker
+/// holding `token` is written to the slot and read back until any such writer would have written
+/// its own: if it was overwritten, the slot was claimed by someone else and the next free slot is
+/// tried.
+fn claim_slot(
+    storage: &mut impl InstanceStorage,
+    mut header_index: usize,
+    token: u128,
+) -> Result<usize> {
+    let indices = storage.indices();
+    let mut blk = [0; BLK_SIZE];
+    loop {
+        if !indices.contains(&header_index) {
+            return Err(Error::InstanceImageFull);
+        }
+        if try_claim_slot(storage, header_index, token)? {
+            return Ok(header_index);
+        }
+        // Skip over the entry (or claim) now occupying the slot.
+        storage.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+        let payload_size = EntryHeader::read_from_prefix(blk.as_slice()).unwrap().payload_size()?;
+        header_index += 1 + payload_blocks(payload_size)?;
     }
+}
 
-    while let Some(header_index) = indices.next() {
-        partition.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+/// Number of times a new claim is read back before being committed. Another writer which read
+/// the slot as free before the claim was written overwrites it within this window, so that only
+/// the last claim written is still in place at the end of it.
+const CLAIM_QUIESCE_READS: usize = 16;
 
-        let header = EntryHeader::read_from_prefix(blk.as_slice()).unwrap();
-        match (header.uuid(), header.payload_size()) {
-            (uuid, _) if uuid.is_nil() => return Ok(PvmfwEntry::New { header_index }),
-            (PvmfwEntry::UUID, payload_size) => {
-                return Ok(PvmfwEntry::Existing { header_index, payload_size })
-            }
-            (uuid, payload_size) => {
-                trace!("Skipping instance.img entry {uuid}: {payload_size:?} bytes");
-                let n = ceiling_div(payload_size, BLK_SIZE).unwrap();
-                if n > 0 {
-                    let _ = indices.nth(n - 1); // consume
-                }
-            }
-        };
+/// Number of times a claim found in a slot is read back before considering that its writer was
+/// interrupted. A live writer commits its entry within its own quiesce window, so this is longer.
+const STALE_CLAIM_READS: usize = 2 * CLAIM_QUIESCE_READS;
+
+/// Claims the slot at `header_index` for a new single-block entry, returning whether it was free,
+/// or held a stale claim, and no other writer claimed it concurrently.
+fn try_claim_slot(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    token: u128,
+) -> Result<bool> {
+    let mut blk = [0; BLK_SIZE];
+    storage.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+    match EntryHeader::read_from_prefix(blk.as_slice()).unwrap().uuid() {
+        uuid if uuid.is_nil() => check_payload_blocks_are_free(storage, header_index, 1)?,
+        // The payload block was reserved by the claim, so it may hold a partially written entry.
+        uuid if uuid == ClaimMarker::UUID && is_stale_claim(storage, header_index, &blk)? => {
+            trace!("Reclaiming stale claim of instance.img slot {header_index}");
+        }
+        _ => return Ok(false),
+    }
+    let mut claim = [0; BLK_SIZE];
+    ClaimMarker::new(token)?.write_to_prefix(claim.as_mut_slice()).unwrap();
+    storage.write_block(header_index, &claim).map_err(Error::FailedIo)?;
+    // The last read is the one that the entry is committed on.
+    for _ in 0..CLAIM_QUIESCE_READS {
+        storage.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+        if blk != claim {
+            trace!("instance.img slot {header_index} was claimed concurrently");
+            return Ok(false);
+        }
     }
+    Ok(true)
+}
 
-    Err(Error::InstanceImageFull)
+/// Returns whether the claim `claim`, read from the slot at `header_index`, was left by a writer
+/// which was interrupted, as it stays in place for longer than a live writer would keep it.
+fn is_stale_claim(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    claim: &[u8],
+) -> Result<bool> {
+    let mut blk = [0; BLK_SIZE];
+    for _ in 0..STALE_CLAIM_READS {
+        storage.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+        if blk != claim {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
-/// Marks the start of an instance.img entry.
+/// Checks that the `count` payload blocks of an entry with its header at `header_index` fit in the
+/// instance.img and don't hold the header of another entry, which happens when the size of a
+/// previous entry under-counts its actual footprint.
+fn check_payload_blocks_are_free(
+    storage: &mut impl InstanceStorage,
+    header_index: usize,
+    count: usize,
+) -> Result<()> {
+    let indices = storage.indices();
+    let mut blk = [0; BLK_SIZE];
+    for index in (header_index + 1)..=(header_index + count) {
+        if !indices.contains(&index) {
+            return Err(Error::InstanceImageFull);
+        }
+        storage.read_block(index, &mut blk).map_err(Error::FailedIo)?;
+        if !EntryHeader::read_from_prefix(blk.as_slice()).unwrap().uuid().is_nil() {
+            return Err(Error::EntryOverlap(index));
+        }
+    }
+    Ok(())
+}
+
+/// Reserves a slot for a single-block entry while it's being written.
 ///
-/// Note: Virtualization/guest/microdroid_manager/src/instance.rs uses the name "partition".
+/// Claims are formatted as the entries of a dedicated owner, so that readers skip them. A claim
+/// that is never committed (e.g. if pvmfw is interrupted) is skipped by readers until a writer
+/// finds it stale and reclaims its slot, see `try_claim_slot`.
 #[derive(AsBytes, FromZeroes, FromBytes)]
 #[repr(C, packed)]
-struct EntryHeader {
-    uuid: u128,
-    payload_size: u64,
+struct ClaimMarker {
+    header: EntryHeader,
+    token: u128,
 }
 
-impl EntryHeader {
-    fn new(uuid: Uuid, payload_size: usize) -> Self {
-        Self { uuid: uuid.to_u128_le(), payload_size: u64::try_from(payload_size).unwrap().to_le() }
+impl ClaimMarker {
+    const UUID: Uuid = Uuid::from_u128(0x5c1a0e2bd3a24f7e9e5b6f0c4d8a7e31);
+
+    fn new(token: u128) -> Result<Self> {
+        Ok(Self { header: EntryHeader::new(Self::UUID, BLK_SIZE)?, token: token.to_le() })
     }
+}
 
-    fn uuid(&self) -> Uuid {
-        Uuid::from_u128_le(self.uuid)
+/// Block-level access to the instance.img partition.
+trait InstanceStorage {
+    fn indices(&self) -> RangeInclusive<usize>;
+    fn read_block(&mut self, index: usize, blk: &mut [u8]) -> gpt::Result<()>;
+    fn write_block(&mut self, index: usize, blk: &[u8]) -> gpt::Result<()>;
+}
+
+impl<H: Hal> InstanceStorage for Partition<H> {
+    fn indices(&self) -> RangeInclusive<usize> {
+        Partition::indices(self)
+    }
+
+    fn read_block(&mut self, index: usize, blk: &mut [u8]) -> gpt::Result<()> {
+        Partition::read_block(self, index, blk)
     }
 
-    fn payload_size(&self) -> usize {
-        usize::try_from(u64::from_le(self.payload_size)).unwrap()
+    fn write_block(&mut self, index: usize, blk: &[u8]) -> gpt::Result<()> {
+        Partition::write_block(self, index, blk)
     }
 }
 
 #[derive(AsBytes, FromZeroes, FromBytes)]
-#[repr(C)]
-pub(crate) struct EntryBody {
-    pub code_hash: Hash,
-    pub auth_hash: Hash,
-    pub salt: Hidden,
-    mode: u8,
+#[repr(C, packed)]
+struct Header {
+    magic: [u8; Header::MAGIC.len()],
+    version: u16,
+    /// Time at which the instance.img was formatted, in seconds since the Unix epoch, or zero in
+    /// images formatted before it was recorded. Readers ignore it, so it isn't versioned.
+    created_epoch: u64,
 }
 
-impl EntryBody {
-    pub(crate) fn new(dice_inputs: &PartialInputs, salt: &Hidden) -> Self {
-        let mode = match dice_inputs.mode {
-            DiceMode::kDiceModeNotInitialized => 0,
-            DiceMode::kDiceModeNormal => 1,
-            DiceMode::kDiceModeDebug => 2,
-            DiceMode::kDiceModeMaintenance => 3,
-        };
+impl Header {
+    const MAGIC: &'static [u8] = b"Android-VM-instance";
+    const VERSION_1: u16 = 1;
 
-        Self {
-            code_hash: dice_inputs.code_hash,
-            auth_hash: dice_inputs.auth_hash,
+    /// Checks that this is an instance.img header, of a version that we support.
+    fn check(&self) -> Result<()> {
+        self.check_magic()?;
+        match self.version() {
+            Self::VERSION_1 => Ok(()),
+            version => Err(Error::UnsupportedInstanceImageVersion(version)),
+        }
+    }
+
+    /// Checks that this is an instance.img header, of any version.
+    fn check_magic(&self) -> Result<()> {
+        if self.magic != Self::MAGIC {
+            return Err(Error::InvalidInstanceImageHeader);
+        }
+        Ok(())
+    }
+
+    fn version(&self) -> u16 {
+        u16::from_le(self.version)
+    }
+
+    fn created_epoch(&self) -> Option<u64> {
+        match u64::from_le(self.created_epoch) {
+            0 => None,
+            t => Some(t),
+        }
+    }
+}
+
+fn read_header(partition: &mut impl InstanceStorage) -> Result<Header> {
+    let mut blk = [0; BLK_SIZE];
+    let header_index = partition.indices().next().ok_or(Error::MissingInstanceImageHeader)?;
+    partition.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+    Ok(Header::read_from_prefix(blk.as_slice()).unwrap())
+}
+
+/// Returns the version of the instance.img header, even if it isn't supported, so that images
+/// written by other versions of pvmfw can be migrated.
+///
+/// Fails with `Error::InvalidInstanceImageHeader` if the header isn't an instance.img one.
+#[cfg(not(test))]
+pub(crate) fn header_version<H: Hal>(instance_img: &mut Partition<H>) -> Result<u16> {
+    read_header_version(instance_img)
+}
+
+fn read_header_version(partition: &mut impl InstanceStorage) -> Result<u16> {
+    let header = read_header(partition)?;
+    header.check_magic()?;
+    Ok(header.version())
+}
+
+/// Returns the time at which the instance.img was formatted, in seconds since the Unix epoch, or
+/// None if its header predates it being recorded. This is only informational.
+#[cfg(not(test))]
+pub(crate) fn header_created_epoch<H: Hal>(instance_img: &mut Partition<H>) -> Result<Option<u64>> {
+    read_header_created_epoch(instance_img)
+}
+
+fn read_header_created_epoch(partition: &mut impl InstanceStorage) -> Result<Option<u64>> {
+    let header = read_header(partition)?;
+    header.check()?;
+    Ok(header.created_epoch())
+}
+
+fn find_instance_img<H: Hal>(pci_root: &mut PciRoot) -> Result<Partition<H>> {
+    let devices = PciTransportIterator::<H>::new(pci_root)
+        .filter(|t| DeviceType::Block == t.device_type())
+        .map(|t| VirtIOBlk::<H>::new(t).map_err(Error::VirtIOBlkCreationFailed));
+
+    find_partition(devices, |device| Partition::get_by_name(device, "vm-instance"))
+}
+
+/// Returns the first partition found by `get_partition` in `devices`.
+///
+/// Disks without the partition and disks whose GPT can't be read or is corrupt are skipped but
+/// are listed in the returned error, if no other disk holds the partition.
+fn find_partition<D, P>(
+    devices: impl Iterator<Item = Result<D>>,
+    mut get_partition: impl FnMut(D) -> gpt::Result<Option<P>>,
+) -> Result<P> {
+    let mut disks = Vec::new();
+    for (i, device) in devices.enumerate() {
+        let status = match get_partition(device?) {
+            Ok(Some(p)) => {
+                trace!("Found instance.img partition in disk #{i}");
+                return Ok(p);
+            }
+            Ok(None) => DiskStatus::NoPartition,
+            Err(e @ gpt::Error::InvalidHeader) => {
+                log::warn!("Skipping disk with corrupt GPT: {e}");
+                DiskStatus::Unreadable(e)
+            }
+            Err(e) => {
+                log::warn!("error while reading from disk: {e}");
+                DiskStatus::Unreadable(e)
+            }
+        };
+        trace!("Skipping disk #{i}: {status}");
+        disks.push(status);
+    }
+
+    Err(Error::MissingInstanceImage(disks))
+}
+
+#[derive(Debug)]
+enum PvmfwEntry {
+    Existing { header_index: usize, payload_size: usize },
+    New { header_index: usize },
+}
+
+const BLK_SIZE: usize = gpt::LBA_SIZE;
+
+impl PvmfwEntry {
+    const UUID: Uuid = Uuid::from_u128(0x90d2174a038a4bc6adf3824848fc5825);
+}
+
+/// Returns how many entries with a single-block payload fit in an instance.img partition of
+/// `partition_block_count` blocks.
+///
+/// The first block holds the instance.img header and each entry then takes a block for its header
+/// followed by one for its payload.
+pub(crate) fn max_single_block_entries(partition_block_count: usize) -> usize {
+    partition_block_count.saturating_sub(1) / 2
+}
+
+/// Returns the number of blocks following the header of an entry with `payload_size` bytes of
+/// payload, which is 0 for entries without payload.
+fn payload_blocks(payload_size: usize) -> Result<usize> {
+    match payload_size {
+        0 => Ok(0),
+        n => match n.checked_add(BLK_SIZE - 1) {
+            Some(rounded_up) => Ok(rounded_up / BLK_SIZE),
+            None => Err(Error::UnsupportedEntrySize(n)),
+        },
+    }
+}
+
+fn locate_entry(partition: &mut impl InstanceStorage) -> Result<PvmfwEntry> {
+    locate_entry_of(partition, PvmfwEntry::UUID)
+}
+
+/// Locates the entry owned by `owner`, skipping over the entries of other owners.
+///
+/// Without such an entry, the slot returned for a new one is the first one holding a claim, which
+/// may be stale, or else the first free one.
+fn locate_entry_of(partition: &mut impl InstanceStorage, owner: Uuid) -> Result<PvmfwEntry> {
+    let mut blk = [0; BLK_SIZE];
+    // The instance.img header is only used for discovery/validation.
+    read_header(partition)?.check()?;
+    let mut indices = partition.indices().skip(1);
+    let mut first_claim = None;
+
+    while let Some(header_index) = indices.next() {
+        partition.read_block(header_index, &mut blk).map_err(Error::FailedIo)?;
+
+        let header = EntryHeader::read_from_prefix(blk.as_slice()).unwrap();
+        match header.uuid() {
+            uuid if uuid.is_nil() => {
+                let header_index = first_claim.unwrap_or(header_index);
+                return Ok(PvmfwEntry::New { header_index });
+            }
+            uuid if uuid == owner => {
+                let payload_size = header.payload_size()?;
+                return Ok(PvmfwEntry::Existing { header_index, payload_size });
+            }
+            uuid => {
+                if uuid == ClaimMarker::UUID {
+                    first_claim = first_claim.or(Some(header_index));
+                }
+                let payload_size = header.payload_size()?;
+                trace!("Skipping instance.img entry {uuid}: {payload_size:?} bytes");
+                // Entries without payload only take their header block, already consumed.
+                if let Some(last_payload_block) = payload_blocks(payload_size)?.checked_sub(1) {
+                    let _ = indices.nth(last_payload_block); // consume
+                }
+            }
+        };
+    }
+
+    match first_claim {
+        Some(header_index) => Ok(PvmfwEntry::New { header_index }),
+        None => Err(Error::InstanceImageFull),
+    }
+}
+
+/// Marks the start of an instance.img entry.
+///
+/// Note: Virtualization/guest/microdroid_manager/src/instance.rs uses the name "partition".
+#[derive(AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+struct EntryHeader {
+    uuid: u128,
+    payload_size: u64,
+    /// Value derived from the secret protecting the payload, or zeros in older entries.
+    check_value: CheckValue,
+    /// Identifier of the `EntryCipher` protecting the payload, 0 in older entries.
+    cipher: u16,
+}
+
+impl EntryHeader {
+    fn new(uuid: Uuid, payload_size: usize) -> Result<Self> {
+        Self::for_entry(uuid, payload_size, [0; CHECK_VALUE_SIZE], EntryCipher::Aes256GcmRandNonce)
+    }
+
+    fn for_entry(
+        uuid: Uuid,
+        payload_size: usize,
+        check_value: CheckValue,
+        cipher: EntryCipher,
+    ) -> Result<Self> {
+        let size =
+            u64::try_from(payload_size).map_err(|_| Error::UnsupportedEntrySize(payload_size))?;
+        Ok(Self {
+            uuid: uuid.to_u128_le(),
+            payload_size: size.to_le(),
+            check_value,
+            cipher: cipher.id().to_le(),
+        })
+    }
+
+    fn uuid(&self) -> Uuid {
+        Uuid::from_u128_le(self.uuid)
+    }
+
+    fn payload_size(&self) -> Result<usize> {
+        let size = u64::from_le(self.payload_size);
+        usize::try_from(size).map_err(|_| Error::InvalidEntrySize(size))
+    }
+
+    fn cipher(&self) -> Result<EntryCipher> {
+        EntryCipher::from_id(u16::from_le(self.cipher))
+    }
+}
+
+/// Version of this pvmfw, recorded in new entries as the minimum version allowed to read them.
+///
+/// Must be incremented by any release which mustn't be rolled back to older pvmfw images.
+pub(crate) const PVMFW_VERSION: u32 = 1;
+
+/// Size of the entries recorded before `EntryBody::min_pvmfw_version` was added.
+const LEGACY_ENTRY_BODY_SIZE: usize = size_of::<EntryBody>() - size_of::<u32>();
+
+#[derive(AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+pub(crate) struct EntryBody {
+    code_hash: Hash,
+    auth_hash: Hash,
+    salt: Hidden,
+    mode: u8,
+    /// Oldest pvmfw version allowed to use the entry, or 0 if the entry predates this field. New
+    /// fields are appended so that the size of the entry tells its layout.
+    min_pvmfw_version: u32,
+}
+
+impl EntryBody {
+    pub(crate) fn new(dice_inputs: &PartialInputs, salt: &Hidden) -> Self {
+        let mode = match dice_inputs.mode {
+            DiceMode::kDiceModeNotInitialized => 0,
+            DiceMode::kDiceModeNormal => 1,
+            DiceMode::kDiceModeDebug => 2,
+            DiceMode::kDiceModeMaintenance => 3,
+        };
+
+        Self {
+            code_hash: dice_inputs.code_hash,
+            auth_hash: dice_inputs.auth_hash,
             salt: *salt,
             mode,
+            min_pvmfw_version: PVMFW_VERSION.to_le(),
         }
     }
 
+    /// Parses a decrypted entry, which may have been recorded without the newer fields.
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        match bytes.len() {
+            n if n == size_of::<Self>() => Ok(Self::read_from(bytes).unwrap()),
+            LEGACY_ENTRY_BODY_SIZE => {
+                let mut body = Self::new_zeroed();
+                body.as_bytes_mut()[..LEGACY_ENTRY_BODY_SIZE].copy_from_slice(bytes);
+                Ok(body)
+            }
+            n => Err(Error::UnsupportedEntrySize(n)),
+        }
+    }
+
+    pub(crate) fn code_hash(&self) -> &Hash {
+        &self.code_hash
+    }
+
+    pub(crate) fn auth_hash(&self) -> &Hash {
+        &self.auth_hash
+    }
+
+    /// Returns the salt recorded for this instance, to be used as a DICE input.
+    ///
+    /// Note: this is secret material and must never be logged.
+    pub(crate) fn salt(&self) -> &Hidden {
+        &self.salt
+    }
+
     pub(crate) fn mode(&self) -> DiceMode {
         match self.mode {
             1 => DiceMode::kDiceModeNormal,
@@ -293,4 +1200,1231 @@ impl EntryBody {
             _ => DiceMode::kDiceModeNotInitialized,
         }
     }
+
+    /// Returns the oldest pvmfw version allowed to use the entry, if recorded.
+    pub(crate) fn min_pvmfw_version(&self) -> Option<u32> {
+        match u32::from_le(self.min_pvmfw_version) {
+            0 => None,
+            v => Some(v),
+        }
+    }
+
+    /// Fails if `running` is older than the pvmfw version which recorded the entry, preventing
+    /// the rollback of pvmfw itself.
+    pub(crate) fn check_pvmfw_version(&self, running: u32) -> Result<()> {
+        match self.min_pvmfw_version() {
+            Some(recorded) if running < recorded => {
+                Err(Error::PvmfwDowngrade { recorded, running })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::marker::PhantomData;
+    use core::ptr::NonNull;
+    use virtio_drivers::device::blk;
+    use virtio_drivers::transport::pci::bus::{BusDeviceIterator, Cam};
+    use virtio_drivers::transport::pci::{virtio_device_type, PciTransport};
+    use virtio_drivers::{BufferDirection, PhysAddr};
+
+    // Stand-ins for the pvmfw and vmbase items used by the instance.img code, which can't be built
+    // for tests.
+
+    pub(super) struct PartialInputs {
+        pub code_hash: Hash,
+        pub auth_hash: Hash,
+        pub mode: DiceMode,
+    }
+
+    pub(super) mod rand {
+        use core::fmt;
+
+        pub struct Error;
+
+        impl fmt::Display for Error {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "No random number generator in tests")
+            }
+        }
+    }
+
+    /// Walks the PCI bus like `vmbase::virtio::pci::PciTransportIterator`.
+    pub(super) struct PciTransportIterator<'a, H: Hal> {
+        pci_root: &'a mut PciRoot,
+        bus: BusDeviceIterator,
+        _hal: PhantomData<H>,
+    }
+
+    impl<'a, H: Hal> PciTransportIterator<'a, H> {
+        pub fn new(pci_root: &'a mut PciRoot) -> Self {
+            let bus = pci_root.enumerate_bus(0);
+            Self { pci_root, bus, _hal: PhantomData }
+        }
+    }
+
+    impl<H: Hal> Iterator for PciTransportIterator<'_, H> {
+        type Item = PciTransport;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let (device_function, info) = self.bus.next()?;
+                if virtio_device_type(&info).is_some() {
+                    return PciTransport::new::<H>(self.pci_root, device_function).ok();
+                }
+            }
+        }
+    }
+
+    pub(super) type VirtIOBlk<H> = blk::VirtIOBlk<H, PciTransport>;
+
+    /// VirtIO HAL for PCI buses without VirtIO devices, on which it is never called.
+    struct MockHal;
+
+    // SAFETY: None of the methods returns, so they can't break the requirements of the trait.
+    unsafe impl Hal for MockHal {
+        fn dma_alloc(_pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            unreachable!("No VirtIO device to allocate DMA buffers for");
+        }
+
+        unsafe fn dma_dealloc(_paddr: PhysAddr, _vaddr: NonNull<u8>, _pages: usize) -> i32 {
+            unreachable!("No VirtIO device to free DMA buffers of");
+        }
+
+        unsafe fn mmio_phys_to_virt(_paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+            unreachable!("No VirtIO device to map the BARs of");
+        }
+
+        unsafe fn share(_buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
+            unreachable!("No VirtIO device to share buffers with");
+        }
+
+        unsafe fn unshare(_paddr: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {
+            unreachable!("No VirtIO device to unshare buffers from");
+        }
+    }
+
+    /// Size of the configuration space of a PCI bus with `Cam::MmioCam`: 32 devices of 8
+    /// functions, with 256 bytes each.
+    const PCI_BUS_CAM_SIZE: usize = 32 * 8 * 256;
+
+    /// Returns the configuration space of a PCI bus with a single non-VirtIO device.
+    fn pci_bus_without_virtio_devices() -> Vec<u32> {
+        // Reads from absent functions return all ones.
+        let mut cam = vec![u32::MAX; PCI_BUS_CAM_SIZE / size_of::<u32>()];
+        // Device 0 of the bus is a single-function non-VirtIO device.
+        cam[..4].copy_from_slice(&[0x1234_8086, 0, 0, 0]);
+        cam
+    }
+
+    #[test]
+    fn get_recorded_entry_skips_non_virtio_devices() {
+        let mut cam = pci_bus_without_virtio_devices();
+        // SAFETY: Only bus 0 is enumerated, of which `cam` is the whole configuration space, and
+        // `cam` outlives `pci_root` without being accessed otherwise.
+        let mut pci_root = unsafe { PciRoot::new(cam.as_mut_ptr().cast(), Cam::MmioCam) };
+
+        let result = get_recorded_entry::<MockHal>(&mut pci_root, &SECRET);
+
+        assert!(matches!(result, Err(Error::MissingInstanceImage(disks)) if disks.is_empty()));
+    }
+
+    #[test]
+    fn has_valid_recorded_entry_requires_instance_img() {
+        let mut cam = pci_bus_without_virtio_devices();
+        // SAFETY: Only bus 0 is enumerated, of which `cam` is the whole configuration space, and
+        // `cam` outlives `pci_root` without being accessed otherwise.
+        let mut pci_root = unsafe { PciRoot::new(cam.as_mut_ptr().cast(), Cam::MmioCam) };
+
+        let result = has_valid_recorded_entry::<MockHal>(&mut pci_root, &SECRET);
+
+        assert!(matches!(result, Err(Error::MissingInstanceImage(_))));
+    }
+
+    #[test]
+    fn secret_matches_entry_requires_instance_img() {
+        let mut cam = pci_bus_without_virtio_devices();
+        // SAFETY: Only bus 0 is enumerated, of which `cam` is the whole configuration space, and
+        // `cam` outlives `pci_root` without being accessed otherwise.
+        let mut pci_root = unsafe { PciRoot::new(cam.as_mut_ptr().cast(), Cam::MmioCam) };
+
+        let result = secret_matches_entry::<MockHal>(&mut pci_root, &SECRET);
+
+        assert!(matches!(result, Err(Error::MissingInstanceImage(_))));
+    }
+
+    #[test]
+    fn export_instance_entry_requires_instance_img() {
+        let mut cam = pci_bus_without_virtio_devices();
+        // SAFETY: Only bus 0 is enumerated, of which `cam` is the whole configuration space, and
+        // `cam` outlives `pci_root` without being accessed otherwise.
+        let mut pci_root = unsafe { PciRoot::new(cam.as_mut_ptr().cast(), Cam::MmioCam) };
+
+        let result = export_instance_entry::<MockHal>(&mut pci_root);
+
+        assert!(matches!(result, Err(Error::MissingInstanceImage(_))));
+    }
+
+    #[test]
+    fn import_instance_entry_requires_instance_img() {
+        let mut cam = pci_bus_without_virtio_devices();
+        // SAFETY: Only bus 0 is enumerated, of which `cam` is the whole configuration space, and
+        // `cam` outlives `pci_root` without being accessed otherwise.
+        let mut pci_root = unsafe { PciRoot::new(cam.as_mut_ptr().cast(), Cam::MmioCam) };
+
+        let result = import_instance_entry::<MockHal>(&mut pci_root, &[0; 2 * BLK_SIZE], TOKEN);
+
+        assert!(matches!(result, Err(Error::MissingInstanceImage(_))));
+    }
+
+    /// In-memory instance.img, for tests which don't have access to VirtIO block devices.
+    struct FakeInstanceImg {
+        blocks: Vec<[u8; BLK_SIZE]>,
+    }
+
+    impl FakeInstanceImg {
+        /// Creates a formatted instance.img of `len` blocks, with no entries.
+        fn new(len: usize) -> Self {
+            Self::with_header(len, Header::MAGIC, Header::VERSION_1)
+        }
+
+        fn with_header(len: usize, magic: &[u8], version: u16) -> Self {
+            Self::with_created_epoch(len, magic, version, 0)
+        }
+
+        fn with_created_epoch(len: usize, magic: &[u8], version: u16, epoch: u64) -> Self {
+            let mut blocks = vec![[0; BLK_SIZE]; len];
+            let header = Header {
+                magic: magic.try_into().unwrap(),
+                version: version.to_le(),
+                created_epoch: epoch.to_le(),
+            };
+            header.write_to_prefix(blocks[0].as_mut_slice()).unwrap();
+            Self { blocks }
+        }
+
+        fn set_entry(&mut self, header_index: usize, uuid: Uuid, payload: &[u8]) {
+            let header = EntryHeader::new(uuid, payload.len()).unwrap();
+            self.blocks[header_index] = [0; BLK_SIZE];
+            header.write_to_prefix(self.blocks[header_index].as_mut_slice()).unwrap();
+            for (i, chunk) in payload.chunks(BLK_SIZE).enumerate() {
+                self.blocks[header_index + 1 + i][..chunk.len()].copy_from_slice(chunk);
+            }
+        }
+    }
+
+    impl InstanceStorage for FakeInstanceImg {
+        fn indices(&self) -> RangeInclusive<usize> {
+            0..=(self.blocks.len() - 1)
+        }
+
+        fn read_block(&mut self, index: usize, blk: &mut [u8]) -> gpt::Result<()> {
+            let src = self.blocks.get(index).ok_or(gpt::Error::BlockOutsidePartition(index))?;
+            blk.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_block(&mut self, index: usize, blk: &[u8]) -> gpt::Result<()> {
+            let dst = self.blocks.get_mut(index).ok_or(gpt::Error::BlockOutsidePartition(index))?;
+            dst.copy_from_slice(blk);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn weak_secret_is_rejected_before_decrypting_entry() {
+        let mut img = FakeInstanceImg::new(8);
+        img.set_entry(1, PvmfwEntry::UUID, &[0xe5; 100]);
+
+        let result = read_recorded_entry(&mut img, &[0; MIN_SECRET_SIZE]);
+
+        assert!(matches!(result, Err(Error::WeakSecret)));
+    }
+
+    #[test]
+    fn weak_secret_is_rejected_by_every_entry_check() {
+        let mut img = FakeInstanceImg::new(8);
+        img.set_entry(1, PvmfwEntry::UUID, &[0xe5; 100]);
+        let weak_secret = [0; MIN_SECRET_SIZE];
+
+        assert!(matches!(has_valid_entry(&mut img, &weak_secret), Err(Error::WeakSecret)));
+        #[cfg(debuggable_build)]
+        assert!(matches!(dump_entry(&mut img, &weak_secret), Err(Error::WeakSecret)));
+    }
+
+    #[test]
+    fn recorded_entry_is_read_back() {
+        let secret = [0xcd; MIN_SECRET_SIZE];
+        let mut img = FakeInstanceImg::new(8);
+        assert!(matches!(read_recorded_entry(&mut img, &secret), Ok((None, 1))));
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &secret);
+
+        let (read_back, header_index) = read_recorded_entry(&mut img, &secret).unwrap();
+
+        assert_eq!(header_index, 1);
+        assert_eq!(read_back.unwrap().as_bytes(), body.as_bytes());
+    }
+
+    #[test]
+    fn empty_secret_is_weak() {
+        assert!(matches!(check_secret_strength(&[]), Err(Error::WeakSecret)));
+    }
+
+    #[test]
+    fn all_zero_secret_is_weak() {
+        assert!(matches!(check_secret_strength(&[0; 2 * MIN_SECRET_SIZE]), Err(Error::WeakSecret)));
+    }
+
+    #[test]
+    fn short_secret_is_weak() {
+        let secret = [0xcd; MIN_SECRET_SIZE - 1];
+
+        assert!(matches!(check_secret_strength(&secret), Err(Error::WeakSecret)));
+    }
+
+    #[test]
+    fn long_enough_secret_is_accepted() {
+        let mut secret = [0; MIN_SECRET_SIZE];
+        secret[MIN_SECRET_SIZE - 1] = 1;
+
+        assert!(check_secret_strength(&secret).is_ok());
+        assert!(check_secret_strength(&[0xcd; 64]).is_ok());
+    }
+
+    /// Claim token of the writer under test.
+    const TOKEN: u128 = 0x70c3;
+    const CHECK_VALUE: CheckValue = [0xcc; CHECK_VALUE_SIZE];
+    const SECRET: [u8; MIN_SECRET_SIZE] = [0x5e; MIN_SECRET_SIZE];
+    const WRONG_SECRET: [u8; MIN_SECRET_SIZE] = [0x3a; MIN_SECRET_SIZE];
+
+    const CODE_HASH: Hash = [0xc0; size_of::<Hash>()];
+    const AUTH_HASH: Hash = [0xa0; size_of::<Hash>()];
+
+    fn dice_inputs(mode: DiceMode) -> PartialInputs {
+        PartialInputs { code_hash: CODE_HASH, auth_hash: AUTH_HASH, mode }
+    }
+
+    #[test]
+    fn entry_body_accessors_return_recorded_values() {
+        let salt = [0x5a; size_of::<Hidden>()];
+        let body = EntryBody::new(&dice_inputs(DiceMode::kDiceModeDebug), &salt);
+
+        assert_eq!(body.code_hash(), &CODE_HASH);
+        assert_eq!(body.auth_hash(), &AUTH_HASH);
+        assert_eq!(body.salt(), &salt);
+        assert_eq!(body.mode(), DiceMode::kDiceModeDebug);
+    }
+
+    #[test]
+    fn entry_body_salt_survives_serialization() {
+        let salt = [0x3c; size_of::<Hidden>()];
+        let body = EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &salt);
+
+        let read_back = EntryBody::read_from(body.as_bytes()).unwrap();
+
+        assert_eq!(read_back.salt(), &salt);
+    }
+
+    #[test]
+    fn entry_body_records_pvmfw_version() {
+        let salt = [0x5a; size_of::<Hidden>()];
+        let body = EntryBody::new(&dice_inputs(DiceMode::kDiceModeDebug), &salt);
+
+        assert_eq!(body.min_pvmfw_version(), Some(PVMFW_VERSION));
+
+        let legacy = EntryBody::parse(&body.as_bytes()[..LEGACY_ENTRY_BODY_SIZE]).unwrap();
+
+        assert_eq!(legacy.min_pvmfw_version(), None);
+        assert_eq!(legacy.code_hash(), &CODE_HASH);
+        assert_eq!(legacy.mode(), DiceMode::kDiceModeDebug);
+        assert!(legacy.check_pvmfw_version(0).is_ok());
+        assert!(matches!(EntryBody::parse(&[0; 10]), Err(Error::UnsupportedEntrySize(10))));
+    }
+
+    fn entry_body_with_min_pvmfw_version(version: u32) -> EntryBody {
+        let salt = [0x5a; size_of::<Hidden>()];
+        let mut body = EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &salt);
+        body.min_pvmfw_version = version.to_le();
+        body
+    }
+
+    #[test]
+    fn same_pvmfw_version_is_accepted() {
+        let body = entry_body_with_min_pvmfw_version(5);
+
+        assert!(body.check_pvmfw_version(5).is_ok());
+    }
+
+    #[test]
+    fn newer_pvmfw_version_is_accepted() {
+        let body = entry_body_with_min_pvmfw_version(5);
+
+        assert!(body.check_pvmfw_version(6).is_ok());
+    }
+
+    #[test]
+    fn older_pvmfw_version_is_rejected() {
+        let body = entry_body_with_min_pvmfw_version(5);
+
+        assert!(matches!(
+            body.check_pvmfw_version(4),
+            Err(Error::PvmfwDowngrade { recorded: 5, running: 4 })
+        ));
+    }
+
+    #[test]
+    fn export_without_entry_returns_none() {
+        let mut img = FakeInstanceImg::new(8);
+
+        assert!(matches!(export_entry(&mut img), Ok(None)));
+    }
+
+    #[test]
+    fn exported_entry_can_be_imported() {
+        const FOREIGN_UUID: Uuid = Uuid::from_u128(0x1234);
+        let ciphertext = [0xe5; 100];
+        let mut src = FakeInstanceImg::new(8);
+        src.set_entry(1, FOREIGN_UUID, &[0xf0; 10]);
+        src.set_entry(3, PvmfwEntry::UUID, &ciphertext);
+
+        let blob = export_entry(&mut src).unwrap().unwrap();
+        assert_eq!(blob.len(), EXPORTED_ENTRY_SIZE);
+
+        let mut dst = FakeInstanceImg::new(8);
+        import_entry(&mut dst, &blob, TOKEN).unwrap();
+
+        assert!(matches!(
+            locate_entry(&mut dst),
+            Ok(PvmfwEntry::Existing { header_index: 1, payload_size: 100 })
+        ));
+        assert_eq!(export_entry(&mut dst).unwrap().unwrap(), blob);
+    }
+
+    #[test]
+    fn import_rejects_foreign_entry() {
+        let mut src = FakeInstanceImg::new(8);
+        src.set_entry(1, Uuid::from_u128(0x1234), &[0xf0; 10]);
+        let mut blob = vec![0; EXPORTED_ENTRY_SIZE];
+        blob[..BLK_SIZE].copy_from_slice(&src.blocks[1]);
+
+        let mut dst = FakeInstanceImg::new(8);
+
+        assert!(matches!(import_entry(&mut dst, &blob, TOKEN), Err(Error::InvalidImportedEntry)));
+        assert!(matches!(
+            import_entry(&mut dst, &blob[..BLK_SIZE], TOKEN),
+            Err(Error::UnsupportedEntrySize(_))
+        ));
+    }
+
+    #[test]
+    fn import_rejects_existing_entry() {
+        let mut src = FakeInstanceImg::new(8);
+        src.set_entry(1, PvmfwEntry::UUID, &[0xe5; 100]);
+        let blob = export_entry(&mut src).unwrap().unwrap();
+
+        assert!(matches!(import_entry(&mut src, &blob, TOKEN), Err(Error::InstanceEntryExists)));
+    }
+
+    #[test]
+    fn find_partition_skips_corrupt_gpt() {
+        let devices = [Ok(0), Ok(1)].into_iter();
+        let partition = find_partition(devices, |device| match device {
+            0 => Err(gpt::Error::InvalidHeader),
+            _ => Ok(Some(device)),
+        });
+
+        assert!(matches!(partition, Ok(1)));
+    }
+
+    #[test]
+    fn find_partition_reports_corrupt_gpt() {
+        let devices = [Ok(0), Ok(1)].into_iter();
+        let partition = find_partition(devices, |device| match device {
+            0 => Err(gpt::Error::InvalidHeader),
+            _ => Ok(None::<()>),
+        });
+
+        let Err(Error::MissingInstanceImage(disks)) = partition else {
+            panic!("Unexpected result");
+        };
+        assert!(matches!(
+            disks.as_slice(),
+            [DiskStatus::Unreadable(gpt::Error::InvalidHeader), DiskStatus::NoPartition]
+        ));
+    }
+
+    #[test]
+    fn find_partition_lists_disks_without_partition() {
+        let devices = [Ok(0), Ok(1)].into_iter();
+        let partition = find_partition(devices, |_| Ok(None::<()>));
+
+        let Err(e @ Error::MissingInstanceImage(_)) = partition else {
+            panic!("Unexpected result");
+        };
+        assert_eq!(
+            e.to_string(),
+            "Failed to find the instance.img partition in 2 disk(s); \
+             disk #0: no instance.img partition; disk #1: no instance.img partition"
+        );
+    }
+
+    #[test]
+    fn find_partition_without_disks() {
+        let partition = find_partition(core::iter::empty::<Result<()>>(), |_| Ok(None::<()>));
+
+        assert!(matches!(partition, Err(Error::MissingInstanceImage(disks)) if disks.is_empty()));
+    }
+
+    #[test]
+    fn locate_entry_skips_interleaved_foreign_entries() {
+        const OWNER_A: Uuid = Uuid::from_u128(0xa);
+        const OWNER_B: Uuid = Uuid::from_u128(0xb);
+        let mut img = FakeInstanceImg::new(16);
+        // Layout: [instance.img header, A (3 blks), pvmfw (1 blk), B (2 blks), A' (0 blks), free]
+        img.set_entry(1, OWNER_A, &[0xaa; SIZE_A]);
+        img.set_entry(5, PvmfwEntry::UUID, &[0xe5; 100]);
+        img.set_entry(7, OWNER_B, &[0xbb; SIZE_B]);
+        img.set_entry(10, Uuid::from_u128(0xa2), &[]);
+
+        const SIZE_A: usize = 2 * BLK_SIZE + 1;
+        assert!(matches!(
+            locate_entry_of(&mut img, OWNER_A),
+            Ok(PvmfwEntry::Existing { header_index: 1, payload_size: SIZE_A })
+        ));
+        assert!(matches!(
+            locate_entry(&mut img),
+            Ok(PvmfwEntry::Existing { header_index: 5, payload_size: 100 })
+        ));
+        const SIZE_B: usize = BLK_SIZE + 1;
+        assert!(matches!(
+            locate_entry_of(&mut img, OWNER_B),
+            Ok(PvmfwEntry::Existing { header_index: 7, payload_size: SIZE_B })
+        ));
+        assert!(matches!(
+            locate_entry_of(&mut img, Uuid::from_u128(0xc)),
+            Ok(PvmfwEntry::New { header_index: 11 })
+        ));
+    }
+
+    #[test]
+    fn locate_entry_skips_foreign_entries_without_payload() {
+        let mut img = FakeInstanceImg::new(8);
+        // Layout: [instance.img header, A (0 blks), B (0 blks), pvmfw (1 blk), C (0 blks), free]
+        img.set_entry(1, Uuid::from_u128(0xa), &[]);
+        img.set_entry(2, Uuid::from_u128(0xb), &[]);
+        img.set_entry(3, PvmfwEntry::UUID, &[0xe5; 100]);
+        img.set_entry(5, Uuid::from_u128(0xc), &[]);
+
+        assert!(matches!(
+            locate_entry(&mut img),
+            Ok(PvmfwEntry::Existing { header_index: 3, payload_size: 100 })
+        ));
+        assert!(matches!(
+            locate_entry_of(&mut img, Uuid::from_u128(0xc)),
+            Ok(PvmfwEntry::Existing { header_index: 5, payload_size: 0 })
+        ));
+        assert!(matches!(
+            locate_entry_of(&mut img, Uuid::from_u128(0xd)),
+            Ok(PvmfwEntry::New { header_index: 6 })
+        ));
+    }
+
+    #[test]
+    fn payload_blocks_rounds_up() {
+        assert!(matches!(payload_blocks(0), Ok(0)));
+        assert!(matches!(payload_blocks(1), Ok(1)));
+        assert!(matches!(payload_blocks(BLK_SIZE), Ok(1)));
+        assert!(matches!(payload_blocks(BLK_SIZE + 1), Ok(2)));
+    }
+
+    #[cfg(debuggable_build)]
+    #[test]
+    fn dumped_entry_has_measurements_but_not_salt() {
+        let mut img = FakeInstanceImg::new(8);
+        assert!(matches!(dump_entry(&mut img, &SECRET), Ok(None)));
+        let salt = [0x5a; size_of::<Hidden>()];
+        let body = EntryBody::new(&dice_inputs(DiceMode::kDiceModeDebug), &salt);
+        record_entry(&mut img, &body, &SECRET);
+
+        let dump = dump_entry(&mut img, &SECRET).unwrap().unwrap();
+
+        assert!(dump.contains(&"c0".repeat(size_of::<Hash>())), "{dump}");
+        assert!(dump.contains(&"a0".repeat(size_of::<Hash>())), "{dump}");
+        assert!(dump.contains("kDiceModeDebug"), "{dump}");
+        assert!(!dump.contains("5a5a"), "{dump}");
+    }
+
+    #[test]
+    fn instance_img_digest_is_chained_over_blocks() {
+        let mut img = FakeInstanceImg::new(2);
+        let digester = Digester::sha256();
+        let mut expected = vec![0; 32];
+        for blk in &img.blocks {
+            expected = digester.digest(&[&expected[..], &blk[..]].concat()).unwrap();
+        }
+
+        assert_eq!(instance_img_digest(&mut img).unwrap(), expected);
+    }
+
+    #[test]
+    fn instance_img_integrity_is_checked() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+        let expected = instance_img_digest(&mut img).unwrap();
+
+        assert!(verify_instance_img_integrity(&mut img, &expected).is_ok());
+
+        img.blocks[7][0] ^= 1; // Tamper with a free block.
+
+        assert!(matches!(
+            verify_instance_img_integrity(&mut img, &expected),
+            Err(Error::InstanceImageIntegrityMismatch)
+        ));
+        assert!(matches!(
+            verify_instance_img_integrity(&mut img, &expected[..16]),
+            Err(Error::InstanceImageIntegrityMismatch)
+        ));
+    }
+
+    fn record_entry(img: &mut FakeInstanceImg, body: &EntryBody, secret: &[u8]) {
+        let PvmfwEntry::New { header_index } = locate_entry(img).unwrap() else {
+            panic!("instance.img already has a pvmfw entry");
+        };
+        let mut blk = [0; BLK_SIZE];
+        let payload_size = seal_entry(body, secret, &mut blk).unwrap();
+        let check_value = derive_check_value(secret).unwrap();
+        write_entry(img, header_index, &blk, payload_size, check_value, ENTRY_CIPHER, TOKEN)
+            .unwrap();
+    }
+
+    #[test]
+    fn has_valid_entry_without_entry() {
+        let mut img = FakeInstanceImg::new(8);
+
+        assert!(matches!(has_valid_entry(&mut img, &SECRET), Ok(false)));
+    }
+
+    #[test]
+    fn has_valid_entry_with_right_secret() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+
+        assert!(matches!(has_valid_entry(&mut img, &SECRET), Ok(true)));
+    }
+
+    #[test]
+    fn has_valid_entry_with_wrong_secret() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+
+        assert!(matches!(has_valid_entry(&mut img, &WRONG_SECRET), Err(Error::BoringSslFailed(_))));
+    }
+
+    fn existing_entry(img: &mut FakeInstanceImg) -> (usize, usize) {
+        let PvmfwEntry::Existing { header_index, payload_size } = locate_entry(img).unwrap() else {
+            panic!("instance.img has no pvmfw entry");
+        };
+        (header_index, payload_size)
+    }
+
+    #[test]
+    fn entry_matches_right_secret() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+        let (header_index, payload_size) = existing_entry(&mut img);
+
+        assert!(matches!(
+            entry_matches_secret(&mut img, header_index, payload_size, &SECRET),
+            Ok(true)
+        ));
+    }
+
+    #[test]
+    fn entry_does_not_match_wrong_secret() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+        let (header_index, payload_size) = existing_entry(&mut img);
+
+        assert!(matches!(
+            entry_matches_secret(&mut img, header_index, payload_size, &WRONG_SECRET),
+            Ok(false)
+        ));
+    }
+
+    #[test]
+    fn entry_check_value_is_checked_without_decrypting() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+        let (header_index, payload_size) = existing_entry(&mut img);
+        img.blocks[2] = [0; BLK_SIZE]; // Corrupt the payload.
+
+        assert!(matches!(
+            entry_matches_secret(&mut img, header_index, payload_size, &SECRET),
+            Ok(true)
+        ));
+    }
+
+    #[test]
+    fn entry_without_check_value_is_decrypted() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+        let check_value_offset = size_of::<u128>() + size_of::<u64>();
+        img.blocks[1][check_value_offset..check_value_offset + CHECK_VALUE_SIZE].fill(0);
+        let (header_index, payload_size) = existing_entry(&mut img);
+
+        assert!(matches!(
+            entry_matches_secret(&mut img, header_index, payload_size, &SECRET),
+            Ok(true)
+        ));
+        assert!(matches!(
+            entry_matches_secret(&mut img, header_index, payload_size, &WRONG_SECRET),
+            Ok(false)
+        ));
+    }
+
+    #[test]
+    fn entry_without_check_value_reports_io_errors() {
+        let mut img = FakeInstanceImg::new(8);
+        // The payload of an entry in the last block would be outside of the instance.img.
+        set_raw_entry_header(&mut img, 7, PvmfwEntry::UUID, 100);
+
+        assert!(matches!(
+            entry_matches_secret(&mut img, 7, 100, &SECRET),
+            Err(Error::FailedIo(gpt::Error::BlockOutsidePartition(8)))
+        ));
+    }
+
+    #[test]
+    fn secret_is_matched_against_recorded_entry() {
+        let mut img = FakeInstanceImg::new(8);
+        assert!(matches!(secret_matches_recorded_entry(&mut img, &SECRET), Ok(false)));
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+
+        assert!(matches!(secret_matches_recorded_entry(&mut img, &SECRET), Ok(true)));
+        assert!(matches!(secret_matches_recorded_entry(&mut img, &WRONG_SECRET), Ok(false)));
+    }
+
+    #[test]
+    fn entry_protected_by_another_secret_is_not_read() {
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &SECRET);
+
+        let result = read_recorded_entry(&mut img, &WRONG_SECRET);
+
+        assert!(matches!(result, Err(Error::EntrySecretMismatch)));
+    }
+
+    fn set_raw_entry_header(img: &mut FakeInstanceImg, header_index: usize, uuid: Uuid, size: u64) {
+        let header = EntryHeader {
+            uuid: uuid.to_u128_le(),
+            payload_size: size.to_le(),
+            check_value: [0; CHECK_VALUE_SIZE],
+            cipher: 0,
+        };
+        img.blocks[header_index] = [0; BLK_SIZE];
+        header.write_to_prefix(img.blocks[header_index].as_mut_slice()).unwrap();
+    }
+
+    #[test]
+    fn locate_entry_rejects_oversized_foreign_entry() {
+        let mut img = FakeInstanceImg::new(8);
+        set_raw_entry_header(&mut img, 1, Uuid::from_u128(0x1234), u64::MAX);
+
+        assert!(matches!(
+            locate_entry(&mut img),
+            Err(Error::UnsupportedEntrySize(_) | Error::InvalidEntrySize(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn payload_size_exceeding_usize_is_rejected() {
+        let size = u64::try_from(usize::MAX).unwrap() + 1;
+        let mut img = FakeInstanceImg::new(8);
+        set_raw_entry_header(&mut img, 1, PvmfwEntry::UUID, size);
+
+        assert!(matches!(locate_entry(&mut img), Err(Error::InvalidEntrySize(s)) if s == size));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn payload_size_up_to_usize_max_is_accepted() {
+        let mut img = FakeInstanceImg::new(8);
+        set_raw_entry_header(&mut img, 1, PvmfwEntry::UUID, u64::MAX);
+
+        assert!(matches!(
+            locate_entry(&mut img),
+            Ok(PvmfwEntry::Existing { header_index: 1, payload_size: usize::MAX })
+        ));
+        assert!(matches!(export_entry(&mut img), Err(Error::UnsupportedEntrySize(usize::MAX))));
+    }
+
+    fn seal_and_open(seal_cipher: EntryCipher, open_cipher: EntryCipher) -> Result<Vec<u8>> {
+        const MESSAGE: &[u8] = b"instance.img entry";
+        let secret = &SECRET;
+        let mut sealed = [0; BLK_SIZE];
+        let sealed =
+            aead_ctx_for_cipher(secret, seal_cipher)?.seal(MESSAGE, &[], &[], &mut sealed)?;
+        let mut opened = [0; BLK_SIZE];
+        let opened =
+            aead_ctx_for_cipher(secret, open_cipher)?.open(sealed, &[], &[], &mut opened)?;
+        Ok(opened.to_vec())
+    }
+
+    #[test]
+    fn derived_keys_match_cipher() {
+        assert_eq!(EntryCipher::Aes128GcmRandNonce.aead().key_length(), 16);
+        assert_eq!(EntryCipher::Aes256GcmRandNonce.aead().key_length(), 32);
+        assert_eq!(derive_key::<16>(&SECRET).unwrap().len(), 16);
+        assert_eq!(derive_key::<32>(&SECRET).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn entry_ciphers_round_trip() {
+        for cipher in [EntryCipher::Aes128GcmRandNonce, EntryCipher::Aes256GcmRandNonce] {
+            assert_eq!(seal_and_open(cipher, cipher).unwrap(), b"instance.img entry");
+        }
+    }
+
+    #[test]
+    fn entry_is_opened_with_recorded_cipher() {
+        let secret = [0xcd; MIN_SECRET_SIZE];
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        for cipher in [EntryCipher::Aes128GcmRandNonce, EntryCipher::Aes256GcmRandNonce] {
+            let mut img = FakeInstanceImg::new(8);
+            let mut blk = [0; BLK_SIZE];
+            let aead_ctx = aead_ctx_for_cipher(&secret, cipher).unwrap();
+            let payload_size = aead_ctx.seal(body.as_bytes(), &[], &[], &mut blk).unwrap().len();
+            let check_value = derive_check_value(&secret).unwrap();
+            write_entry(&mut img, 1, &blk, payload_size, check_value, cipher, TOKEN).unwrap();
+
+            let (read_back, _) = read_recorded_entry(&mut img, &secret).unwrap();
+
+            assert_eq!(read_back.unwrap().as_bytes(), body.as_bytes(), "{cipher:?}");
+        }
+    }
+
+    #[test]
+    fn entry_with_unknown_cipher_is_rejected() {
+        let secret = [0xcd; MIN_SECRET_SIZE];
+        let mut img = FakeInstanceImg::new(8);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+        record_entry(&mut img, &body, &secret);
+        let cipher_offset = size_of::<EntryHeader>() - size_of::<u16>();
+        img.blocks[1][cipher_offset..size_of::<EntryHeader>()].copy_from_slice(&7u16.to_le_bytes());
+
+        assert!(matches!(
+            read_recorded_entry(&mut img, &secret),
+            Err(Error::UnsupportedEntryCipher(7))
+        ));
+    }
+
+    #[test]
+    fn entry_ciphers_are_not_interchangeable() {
+        assert!(seal_and_open(EntryCipher::Aes128GcmRandNonce, EntryCipher::Aes256GcmRandNonce)
+            .is_err());
+        assert!(seal_and_open(EntryCipher::Aes256GcmRandNonce, EntryCipher::Aes128GcmRandNonce)
+            .is_err());
+    }
+
+    #[test]
+    fn header_with_supported_version_is_accepted() {
+        let mut img = FakeInstanceImg::with_header(8, Header::MAGIC, Header::VERSION_1);
+
+        assert!(matches!(locate_entry(&mut img), Ok(PvmfwEntry::New { header_index: 1 })));
+    }
+
+    #[test]
+    fn header_with_future_version_is_unsupported() {
+        let mut img = FakeInstanceImg::with_header(8, Header::MAGIC, Header::VERSION_1 + 1);
+
+        assert!(matches!(locate_entry(&mut img), Err(Error::UnsupportedInstanceImageVersion(2))));
+    }
+
+    #[test]
+    fn header_with_bad_magic_is_invalid() {
+        let magic = [b'X'; Header::MAGIC.len()];
+        let mut img = FakeInstanceImg::with_header(8, &magic, Header::VERSION_1);
+
+        assert!(matches!(locate_entry(&mut img), Err(Error::InvalidInstanceImageHeader)));
+    }
+
+    #[test]
+    fn header_version_is_read_back() {
+        let mut img = FakeInstanceImg::with_header(8, Header::MAGIC, Header::VERSION_1);
+
+        assert!(matches!(read_header_version(&mut img), Ok(Header::VERSION_1)));
+    }
+
+    #[test]
+    fn header_version_is_read_back_when_unsupported() {
+        let mut img = FakeInstanceImg::with_header(8, Header::MAGIC, Header::VERSION_1 + 1);
+
+        assert!(matches!(read_header_version(&mut img), Ok(2)));
+    }
+
+    #[test]
+    fn header_created_epoch_is_read_back() {
+        let epoch = 1_700_000_000;
+        let mut img =
+            FakeInstanceImg::with_created_epoch(8, Header::MAGIC, Header::VERSION_1, epoch);
+
+        assert!(matches!(read_header_created_epoch(&mut img), Ok(Some(e)) if e == epoch));
+        assert!(matches!(locate_entry(&mut img), Ok(PvmfwEntry::New { header_index: 1 })));
+    }
+
+    #[test]
+    fn legacy_header_has_no_created_epoch() {
+        // Images formatted before the creation time was recorded are zero-filled past the version.
+        let mut img = FakeInstanceImg::new(8);
+
+        assert!(matches!(read_header_created_epoch(&mut img), Ok(None)));
+    }
+
+    #[test]
+    fn header_version_requires_magic() {
+        let magic = [b'X'; Header::MAGIC.len()];
+        let mut img = FakeInstanceImg::with_header(8, &magic, Header::VERSION_1);
+
+        assert!(matches!(read_header_version(&mut img), Err(Error::InvalidInstanceImageHeader)));
+    }
+
+    /// Instance.img where another writer, which also found `slot` free, claims it after the writer
+    /// under test did, once the claim of the latter has been read back `reads_before_race` times.
+    struct RacingInstanceImg {
+        img: FakeInstanceImg,
+        slot: usize,
+        reads_before_race: usize,
+        claimed: bool,
+        raced: bool,
+    }
+
+    impl RacingInstanceImg {
+        fn new(img: FakeInstanceImg, slot: usize, reads_before_race: usize) -> Self {
+            Self { img, slot, reads_before_race, claimed: false, raced: false }
+        }
+    }
+
+    impl InstanceStorage for RacingInstanceImg {
+        fn indices(&self) -> RangeInclusive<usize> {
+            self.img.indices()
+        }
+
+        fn read_block(&mut self, index: usize, blk: &mut [u8]) -> gpt::Result<()> {
+            if index == self.slot && self.claimed && !self.raced {
+                if self.reads_before_race == 0 {
+                    self.raced = true;
+                    self.img.blocks[index] = claim_block(!TOKEN);
+                } else {
+                    self.reads_before_race -= 1;
+                }
+            }
+            self.img.read_block(index, blk)
+        }
+
+        fn write_block(&mut self, index: usize, blk: &[u8]) -> gpt::Result<()> {
+            self.claimed |= index == self.slot;
+            self.img.write_block(index, blk)
+        }
+    }
+
+    fn claim_block(token: u128) -> [u8; BLK_SIZE] {
+        let mut claim = [0; BLK_SIZE];
+        ClaimMarker::new(token).unwrap().write_to_prefix(claim.as_mut_slice()).unwrap();
+        claim
+    }
+
+    #[test]
+    fn max_plaintext_size_matches_layout() {
+        // AES-GCM with random nonce appends a 12-byte nonce and a 16-byte tag.
+        const OVERHEAD: usize = 12 + 16;
+
+        assert_eq!(max_plaintext_size(), BLK_SIZE - OVERHEAD);
+        assert_eq!(EntryCipher::Aes128GcmRandNonce.max_plaintext_size(), BLK_SIZE - OVERHEAD);
+        assert!(size_of::<EntryBody>() <= max_plaintext_size());
+    }
+
+    #[test]
+    fn largest_plaintext_fits_in_payload_block() {
+        let aead_ctx = aead_ctx_from_secret(&[0x5e; 32]).unwrap();
+        let plaintext = vec![0xab; max_plaintext_size()];
+        let mut blk = [0; BLK_SIZE];
+
+        let sealed = aead_ctx.seal(&plaintext, &[], &[], &mut blk).unwrap();
+
+        assert_eq!(sealed.len(), BLK_SIZE);
+    }
+
+    #[test]
+    fn claim_is_replaced_by_entry_header() {
+        let mut img = FakeInstanceImg::new(8);
+
+        write_entry(&mut img, 1, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN).unwrap();
+
+        let header = EntryHeader::read_from_prefix(img.blocks[1].as_slice()).unwrap();
+        assert_eq!(header.uuid(), PvmfwEntry::UUID);
+        assert!(img.blocks[1][size_of::<EntryHeader>()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn concurrently_claimed_slot_is_skipped() {
+        let mut racing = RacingInstanceImg::new(FakeInstanceImg::new(8), 1, 0);
+
+        write_entry(&mut racing, 1, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN)
+            .unwrap();
+
+        assert!(racing.raced);
+        assert!(matches!(
+            locate_entry(&mut racing.img),
+            Ok(PvmfwEntry::Existing { header_index: 3, payload_size: 100 })
+        ));
+        assert_eq!(racing.img.blocks[4], [0xe5; BLK_SIZE]);
+    }
+
+    #[test]
+    fn slot_claimed_after_read_back_is_skipped() {
+        // The other writer read the slot as free before the claim under test was written, but
+        // only overwrites it after that claim was first read back.
+        let mut racing = RacingInstanceImg::new(FakeInstanceImg::new(8), 1, 1);
+
+        write_entry(&mut racing, 1, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN)
+            .unwrap();
+
+        assert!(racing.raced);
+        assert_eq!(racing.img.blocks[1], claim_block(!TOKEN));
+        assert_eq!(racing.img.blocks[2], [0; BLK_SIZE]);
+        assert!(matches!(
+            locate_entry(&mut racing.img),
+            Ok(PvmfwEntry::Existing { header_index: 3, payload_size: 100 })
+        ));
+    }
+
+    #[test]
+    fn slot_claimed_at_end_of_quiesce_window_is_skipped() {
+        let mut racing =
+            RacingInstanceImg::new(FakeInstanceImg::new(8), 1, CLAIM_QUIESCE_READS - 1);
+
+        write_entry(&mut racing, 1, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN)
+            .unwrap();
+
+        assert!(racing.raced);
+        assert_eq!(racing.img.blocks[1], claim_block(!TOKEN));
+        assert!(matches!(
+            locate_entry(&mut racing.img),
+            Ok(PvmfwEntry::Existing { header_index: 3, payload_size: 100 })
+        ));
+    }
+
+    #[test]
+    fn claim_fails_when_racing_for_last_slot() {
+        let mut racing = RacingInstanceImg::new(FakeInstanceImg::new(4), 2, 0);
+        racing.img.set_entry(1, Uuid::from_u128(0x1234), &[]);
+
+        assert!(matches!(
+            write_entry(&mut racing, 2, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN),
+            Err(Error::InstanceImageFull)
+        ));
+    }
+
+    #[test]
+    fn payload_is_not_written_over_next_entry() {
+        let mut img = FakeInstanceImg::new(8);
+        // Layout: [instance.img header, A (0 blks, under-counted), free, B (1 blk), free]
+        img.set_entry(1, Uuid::from_u128(0xa), &[]);
+        img.set_entry(3, Uuid::from_u128(0xb), &[0xbb; 16]);
+        let b_header = img.blocks[3];
+
+        assert!(matches!(locate_entry(&mut img), Ok(PvmfwEntry::New { header_index: 2 })));
+        assert!(matches!(
+            write_entry(&mut img, 2, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN),
+            Err(Error::EntryOverlap(3))
+        ));
+        assert_eq!(img.blocks[3], b_header);
+        assert_eq!(img.blocks[2], [0; BLK_SIZE]);
+    }
+
+    #[test]
+    fn payload_must_fit_in_instance_img() {
+        let mut img = FakeInstanceImg::new(3);
+        img.set_entry(1, Uuid::from_u128(0xa), &[]);
+
+        assert!(matches!(locate_entry(&mut img), Ok(PvmfwEntry::New { header_index: 2 })));
+        assert!(matches!(
+            write_entry(&mut img, 2, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN),
+            Err(Error::InstanceImageFull)
+        ));
+        assert_eq!(img.blocks[2], [0; BLK_SIZE]);
+    }
+
+    #[test]
+    fn stale_claims_are_skipped_by_readers() {
+        let mut img = FakeInstanceImg::new(8);
+        assert_eq!(claim_slot(&mut img, 1, !TOKEN).unwrap(), 1);
+        img.set_entry(3, PvmfwEntry::UUID, &[0xe5; 100]);
+
+        assert!(matches!(
+            locate_entry(&mut img),
+            Ok(PvmfwEntry::Existing { header_index: 3, payload_size: 100 })
+        ));
+    }
+
+    #[test]
+    fn stale_claim_is_reclaimed() {
+        let mut img = FakeInstanceImg::new(4);
+        assert_eq!(claim_slot(&mut img, 1, !TOKEN).unwrap(), 1);
+        // The interrupted writer had already written its payload.
+        img.blocks[2] = [0xa5; BLK_SIZE];
+
+        assert!(matches!(locate_entry(&mut img), Ok(PvmfwEntry::New { header_index: 1 })));
+        write_entry(&mut img, 1, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN).unwrap();
+
+        assert!(matches!(
+            locate_entry(&mut img),
+            Ok(PvmfwEntry::Existing { header_index: 1, payload_size: 100 })
+        ));
+        assert_eq!(img.blocks[2], [0xe5; BLK_SIZE]);
+    }
+
+    /// Instance.img where the claim in `slot` is committed by its writer while being read.
+    struct CommittingInstanceImg {
+        img: FakeInstanceImg,
+        slot: usize,
+    }
+
+    impl InstanceStorage for CommittingInstanceImg {
+        fn indices(&self) -> RangeInclusive<usize> {
+            self.img.indices()
+        }
+
+        fn read_block(&mut self, index: usize, blk: &mut [u8]) -> gpt::Result<()> {
+            self.img.read_block(index, blk)?;
+            if index == self.slot && self.img.blocks[index] == claim_block(!TOKEN) {
+                self.img.set_entry(index, Uuid::from_u128(0xa), &[0xa; BLK_SIZE]);
+            }
+            Ok(())
+        }
+
+        fn write_block(&mut self, index: usize, blk: &[u8]) -> gpt::Result<()> {
+            self.img.write_block(index, blk)
+        }
+    }
+
+    #[test]
+    fn live_claim_is_not_reclaimed() {
+        let mut img = FakeInstanceImg::new(8);
+        img.blocks[1] = claim_block(!TOKEN);
+        let mut committing = CommittingInstanceImg { img, slot: 1 };
+
+        write_entry(&mut committing, 1, &[0xe5; BLK_SIZE], 100, CHECK_VALUE, ENTRY_CIPHER, TOKEN)
+            .unwrap();
+
+        assert!(matches!(
+            locate_entry_of(&mut committing.img, Uuid::from_u128(0xa)),
+            Ok(PvmfwEntry::Existing { header_index: 1, payload_size: BLK_SIZE })
+        ));
+        assert!(matches!(
+            locate_entry(&mut committing.img),
+            Ok(PvmfwEntry::Existing { header_index: 3, payload_size: 100 })
+        ));
+    }
+
+    #[test]
+    fn max_single_block_entries_accounts_for_header_and_payload_blocks() {
+        assert_eq!(max_single_block_entries(0), 0);
+        assert_eq!(max_single_block_entries(1), 0);
+        assert_eq!(max_single_block_entries(2), 0);
+        assert_eq!(max_single_block_entries(3), 1);
+        assert_eq!(max_single_block_entries(4), 1);
+        assert_eq!(max_single_block_entries(5), 2);
+        assert_eq!(max_single_block_entries(2048), 1023);
+    }
+
+    #[test]
+    fn entry_is_recorded_at_free_slot() {
+        let mut img = FakeInstanceImg::new(8);
+        img.set_entry(1, Uuid::from_u128(0xa), &[0xa; BLK_SIZE]);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+
+        record_entry_at(&mut img, &body, &SECRET, 3, TOKEN).unwrap();
+
+        let Ok(PvmfwEntry::Existing { header_index: 3, payload_size }) = locate_entry(&mut img)
+        else {
+            panic!("pvmfw entry wasn't recorded at slot 3");
+        };
+        let read_back = read_entry_body(&mut img, 3, payload_size, &SECRET).unwrap();
+        assert_eq!(read_back.as_bytes(), body.as_bytes());
+    }
+
+    #[test]
+    fn entry_is_not_recorded_at_occupied_slot() {
+        let mut img = FakeInstanceImg::new(8);
+        img.set_entry(1, Uuid::from_u128(0xa), &[0xa; BLK_SIZE]);
+        let b_header = img.blocks[1];
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+
+        assert!(matches!(
+            record_entry_at(&mut img, &body, &SECRET, 1, TOKEN),
+            Err(Error::SlotOccupied(1))
+        ));
+        assert_eq!(img.blocks[1], b_header);
+    }
+
+    #[test]
+    fn entry_is_not_recorded_at_unreachable_slot() {
+        let mut img = FakeInstanceImg::new(8);
+        // Layout: [instance.img header, A (1 blk), free]
+        img.set_entry(1, Uuid::from_u128(0xa), &[0xa; BLK_SIZE]);
+        let blocks = img.blocks.clone();
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+
+        for slot in [2, 5, 8] {
+            assert!(matches!(
+                record_entry_at(&mut img, &body, &SECRET, slot, TOKEN),
+                Err(Error::UnreachableSlot(s)) if s == slot
+            ));
+        }
+        assert_eq!(img.blocks, blocks);
+        assert!(matches!(locate_entry(&mut img), Ok(PvmfwEntry::New { header_index: 3 })));
+    }
+
+    #[test]
+    fn entry_is_not_recorded_after_pvmfw_entry() {
+        let mut img = FakeInstanceImg::new(8);
+        img.set_entry(1, PvmfwEntry::UUID, &[0xe5; 100]);
+        let body =
+            EntryBody::new(&dice_inputs(DiceMode::kDiceModeNormal), &[0x5a; size_of::<Hidden>()]);
+
+        assert!(matches!(
+            record_entry_at(&mut img, &body, &SECRET, 3, TOKEN),
+            Err(Error::InstanceEntryExists)
+        ));
+    }
 }
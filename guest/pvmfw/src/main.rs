@@ -37,9 +37,13 @@ use crate::dice::PartialInputs;
 use crate::entry::RebootReason;
 use crate::fdt::modify_for_next_stage;
 use crate::helpers::GUEST_PAGE_SIZE;
+#[cfg(debuggable_build)]
+use crate::instance::debug_dump_entry;
 use crate::instance::EntryBody;
 use crate::instance::Error as InstanceError;
-use crate::instance::{get_recorded_entry, record_instance_entry};
+use crate::instance::{
+    get_recorded_entry, header_created_epoch, header_version, record_instance_entry, PVMFW_VERSION,
+};
 use alloc::borrow::Cow;
 use alloc::boxed::Box;
 use bssl_avf::Digester;
@@ -58,6 +62,7 @@ use vmbase::memory::flush;
 use vmbase::memory::MEMORY;
 use vmbase::rand;
 use vmbase::virtio::pci;
+use vmbase::virtio::HalImpl;
 
 const NEXT_BCC_SIZE: usize = GUEST_PAGE_SIZE;
 
@@ -157,13 +162,30 @@ fn main(
     } else {
         info!("Fallback to instance.img based rollback checks");
         let (recorded_entry, mut instance_img, header_index) =
-            get_recorded_entry(&mut pci_root, cdi_seal).map_err(|e| {
+            get_recorded_entry::<HalImpl>(&mut pci_root, cdi_seal).map_err(|e| {
                 error!("Failed to get entry from instance.img: {e}");
                 RebootReason::InternalError
             })?;
+        match header_version(&mut instance_img) {
+            Ok(version) => debug!("instance.img header version: {version}"),
+            Err(e) => warn!("Failed to read the instance.img header version: {e}"),
+        }
+        match header_created_epoch(&mut instance_img) {
+            Ok(Some(epoch)) => debug!("instance.img formatted at {epoch}s since the Unix epoch"),
+            Ok(None) => debug!("instance.img formatted before its creation time was recorded"),
+            Err(e) => warn!("Failed to read the instance.img creation time: {e}"),
+        }
         let (new_instance, salt) = if let Some(entry) = recorded_entry {
-            check_dice_measurements_match_entry(&dice_inputs, &entry)?;
-            let salt = instance_hash.unwrap_or(entry.salt);
+            if let Err(e) = check_dice_measurements_match_entry(&dice_inputs, &entry) {
+                #[cfg(debuggable_build)]
+                match debug_dump_entry(&mut instance_img, cdi_seal) {
+                    Ok(Some(dump)) => info!("Recorded instance.img entry: {dump}"),
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to dump the instance.img entry: {e}"),
+                }
+                return Err(e);
+            }
+            let salt = instance_hash.unwrap_or(*entry.salt());
             (false, salt)
         } else {
             // New instance!
@@ -262,9 +284,10 @@ fn ensure_dice_measurements_match_entry(
     dice_inputs: &PartialInputs,
     entry: &EntryBody,
 ) -> Result<(), InstanceError> {
-    if entry.code_hash != dice_inputs.code_hash {
+    entry.check_pvmfw_version(PVMFW_VERSION)?;
+    if *entry.code_hash() != dice_inputs.code_hash {
         Err(InstanceError::RecordedCodeHashMismatch)
-    } else if entry.auth_hash != dice_inputs.auth_hash {
+    } else if *entry.auth_hash() != dice_inputs.auth_hash {
         Err(InstanceError::RecordedAuthHashMismatch)
     } else if entry.mode() != dice_inputs.mode {
         Err(InstanceError::RecordedDiceModeMismatch)
@@ -14,20 +14,249 @@
 
 //! High-level FDT functions.
 
+#[cfg(test)]
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::CStr;
 use core::ops::Range;
 use cstr::cstr;
-use libfdt::{Fdt, FdtError};
+use libfdt::{Fdt, FdtError, FdtNode, Phandle};
+
+/// Granule the swiotlb region must be aligned to, matching vmbase::memory::PAGE_SIZE.
+const SWIOTLB_ALIGNMENT: usize = 4096;
+/// Largest `rng-seed` accepted from the hypervisor.
+const MAX_RNG_SEED_SIZE: usize = 256;
 
 /// Reads the DICE data range from the given `fdt`.
+///
+/// Returns `FdtError::NotFound` if there is no DICE region, or `FdtError::BadValue` if it's empty,
+/// which means that the DT is broken.
 pub fn read_dice_range_from(fdt: &Fdt) -> libfdt::Result<Range<usize>> {
+    let node = find_reserved_memory_node(fdt, cstr!("google,open-dice"))?;
+    let (_, range) =
+        reserved_memory_region(node.ok_or(FdtError::NotFound)?)?.ok_or(FdtError::NotFound)?;
+    if range.is_empty() {
+        return Err(FdtError::BadValue);
+    }
+    Ok(range)
+}
+
+/// Reads the range of the DICE handover (CDIs and certificate chain), if it's passed in its own
+/// `/reserved-memory` region, separately from the DICE data range.
+///
+/// Returns `FdtError::BadValue` if the region is empty.
+#[allow(dead_code)] // TODO: Map the handover from it once pvmfw passes one.
+pub(crate) fn read_dice_handover_range(fdt: &Fdt) -> libfdt::Result<Option<Range<usize>>> {
+    let node = match find_reserved_memory_node(fdt, cstr!("android,dice-handover")) {
+        Ok(Some(node)) => node,
+        Ok(None) | Err(FdtError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let range: Range<usize> = node.first_reg()?.try_into()?;
+    if range.is_empty() {
+        return Err(FdtError::BadValue);
+    }
+    Ok(Some(range))
+}
+
+/// Returns the node name and `reg` range of each region described under `/reserved-memory`.
+///
+/// Regions without a `reg` property (e.g. dynamically allocated ones) are skipped, while regions
+/// whose name or `reg` can't be parsed yield an error.
+#[allow(dead_code)] // TODO: Use it to find the memory sharing regions, once rialto reads them.
+pub(crate) fn reserved_memory_regions(
+    fdt: &Fdt,
+) -> libfdt::Result<impl Iterator<Item = libfdt::Result<(&str, Range<usize>)>>> {
+    Ok(reserved_memory_nodes(fdt)?.filter_map(|node| reserved_memory_region(node).transpose()))
+}
+
+fn reserved_memory_region(node: FdtNode) -> libfdt::Result<Option<(&str, Range<usize>)>> {
+    let Some(mut reg) = node.reg()? else {
+        return Ok(None);
+    };
+    let name = node.name()?.to_str().map_err(|_| FdtError::BadValue)?;
+    let range = reg.next().ok_or(FdtError::BadValue)?.try_into()?;
+    Ok(Some((name, range)))
+}
+
+fn reserved_memory_nodes(fdt: &Fdt) -> libfdt::Result<impl Iterator<Item = FdtNode<'_>>> {
     let node = fdt.node(cstr!("/reserved-memory"))?.ok_or(FdtError::NotFound)?;
-    let node = node.next_compatible(cstr!("google,open-dice"))?.ok_or(FdtError::NotFound)?;
-    node.first_reg()?.try_into()
+    node.subnodes()
+}
+
+/// Finds the first `/reserved-memory` subnode compatible with `compatible`.
+fn find_reserved_memory_node<'a>(
+    fdt: &'a Fdt,
+    compatible: &CStr,
+) -> libfdt::Result<Option<FdtNode<'a>>> {
+    for node in reserved_memory_nodes(fdt)? {
+        if is_compatible(&node, compatible)? {
+            return Ok(Some(node));
+        }
+    }
+    Ok(None)
+}
+
+fn is_compatible(node: &FdtNode, compatible: &CStr) -> libfdt::Result<bool> {
+    let Some(value) = node.getprop(cstr!("compatible"))? else {
+        return Ok(false);
+    };
+    Ok(value.split(|b| *b == 0).any(|c| c == compatible.to_bytes()))
+}
+
+/// Reads the range of the restricted DMA pool (swiotlb) used to bounce buffers shared with the
+/// host, if one is described with a static `reg` under `/reserved-memory`.
+///
+/// Returns `FdtError::BadValue` if the region is empty or isn't page-aligned.
+pub(crate) fn read_swiotlb_range(fdt: &Fdt) -> libfdt::Result<Option<Range<usize>>> {
+    let node = match find_reserved_memory_node(fdt, cstr!("restricted-dma-pool")) {
+        Ok(Some(node)) => node,
+        Ok(None) | Err(FdtError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let range: Range<usize> = node.first_reg()?.try_into()?;
+    let is_aligned = |addr: usize| addr % SWIOTLB_ALIGNMENT == 0;
+    if range.is_empty() || !is_aligned(range.start) || !is_aligned(range.end) {
+        return Err(FdtError::BadValue);
+    }
+    Ok(Some(range))
 }
 
+/// Returns whether the `/avf` node, which AVF adds to the DT of its guests, is present.
+///
+/// All readers of `/avf` properties fail with `FdtError::NotFound` if the node is missing and return
+/// `None` if only the property is, so callers can use this to tell a non-AVF boot apart up front.
+pub(crate) fn avf_node_present(fdt: &Fdt) -> libfdt::Result<bool> {
+    Ok(fdt.node(cstr!("/avf"))?.is_some())
+}
+
+/// Returns the `/avf` node, or `FdtError::NotFound` if the guest wasn't booted by AVF.
+fn avf_node(fdt: &Fdt) -> libfdt::Result<FdtNode<'_>> {
+    fdt.node(cstr!("/avf"))?.ok_or(FdtError::NotFound)
+}
+
+/// Reads the root digest of the vendor image hashtree, from
+/// `/avf/vendor_hashtree_descriptor_root_digest`.
+///
+/// Returns `None` if the property is absent, or `FdtError::NotFound` if there is no `/avf` node.
 pub(crate) fn read_vendor_hashtree_root_digest(fdt: &Fdt) -> libfdt::Result<Option<&[u8]>> {
-    let node = fdt.node(cstr!("/avf"))?.ok_or(FdtError::NotFound)?;
-    node.getprop(cstr!("vendor_hashtree_descriptor_root_digest"))
+    avf_node(fdt)?.getprop(cstr!("vendor_hashtree_descriptor_root_digest"))
+}
+
+/// Hash algorithm of a hashtree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Size of the digests produced by the algorithm, in bytes.
+    pub(crate) fn digest_size(&self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+}
+
+/// Reads the hash algorithm of the vendor image hashtree, from
+/// `/avf/vendor_hashtree_descriptor_hash_algorithm`.
+///
+/// Returns `None` if the property is absent, leaving the caller to pick a default, or
+/// `FdtError::NotFound` if there is no `/avf` node. Fails with `FdtError::BadValue` if the
+/// algorithm isn't supported or doesn't match the size of the vendor hashtree root digest.
+pub(crate) fn read_vendor_hashtree_algorithm(fdt: &Fdt) -> libfdt::Result<Option<HashAlgorithm>> {
+    let Some(algorithm) =
+        avf_node(fdt)?.getprop_str(cstr!("vendor_hashtree_descriptor_hash_algorithm"))?
+    else {
+        return Ok(None);
+    };
+    let algorithm = match algorithm.to_bytes() {
+        b"sha256" => HashAlgorithm::Sha256,
+        b"sha512" => HashAlgorithm::Sha512,
+        _ => return Err(FdtError::BadValue),
+    };
+    if let Some(digest) = read_vendor_hashtree_root_digest(fdt)? {
+        if digest.len() != algorithm.digest_size() {
+            return Err(FdtError::BadValue);
+        }
+    }
+    Ok(Some(algorithm))
+}
+
+/// Size of the public key of Secretkeeper, a canonically encoded Ed25519 `COSE_Key`.
+const SECRETKEEPER_PUBLIC_KEY_SIZE: usize = 42;
+
+/// Reads the public key of the Secretkeeper HAL, from `/avf/secretkeeper_public_key`, used to
+/// establish the secure channel with Secretkeeper.
+///
+/// Returns `None` if the property is absent, `FdtError::NotFound` if there is no `/avf` node, or
+/// `FdtError::BadValue` if it isn't of the expected size.
+pub(crate) fn read_secretkeeper_public_key(fdt: &Fdt) -> libfdt::Result<Option<&[u8]>> {
+    let Some(key) = avf_node(fdt)?.getprop(cstr!("secretkeeper_public_key"))? else {
+        return Ok(None);
+    };
+    if key.len() != SECRETKEEPER_PUBLIC_KEY_SIZE {
+        return Err(FdtError::BadValue);
+    }
+    Ok(Some(key))
+}
+
+/// Reads the version of the kernel expected by the host, from `/avf/avf,kernel-version`.
+///
+/// Returns `None` if the property is absent, `FdtError::NotFound` if there is no `/avf` node, or
+/// `FdtError::BadValue` if it isn't a NUL-terminated UTF-8 string.
+pub(crate) fn read_kernel_version(fdt: &Fdt) -> libfdt::Result<Option<&str>> {
+    let Some(version) = avf_node(fdt)?.getprop_str(cstr!("avf,kernel-version"))? else {
+        return Ok(None);
+    };
+    version.to_str().map(Some).map_err(|_| FdtError::BadValue)
+}
+
+/// Reads the names of the APEXes the guest may be given, from the string list
+/// `/avf/avf,apex-allowlist`.
+///
+/// Returns `None` if the property is absent, `FdtError::NotFound` if there is no `/avf` node, or
+/// `FdtError::BadValue` if it isn't a list of NUL-terminated, non-empty, UTF-8 strings.
+pub(crate) fn read_apex_allowlist(fdt: &Fdt) -> libfdt::Result<Option<Vec<&str>>> {
+    let Some(value) = avf_node(fdt)?.getprop(cstr!("avf,apex-allowlist"))? else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(Some(Vec::new()));
+    }
+    let value = value.strip_suffix(b"\0").ok_or(FdtError::BadValue)?;
+    value
+        .split(|b| *b == 0)
+        .map(|name| match core::str::from_utf8(name) {
+            Ok(name) if !name.is_empty() => Ok(name),
+            _ => Err(FdtError::BadValue),
+        })
+        .collect::<libfdt::Result<_>>()
+        .map(Some)
+}
+
+/// Page sizes, in bytes, which the guest may be told to use.
+const SUPPORTED_PAGE_SIZES: [usize; 3] = [4096, 16384, 65536];
+
+/// Reads the page size the guest is expected to use, from `/avf/avf,page-size`.
+///
+/// Returns `None` if the property is absent, in which case the default page size should be used,
+/// `FdtError::NotFound` if there is no `/avf` node, or `FdtError::BadValue` if it isn't a <u32> of a
+/// supported page size.
+pub(crate) fn read_page_size(fdt: &Fdt) -> libfdt::Result<Option<usize>> {
+    let Some(page_size) = avf_node(fdt)?.getprop_u32(cstr!("avf,page-size"))? else {
+        return Ok(None);
+    };
+    let page_size = usize::try_from(page_size).map_err(|_| FdtError::BadValue)?;
+    if !SUPPORTED_PAGE_SIZES.contains(&page_size) {
+        return Err(FdtError::BadValue);
+    }
+    Ok(Some(page_size))
 }
 
 pub(crate) fn read_is_strict_boot(fdt: &Fdt) -> libfdt::Result<bool> {
@@ -36,3 +265,988 @@ pub(crate) fn read_is_strict_boot(fdt: &Fdt) -> libfdt::Result<bool> {
         None => Ok(false),
     }
 }
+
+/// Reads whether this is the first boot of the VM instance, in which case DICE derivation should
+/// create new values rather than verify the previously recorded ones.
+pub(crate) fn read_is_new_instance(fdt: &Fdt) -> libfdt::Result<bool> {
+    match fdt.chosen()? {
+        Some(node) => Ok(node.getprop(cstr!("avf,new-instance"))?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// Reads whether the guest must refuse to boot without an entropy seed from the hypervisor.
+pub(crate) fn read_require_rng_seed(fdt: &Fdt) -> libfdt::Result<bool> {
+    match fdt.chosen()? {
+        Some(node) => Ok(node.getprop(cstr!("avf,require-rng-seed"))?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// Reads the entropy seed as `read_rng_seed`, failing with `FdtError::NotFound` if it's absent
+/// but required by `/chosen/avf,require-rng-seed`.
+pub(crate) fn read_checked_rng_seed(fdt: &Fdt) -> libfdt::Result<Option<&[u8]>> {
+    let seed = read_rng_seed(fdt)?;
+    if seed.is_none() && read_require_rng_seed(fdt)? {
+        return Err(FdtError::NotFound);
+    }
+    Ok(seed)
+}
+
+/// Reads the entropy seed passed by the hypervisor as `/chosen/rng-seed`, if any.
+pub(crate) fn read_rng_seed(fdt: &Fdt) -> libfdt::Result<Option<&[u8]>> {
+    let Some(node) = fdt.chosen()? else {
+        return Ok(None);
+    };
+    let Some(seed) = node.getprop(cstr!("rng-seed"))? else {
+        return Ok(None);
+    };
+    if !(1..=MAX_RNG_SEED_SIZE).contains(&seed.len()) {
+        return Err(FdtError::BadValue);
+    }
+    Ok(Some(seed))
+}
+
+/// Overwrites `/chosen/rng-seed` with zeros, keeping the property and its size, so that the seed
+/// can't be reused or leaked once consumed.
+pub(crate) fn clear_rng_seed(fdt: &mut Fdt) -> libfdt::Result<()> {
+    let Some(len) = read_rng_seed(fdt)?.map(<[u8]>::len) else {
+        return Ok(());
+    };
+    let zeros = [0u8; MAX_RNG_SEED_SIZE];
+    let mut node = fdt.chosen_mut()?.ok_or(FdtError::NotFound)?;
+    node.setprop_inplace(cstr!("rng-seed"), &zeros[..len])
+}
+
+/// Reads the host monotonic time at which the VM was booted, in nanoseconds, from
+/// `/chosen/avf,boot-time-ns`, to align the clock of the guest with the one of the host.
+///
+/// Returns `None` if absent, or `FdtError::BadValue` if it isn't a 64-bit big-endian value.
+pub(crate) fn read_boot_time_ns(fdt: &Fdt) -> libfdt::Result<Option<u64>> {
+    let Some(node) = fdt.chosen()? else {
+        return Ok(None);
+    };
+    let Some(value) = node.getprop(cstr!("avf,boot-time-ns"))? else {
+        return Ok(None);
+    };
+    let value = value.try_into().map_err(|_| FdtError::BadValue)?;
+    Ok(Some(u64::from_be_bytes(value)))
+}
+
+/// Mode in which the guest is booted.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum BootMode {
+    Normal,
+    Recovery,
+    /// A mode this guest doesn't know about, left for the caller to handle.
+    Unknown(String),
+}
+
+/// Reads the mode in which the guest is booted, from `/chosen/avf,boot-mode`.
+///
+/// Returns `BootMode::Normal` if absent, or `FdtError::BadValue` if it isn't a NUL-terminated
+/// UTF-8 string.
+pub(crate) fn read_boot_mode(fdt: &Fdt) -> libfdt::Result<BootMode> {
+    let Some(node) = fdt.chosen()? else {
+        return Ok(BootMode::Normal);
+    };
+    let Some(mode) = node.getprop_str(cstr!("avf,boot-mode"))? else {
+        return Ok(BootMode::Normal);
+    };
+    match mode.to_str().map_err(|_| FdtError::BadValue)? {
+        "normal" => Ok(BootMode::Normal),
+        "recovery" => Ok(BootMode::Recovery),
+        mode => Ok(BootMode::Unknown(mode.into())),
+    }
+}
+
+/// Topology of the vCPUs of the guest.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CpuTopology {
+    /// The CPUs of each cluster, as indices into the CPU nodes of `/cpus`.
+    pub(crate) clusters: Vec<Vec<usize>>,
+}
+
+/// Reads the topology of the vCPUs from the clusters and cores of `/cpus/cpu-map`.
+///
+/// Returns a single cluster with all the CPUs if `/cpus/cpu-map` is absent, or
+/// `FdtError::BadPhandle` if a core doesn't reference a CPU node of `/cpus`. Nested clusters
+/// aren't supported but the threads of a core are listed individually.
+pub(crate) fn read_cpu_topology(fdt: &Fdt) -> libfdt::Result<CpuTopology> {
+    let cpus = fdt.node(cstr!("/cpus"))?.ok_or(FdtError::NotFound)?;
+    let mut cpu_phandles = Vec::new();
+    for node in cpus.subnodes()? {
+        if node.device_type()? == Some(cstr!("cpu")) {
+            cpu_phandles.push(node.get_phandle()?);
+        }
+    }
+    let Some(cpu_map) = cpus.subnode(cstr!("cpu-map"))? else {
+        return Ok(CpuTopology { clusters: vec![(0..cpu_phandles.len()).collect()] });
+    };
+
+    let mut clusters = Vec::new();
+    for cluster in cpu_map.subnodes()? {
+        let mut cluster_cpus = Vec::new();
+        for core in cluster.subnodes()? {
+            if !core.name()?.to_bytes().starts_with(b"core") {
+                return Err(FdtError::BadStructure);
+            }
+            let mut threads = core.subnodes()?.peekable();
+            if threads.peek().is_none() {
+                cluster_cpus.push(cpu_index(&core, &cpu_phandles)?);
+            }
+            for thread in threads {
+                cluster_cpus.push(cpu_index(&thread, &cpu_phandles)?);
+            }
+        }
+        clusters.push(cluster_cpus);
+    }
+    Ok(CpuTopology { clusters })
+}
+
+/// Returns the index of the CPU node referenced by the `cpu` property of `node`.
+fn cpu_index(node: &FdtNode, cpu_phandles: &[Option<Phandle>]) -> libfdt::Result<usize> {
+    let phandle = node.getprop_u32(cstr!("cpu"))?.ok_or(FdtError::NotFound)?;
+    let phandle = Some(Phandle::try_from(phandle)?);
+    cpu_phandles.iter().position(|p| *p == phandle).ok_or(FdtError::BadPhandle)
+}
+
+/// Returns the paths of the nodes tagged with `avf,assigned`, i.e. the devices assigned to the
+/// VM through the DTBO applied by the VFIO handler, in tree order.
+pub(crate) fn read_assigned_device_nodes(fdt: &Fdt) -> libfdt::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    // Names of the nodes from the root to the current one, as descendants are visited depth-first.
+    let mut ancestors = Vec::new();
+    for (node, depth) in fdt.root().descendants() {
+        ancestors.truncate(depth - 1);
+        ancestors.push(node.name()?.to_str().map_err(|_| FdtError::BadValue)?);
+        if node.getprop(cstr!("avf,assigned"))?.is_some() {
+            paths.push(ancestors.iter().flat_map(|name| ["/", *name]).collect());
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FDT_SIZE: usize = 4096;
+
+    fn write_chosen_flag(fdt: &mut Fdt, name: &CStr, value: bool) -> libfdt::Result<()> {
+        if fdt.chosen()?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("chosen"))?;
+        }
+        let mut chosen = fdt.chosen_mut()?.ok_or(FdtError::NotFound)?;
+        if value {
+            chosen.setprop_empty(name)
+        } else {
+            chosen.delprop(name).or_else(|e| if e == FdtError::NotFound { Ok(()) } else { Err(e) })
+        }
+    }
+
+    fn add_reserved_memory_region(
+        fdt: &mut Fdt,
+        name: &CStr,
+        compatible: Option<&CStr>,
+        addr: u64,
+        size: u64,
+    ) -> libfdt::Result<()> {
+        if fdt.node(cstr!("/reserved-memory"))?.is_none() {
+            let mut node = fdt.root_mut().add_subnode(cstr!("reserved-memory"))?;
+            node.setprop(cstr!("#address-cells"), &2u32.to_be_bytes())?;
+            node.setprop(cstr!("#size-cells"), &2u32.to_be_bytes())?;
+        }
+        let node = fdt.node_mut(cstr!("/reserved-memory"))?.ok_or(FdtError::NotFound)?;
+        let mut region = node.add_subnode(name)?;
+        if let Some(compatible) = compatible {
+            region.setprop(cstr!("compatible"), compatible.to_bytes_with_nul())?;
+        }
+        region.appendprop_addrrange(cstr!("reg"), addr, size)
+    }
+
+    fn write_rng_seed(fdt: &mut Fdt, seed: &[u8]) -> libfdt::Result<()> {
+        if fdt.chosen()?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("chosen"))?;
+        }
+        fdt.chosen_mut()?.ok_or(FdtError::NotFound)?.setprop(cstr!("rng-seed"), seed)
+    }
+
+    fn write_is_new_instance(fdt: &mut Fdt, new_instance: bool) -> libfdt::Result<()> {
+        write_chosen_flag(fdt, cstr!("avf,new-instance"), new_instance)
+    }
+
+    #[test]
+    fn new_instance_is_false_without_chosen() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_is_new_instance(fdt), Ok(false));
+    }
+
+    #[test]
+    fn new_instance_round_trips() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_is_new_instance(fdt, true).unwrap();
+        assert_eq!(read_is_new_instance(fdt), Ok(true));
+
+        write_is_new_instance(fdt, false).unwrap();
+        assert_eq!(read_is_new_instance(fdt), Ok(false));
+    }
+
+    #[test]
+    fn new_instance_is_independent_of_strict_boot() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_chosen_flag(fdt, cstr!("avf,strict-boot"), true).unwrap();
+
+        assert_eq!(read_is_strict_boot(fdt), Ok(true));
+        assert_eq!(read_is_new_instance(fdt), Ok(false));
+    }
+
+    #[test]
+    fn reserved_memory_regions_lists_all_regions() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(fdt, cstr!("swiotlb"), None, 0x8000_0000, 0x10_0000).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice"),
+            Some(cstr!("google,open-dice")),
+            0x9000_0000,
+            0x1000,
+        )
+        .unwrap();
+        add_reserved_memory_region(fdt, cstr!("other"), None, 0xa000_0000, 0x2000).unwrap();
+
+        let regions: Vec<_> =
+            reserved_memory_regions(fdt).unwrap().collect::<libfdt::Result<_>>().unwrap();
+
+        assert_eq!(
+            regions,
+            [
+                ("swiotlb", 0x8000_0000..0x8010_0000),
+                ("dice", 0x9000_0000..0x9000_1000),
+                ("other", 0xa000_0000..0xa000_2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved_memory_regions_skips_regions_without_reg() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(fdt, cstr!("static"), None, 0x8000_0000, 0x1000).unwrap();
+        let node = fdt.node_mut(cstr!("/reserved-memory")).unwrap().unwrap();
+        node.add_subnode(cstr!("dynamic")).unwrap().setprop_empty(cstr!("reusable")).unwrap();
+
+        let regions: Vec<_> =
+            reserved_memory_regions(fdt).unwrap().collect::<libfdt::Result<_>>().unwrap();
+
+        assert_eq!(regions, [("static", 0x8000_0000..0x8000_1000)]);
+    }
+
+    #[test]
+    fn reserved_memory_regions_reports_malformed_reg() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(fdt, cstr!("good"), None, 0x8000_0000, 0x1000).unwrap();
+        add_reserved_memory_region(fdt, cstr!("overflowing"), None, u64::MAX, 0x1000).unwrap();
+
+        let regions: Vec<_> = reserved_memory_regions(fdt).unwrap().collect();
+
+        assert_eq!(regions, [Ok(("good", 0x8000_0000..0x8000_1000)), Err(FdtError::BadValue)]);
+    }
+
+    #[test]
+    fn dice_range_is_found_among_reserved_regions() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(fdt, cstr!("swiotlb"), None, 0x8000_0000, 0x10_0000).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice"),
+            Some(cstr!("google,open-dice")),
+            0x9000_0000,
+            0x1000,
+        )
+        .unwrap();
+
+        assert_eq!(read_dice_range_from(fdt), Ok(0x9000_0000..0x9000_1000));
+    }
+
+    #[test]
+    fn empty_dice_range_is_rejected() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice"),
+            Some(cstr!("google,open-dice")),
+            0x9000_0000,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(read_dice_range_from(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn dice_range_is_not_found_without_reserved_memory() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_dice_range_from(fdt), Err(FdtError::NotFound));
+        assert!(reserved_memory_regions(fdt).is_err());
+    }
+
+    #[test]
+    fn swiotlb_range_is_read_when_aligned() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("swiotlb"),
+            Some(cstr!("restricted-dma-pool")),
+            0x8000_0000,
+            0x10_0000,
+        )
+        .unwrap();
+
+        assert_eq!(read_swiotlb_range(fdt), Ok(Some(0x8000_0000..0x8010_0000)));
+    }
+
+    #[test]
+    fn swiotlb_range_is_rejected_when_misaligned() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("swiotlb"),
+            Some(cstr!("restricted-dma-pool")),
+            0x8000_0800,
+            0x10_0000,
+        )
+        .unwrap();
+
+        assert_eq!(read_swiotlb_range(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn swiotlb_range_is_rejected_when_empty() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("swiotlb"),
+            Some(cstr!("restricted-dma-pool")),
+            0x8000_0000,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(read_swiotlb_range(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn swiotlb_range_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_swiotlb_range(fdt), Ok(None));
+
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice"),
+            Some(cstr!("google,open-dice")),
+            0x9000_0000,
+            0x1000,
+        )
+        .unwrap();
+
+        assert_eq!(read_swiotlb_range(fdt), Ok(None));
+    }
+
+    #[test]
+    fn rng_seed_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        let seed = [0xa5u8; 64];
+        write_rng_seed(fdt, &seed).unwrap();
+
+        assert_eq!(read_rng_seed(fdt), Ok(Some(&seed[..])));
+    }
+
+    #[test]
+    fn rng_seed_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_rng_seed(fdt), Ok(None));
+
+        write_chosen_flag(fdt, cstr!("avf,strict-boot"), true).unwrap();
+
+        assert_eq!(read_rng_seed(fdt), Ok(None));
+    }
+
+    #[test]
+    fn rng_seed_is_rejected_when_oversized_or_empty() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_rng_seed(fdt, &[0xa5u8; MAX_RNG_SEED_SIZE + 1]).unwrap();
+        assert_eq!(read_rng_seed(fdt), Err(FdtError::BadValue));
+
+        write_rng_seed(fdt, &[]).unwrap();
+        assert_eq!(read_rng_seed(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn rng_seed_reads_zeros_once_cleared() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        let seed = [0xa5u8; 64];
+        write_rng_seed(fdt, &seed).unwrap();
+        assert_eq!(read_rng_seed(fdt), Ok(Some(&seed[..])));
+
+        clear_rng_seed(fdt).unwrap();
+
+        assert_eq!(read_rng_seed(fdt), Ok(Some(&[0u8; 64][..])));
+    }
+
+    #[test]
+    fn rng_seed_clearing_is_noop_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(clear_rng_seed(fdt), Ok(()));
+        assert_eq!(read_rng_seed(fdt), Ok(None));
+    }
+
+    #[test]
+    fn assigned_device_nodes_are_listed_in_tree_order() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        let bus = fdt.root_mut().add_subnode(cstr!("bus")).unwrap();
+        let mut light = bus.add_subnode(cstr!("light@70000000")).unwrap();
+        light.setprop_empty(cstr!("avf,assigned")).unwrap();
+        let mut led = fdt.root_mut().add_subnode(cstr!("led@80000000")).unwrap();
+        led.setprop_empty(cstr!("avf,assigned")).unwrap();
+        fdt.root_mut().add_subnode(cstr!("uart@3f8")).unwrap();
+
+        assert_eq!(
+            read_assigned_device_nodes(fdt),
+            Ok(vec!["/bus/light@70000000".into(), "/led@80000000".into()])
+        );
+    }
+
+    #[test]
+    fn assigned_device_nodes_are_empty_without_tagged_nodes() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        fdt.root_mut().add_subnode(cstr!("uart@3f8")).unwrap();
+
+        assert_eq!(read_assigned_device_nodes(fdt), Ok(vec![]));
+    }
+
+    fn write_kernel_version(fdt: &mut Fdt, version: &[u8]) -> libfdt::Result<()> {
+        if fdt.node(cstr!("/avf"))?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("avf"))?;
+        }
+        let mut node = fdt.node_mut(cstr!("/avf"))?.ok_or(FdtError::NotFound)?;
+        node.setprop(cstr!("avf,kernel-version"), version)
+    }
+
+    #[test]
+    fn kernel_version_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_kernel_version(fdt, b"6.1.57-android14-11\0").unwrap();
+
+        assert_eq!(read_kernel_version(fdt), Ok(Some("6.1.57-android14-11")));
+    }
+
+    #[test]
+    fn kernel_version_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_kernel_version(fdt), Err(FdtError::NotFound));
+
+        fdt.root_mut().add_subnode(cstr!("avf")).unwrap();
+
+        assert_eq!(read_kernel_version(fdt), Ok(None));
+    }
+
+    #[test]
+    fn kernel_version_is_rejected_when_malformed() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_kernel_version(fdt, b"6.1.57").unwrap();
+        assert_eq!(read_kernel_version(fdt), Err(FdtError::BadValue));
+
+        write_kernel_version(fdt, b"6.1\xff\0").unwrap();
+        assert_eq!(read_kernel_version(fdt), Err(FdtError::BadValue));
+    }
+
+    fn write_boot_time_ns(fdt: &mut Fdt, value: &[u8]) -> libfdt::Result<()> {
+        if fdt.chosen()?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("chosen"))?;
+        }
+        fdt.chosen_mut()?.ok_or(FdtError::NotFound)?.setprop(cstr!("avf,boot-time-ns"), value)
+    }
+
+    #[test]
+    fn boot_time_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_boot_time_ns(fdt, &0x0123_4567_89ab_cdefu64.to_be_bytes()).unwrap();
+
+        assert_eq!(read_boot_time_ns(fdt), Ok(Some(0x0123_4567_89ab_cdef)));
+    }
+
+    #[test]
+    fn boot_time_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_boot_time_ns(fdt), Ok(None));
+
+        fdt.root_mut().add_subnode(cstr!("chosen")).unwrap();
+        assert_eq!(read_boot_time_ns(fdt), Ok(None));
+    }
+
+    #[test]
+    fn boot_time_of_wrong_length_is_rejected() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_boot_time_ns(fdt, &1u32.to_be_bytes()).unwrap();
+        assert_eq!(read_boot_time_ns(fdt), Err(FdtError::BadValue));
+
+        write_boot_time_ns(fdt, &[0; 9]).unwrap();
+        assert_eq!(read_boot_time_ns(fdt), Err(FdtError::BadValue));
+    }
+
+    fn write_apex_allowlist(fdt: &mut Fdt, value: &[u8]) -> libfdt::Result<()> {
+        if fdt.node(cstr!("/avf"))?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("avf"))?;
+        }
+        let mut node = fdt.node_mut(cstr!("/avf"))?.ok_or(FdtError::NotFound)?;
+        node.setprop(cstr!("avf,apex-allowlist"), value)
+    }
+
+    #[test]
+    fn apex_allowlist_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_apex_allowlist(fdt, b"com.android.art\0com.android.os.statsd\0com.android.sdkext\0")
+            .unwrap();
+
+        assert_eq!(
+            read_apex_allowlist(fdt),
+            Ok(Some(vec!["com.android.art", "com.android.os.statsd", "com.android.sdkext"]))
+        );
+    }
+
+    #[test]
+    fn apex_allowlist_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_apex_allowlist(fdt), Err(FdtError::NotFound));
+
+        fdt.root_mut().add_subnode(cstr!("avf")).unwrap();
+        assert_eq!(read_apex_allowlist(fdt), Ok(None));
+    }
+
+    #[test]
+    fn empty_apex_allowlist_allows_nothing() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_apex_allowlist(fdt, b"").unwrap();
+
+        assert_eq!(read_apex_allowlist(fdt), Ok(Some(vec![])));
+    }
+
+    #[test]
+    fn malformed_apex_allowlist_is_rejected() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        for value in [&b"com.android.art"[..], b"com.android.art\0\0", b"\xff\0"] {
+            write_apex_allowlist(fdt, value).unwrap();
+            assert_eq!(read_apex_allowlist(fdt), Err(FdtError::BadValue), "{value:?}");
+        }
+    }
+
+    fn write_page_size(fdt: &mut Fdt, value: &[u8]) -> libfdt::Result<()> {
+        if fdt.node(cstr!("/avf"))?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("avf"))?;
+        }
+        let mut node = fdt.node_mut(cstr!("/avf"))?.ok_or(FdtError::NotFound)?;
+        node.setprop(cstr!("avf,page-size"), value)
+    }
+
+    #[test]
+    fn supported_page_sizes_are_read() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        for page_size in [4096u32, 16384, 65536] {
+            write_page_size(fdt, &page_size.to_be_bytes()).unwrap();
+            assert_eq!(read_page_size(fdt), Ok(Some(page_size.try_into().unwrap())));
+        }
+    }
+
+    #[test]
+    fn unsupported_page_sizes_are_rejected() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        for page_size in [0u32, 2048, 8192, 12288, 1 << 21] {
+            write_page_size(fdt, &page_size.to_be_bytes()).unwrap();
+            assert_eq!(read_page_size(fdt), Err(FdtError::BadValue), "{page_size}");
+        }
+        write_page_size(fdt, &4096u64.to_be_bytes()).unwrap();
+        assert_eq!(read_page_size(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn page_size_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_page_size(fdt), Err(FdtError::NotFound));
+
+        fdt.root_mut().add_subnode(cstr!("avf")).unwrap();
+        assert_eq!(read_page_size(fdt), Ok(None));
+    }
+
+    fn add_cpus(fdt: &mut Fdt, names: &[&CStr]) -> libfdt::Result<()> {
+        fdt.root_mut().add_subnode(cstr!("cpus"))?;
+        for (i, name) in names.iter().enumerate() {
+            let cpus = fdt.node_mut(cstr!("/cpus"))?.ok_or(FdtError::NotFound)?;
+            let mut cpu = cpus.add_subnode(name)?;
+            cpu.setprop(cstr!("device_type"), b"cpu\0")?;
+            cpu.setprop(cstr!("phandle"), &(u32::try_from(i).unwrap() + 1).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn add_core(
+        fdt: &mut Fdt,
+        cluster: &CStr,
+        core: &CStr,
+        cpu_phandle: u32,
+    ) -> libfdt::Result<()> {
+        let cluster = fdt.node_mut(cluster)?.ok_or(FdtError::NotFound)?;
+        cluster.add_subnode(core)?.setprop(cstr!("cpu"), &cpu_phandle.to_be_bytes())
+    }
+
+    fn cpu_names() -> [&'static CStr; 4] {
+        [cstr!("cpu@0"), cstr!("cpu@1"), cstr!("cpu@2"), cstr!("cpu@3")]
+    }
+
+    fn add_cpu_map(fdt: &mut Fdt) -> libfdt::Result<()> {
+        let cpus = fdt.node_mut(cstr!("/cpus"))?.ok_or(FdtError::NotFound)?;
+        cpus.add_subnode(cstr!("cpu-map"))?.add_subnodes(&[cstr!("cluster0"), cstr!("cluster1")])
+    }
+
+    #[test]
+    fn cpu_topology_is_read_from_cpu_map() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_cpus(fdt, &cpu_names()).unwrap();
+        add_cpu_map(fdt).unwrap();
+        let cluster0 = cstr!("/cpus/cpu-map/cluster0");
+        let cluster1 = cstr!("/cpus/cpu-map/cluster1");
+        add_core(fdt, cluster0, cstr!("core0"), 1).unwrap();
+        add_core(fdt, cluster0, cstr!("core1"), 2).unwrap();
+        add_core(fdt, cluster1, cstr!("core0"), 4).unwrap();
+        add_core(fdt, cluster1, cstr!("core1"), 3).unwrap();
+
+        assert_eq!(
+            read_cpu_topology(fdt),
+            Ok(CpuTopology { clusters: vec![vec![0, 1], vec![3, 2]] })
+        );
+    }
+
+    #[test]
+    fn cpu_topology_is_a_single_cluster_without_cpu_map() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_cpus(fdt, &cpu_names()).unwrap();
+
+        assert_eq!(read_cpu_topology(fdt), Ok(CpuTopology { clusters: vec![vec![0, 1, 2, 3]] }));
+    }
+
+    #[test]
+    fn cpu_topology_rejects_cores_without_cpu_node() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_cpus(fdt, &cpu_names()[..2]).unwrap();
+        add_cpu_map(fdt).unwrap();
+        add_core(fdt, cstr!("/cpus/cpu-map/cluster0"), cstr!("core0"), 3).unwrap();
+
+        assert_eq!(read_cpu_topology(fdt), Err(FdtError::BadPhandle));
+    }
+
+    #[test]
+    fn required_rng_seed_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_chosen_flag(fdt, cstr!("avf,require-rng-seed"), true).unwrap();
+        write_rng_seed(fdt, &[0xab; 32]).unwrap();
+
+        assert_eq!(read_require_rng_seed(fdt), Ok(true));
+        assert_eq!(read_checked_rng_seed(fdt), Ok(Some(&[0xab; 32][..])));
+    }
+
+    #[test]
+    fn required_rng_seed_is_rejected_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        write_chosen_flag(fdt, cstr!("avf,require-rng-seed"), true).unwrap();
+
+        assert_eq!(read_checked_rng_seed(fdt), Err(FdtError::NotFound));
+    }
+
+    #[test]
+    fn rng_seed_is_optional_unless_required() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_require_rng_seed(fdt), Ok(false));
+        assert_eq!(read_checked_rng_seed(fdt), Ok(None));
+
+        write_chosen_flag(fdt, cstr!("avf,require-rng-seed"), false).unwrap();
+        assert_eq!(read_require_rng_seed(fdt), Ok(false));
+        assert_eq!(read_checked_rng_seed(fdt), Ok(None));
+    }
+
+    #[test]
+    fn dice_handover_range_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice"),
+            Some(cstr!("google,open-dice")),
+            0x9000_0000,
+            0x1000,
+        )
+        .unwrap();
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice-handover"),
+            Some(cstr!("android,dice-handover")),
+            0x9000_1000,
+            0x2000,
+        )
+        .unwrap();
+
+        assert_eq!(read_dice_handover_range(fdt), Ok(Some(0x9000_1000..0x9000_3000)));
+        assert_eq!(read_dice_range_from(fdt), Ok(0x9000_0000..0x9000_1000));
+    }
+
+    #[test]
+    fn dice_handover_range_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_dice_handover_range(fdt), Ok(None));
+
+        add_reserved_memory_region(
+            fdt,
+            cstr!("dice"),
+            Some(cstr!("google,open-dice")),
+            0x9000_0000,
+            0x1000,
+        )
+        .unwrap();
+        assert_eq!(read_dice_handover_range(fdt), Ok(None));
+    }
+
+    fn write_secretkeeper_public_key(fdt: &mut Fdt, key: &[u8]) -> libfdt::Result<()> {
+        if fdt.node(cstr!("/avf"))?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("avf"))?;
+        }
+        let mut node = fdt.node_mut(cstr!("/avf"))?.ok_or(FdtError::NotFound)?;
+        node.setprop(cstr!("secretkeeper_public_key"), key)
+    }
+
+    #[test]
+    fn secretkeeper_public_key_is_read_when_present() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        let key = [0xa4; SECRETKEEPER_PUBLIC_KEY_SIZE];
+        write_secretkeeper_public_key(fdt, &key).unwrap();
+
+        assert_eq!(read_secretkeeper_public_key(fdt), Ok(Some(&key[..])));
+    }
+
+    #[test]
+    fn secretkeeper_public_key_of_wrong_length_is_rejected() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_secretkeeper_public_key(fdt, &[0xa4; SECRETKEEPER_PUBLIC_KEY_SIZE - 1]).unwrap();
+
+        assert_eq!(read_secretkeeper_public_key(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn secretkeeper_public_key_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_secretkeeper_public_key(fdt), Err(FdtError::NotFound));
+
+        fdt.root_mut().add_subnode(cstr!("avf")).unwrap();
+        assert_eq!(read_secretkeeper_public_key(fdt), Ok(None));
+    }
+
+    #[test]
+    fn vendor_hashtree_root_digest_requires_avf_node() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(avf_node_present(fdt), Ok(false));
+        assert_eq!(read_vendor_hashtree_root_digest(fdt), Err(FdtError::NotFound));
+    }
+
+    #[test]
+    fn vendor_hashtree_root_digest_is_none_when_absent_from_avf_node() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        fdt.root_mut().add_subnode(cstr!("avf")).unwrap();
+
+        assert_eq!(avf_node_present(fdt), Ok(true));
+        assert_eq!(read_vendor_hashtree_root_digest(fdt), Ok(None));
+
+        let digest = [0xab; 32];
+        let mut node = fdt.node_mut(cstr!("/avf")).unwrap().unwrap();
+        node.setprop(cstr!("vendor_hashtree_descriptor_root_digest"), &digest).unwrap();
+        assert_eq!(read_vendor_hashtree_root_digest(fdt), Ok(Some(&digest[..])));
+    }
+
+    fn write_boot_mode(fdt: &mut Fdt, mode: &[u8]) -> libfdt::Result<()> {
+        if fdt.chosen()?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("chosen"))?;
+        }
+        let mut chosen = fdt.chosen_mut()?.ok_or(FdtError::NotFound)?;
+        chosen.setprop(cstr!("avf,boot-mode"), mode)
+    }
+
+    #[test]
+    fn boot_mode_is_read_when_normal() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_boot_mode(fdt, b"normal\0").unwrap();
+
+        assert_eq!(read_boot_mode(fdt), Ok(BootMode::Normal));
+    }
+
+    #[test]
+    fn boot_mode_is_read_when_recovery() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_boot_mode(fdt, b"recovery\0").unwrap();
+
+        assert_eq!(read_boot_mode(fdt), Ok(BootMode::Recovery));
+    }
+
+    #[test]
+    fn boot_mode_is_normal_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+
+        assert_eq!(read_boot_mode(fdt), Ok(BootMode::Normal));
+
+        fdt.root_mut().add_subnode(cstr!("chosen")).unwrap();
+
+        assert_eq!(read_boot_mode(fdt), Ok(BootMode::Normal));
+    }
+
+    #[test]
+    fn boot_mode_is_unknown_when_unrecognized() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_boot_mode(fdt, b"fastboot\0").unwrap();
+
+        assert_eq!(read_boot_mode(fdt), Ok(BootMode::Unknown("fastboot".into())));
+
+        write_boot_mode(fdt, b"\xff\0").unwrap();
+
+        assert_eq!(read_boot_mode(fdt), Err(FdtError::BadValue));
+    }
+
+    fn write_vendor_hashtree(
+        fdt: &mut Fdt,
+        algorithm: Option<&[u8]>,
+        digest: Option<&[u8]>,
+    ) -> libfdt::Result<()> {
+        if fdt.node(cstr!("/avf"))?.is_none() {
+            fdt.root_mut().add_subnode(cstr!("avf"))?;
+        }
+        let mut node = fdt.node_mut(cstr!("/avf"))?.ok_or(FdtError::NotFound)?;
+        if let Some(algorithm) = algorithm {
+            node.setprop(cstr!("vendor_hashtree_descriptor_hash_algorithm"), algorithm)?;
+        }
+        if let Some(digest) = digest {
+            node.setprop(cstr!("vendor_hashtree_descriptor_root_digest"), digest)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vendor_hashtree_algorithm_is_read_when_matching_digest() {
+        for (algorithm, expected) in
+            [(&b"sha256\0"[..], HashAlgorithm::Sha256), (b"sha512\0", HashAlgorithm::Sha512)]
+        {
+            let mut buf = [0u8; FDT_SIZE];
+            let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+            let digest = vec![0xab; expected.digest_size()];
+            write_vendor_hashtree(fdt, Some(algorithm), Some(&digest)).unwrap();
+
+            assert_eq!(read_vendor_hashtree_algorithm(fdt), Ok(Some(expected)));
+        }
+    }
+
+    #[test]
+    fn vendor_hashtree_algorithm_is_rejected_when_mismatching_digest() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_vendor_hashtree(fdt, Some(b"sha512\0"), Some(&[0xab; 32])).unwrap();
+
+        assert_eq!(read_vendor_hashtree_algorithm(fdt), Err(FdtError::BadValue));
+
+        write_vendor_hashtree(fdt, Some(b"md5\0"), Some(&[0xab; 16])).unwrap();
+
+        assert_eq!(read_vendor_hashtree_algorithm(fdt), Err(FdtError::BadValue));
+    }
+
+    #[test]
+    fn vendor_hashtree_algorithm_is_none_when_absent() {
+        let mut buf = [0u8; FDT_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buf).unwrap();
+        write_vendor_hashtree(fdt, None, Some(&[0xab; 32])).unwrap();
+
+        assert_eq!(read_vendor_hashtree_algorithm(fdt), Ok(None));
+
+        write_vendor_hashtree(fdt, Some(b"sha256\0"), None).unwrap();
+        let mut node = fdt.node_mut(cstr!("/avf")).unwrap().unwrap();
+        node.delprop(cstr!("vendor_hashtree_descriptor_root_digest")).unwrap();
+
+        assert_eq!(read_vendor_hashtree_algorithm(fdt), Ok(Some(HashAlgorithm::Sha256)));
+    }
+}
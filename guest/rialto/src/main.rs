@@ -26,7 +26,13 @@ extern crate alloc;
 
 use crate::communication::VsockStream;
 use crate::error::{Error, Result};
-use crate::fdt::{read_dice_range_from, read_is_strict_boot, read_vendor_hashtree_root_digest};
+use crate::fdt::{
+    avf_node_present, clear_rng_seed, read_apex_allowlist, read_assigned_device_nodes,
+    read_boot_mode, read_boot_time_ns, read_checked_rng_seed, read_cpu_topology,
+    read_dice_range_from, read_is_new_instance, read_is_strict_boot, read_kernel_version,
+    read_page_size, read_secretkeeper_public_key, read_swiotlb_range,
+    read_vendor_hashtree_algorithm, read_vendor_hashtree_root_digest, BootMode,
+};
 use alloc::boxed::Box;
 use ciborium_io::Write;
 use core::num::NonZeroUsize;
@@ -34,7 +40,7 @@ use core::slice;
 use diced_open_dice::{bcc_handover_parse, DiceArtifacts};
 use fdtpci::PciInfo;
 use libfdt::FdtError;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use service_vm_comm::{ServiceVmRequest, VmType};
 use service_vm_fake_chain::service_vm;
 use service_vm_requests::{process_request, RequestContext};
@@ -44,9 +50,7 @@ use virtio_drivers::{
     Hal,
 };
 use vmbase::{
-    configure_heap,
-    fdt::SwiotlbInfo,
-    generate_image_header,
+    configure_heap, generate_image_header,
     hyp::{get_mem_sharer, get_mmio_guard},
     layout::{self, crosvm, UART_PAGE_ADDR},
     main,
@@ -101,11 +105,48 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
         .lock()
         .as_mut()
         .unwrap()
-        .alloc(fdt_addr, NonZeroUsize::new(crosvm::FDT_MAX_SIZE).unwrap())?;
+        .alloc_mut(fdt_addr, NonZeroUsize::new(crosvm::FDT_MAX_SIZE).unwrap())?;
     // SAFETY: The tracker validated the range to be in main memory, mapped, and not overlap.
-    let fdt = unsafe { slice::from_raw_parts(fdt_range.start as *mut u8, fdt_range.len()) };
+    let fdt = unsafe { slice::from_raw_parts_mut(fdt_range.start as *mut u8, fdt_range.len()) };
     // We do not need to validate the DT since it is already validated in pvmfw.
-    let fdt = libfdt::Fdt::from_slice(fdt)?;
+    let fdt = libfdt::Fdt::from_mut_slice(fdt)?;
+
+    // Rialto draws its entropy from the TRNG, so the seed is only checked to be there if required.
+    let rng_seed_len = read_checked_rng_seed(fdt)
+        .inspect_err(|_| error!("Missing or invalid /chosen/rng-seed"))?
+        .map(<[u8]>::len);
+    if let Some(len) = rng_seed_len {
+        debug!("Entropy seed of {len} bytes provided");
+        // Wipe the unused seed so that it doesn't end up in a memory dump.
+        clear_rng_seed(fdt)?;
+    }
+    let fdt: &libfdt::Fdt = fdt;
+    match read_boot_mode(fdt)? {
+        BootMode::Normal => {}
+        BootMode::Recovery => {
+            error!("Rialto has no recovery mode");
+            return Err(FdtError::BadValue.into());
+        }
+        BootMode::Unknown(mode) => {
+            error!("Unknown boot mode {mode}");
+            return Err(FdtError::BadValue.into());
+        }
+    }
+    // Rialto only runs on its boot CPU, so the topology is only logged.
+    match read_cpu_topology(fdt) {
+        Ok(topology) => {
+            for (i, cpus) in topology.clusters.iter().enumerate() {
+                debug!("CPU cluster {i}: {cpus:?}");
+            }
+        }
+        Err(e) => warn!("Failed to read the CPU topology: {e}"),
+    }
+    // Rialto keeps no clock to offset, but the logs can still be lined up with the host ones.
+    match read_boot_time_ns(fdt) {
+        Ok(Some(boot_time_ns)) => info!("Booted at host monotonic time {boot_time_ns}ns"),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the boot time: {e}"),
+    }
 
     let memory_range = fdt.first_memory_range()?;
     MEMORY.lock().as_mut().unwrap().shrink(&memory_range).inspect_err(|_| {
@@ -117,11 +158,9 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
         MEMORY.lock().as_mut().unwrap().init_dynamic_shared_pool(granule).inspect_err(|_| {
             error!("Failed to initialize dynamically shared pool.");
         })?;
-    } else if let Ok(swiotlb_info) = SwiotlbInfo::new_from_fdt(fdt) {
-        let range = swiotlb_info.fixed_range().ok_or_else(|| {
-            error!("Pre-shared pool range not specified in swiotlb node");
-            Error::from(FdtError::BadValue)
-        })?;
+    } else if let Some(range) = read_swiotlb_range(fdt).inspect_err(|_| {
+        error!("Invalid pre-shared pool range in swiotlb node");
+    })? {
         MEMORY.lock().as_mut().unwrap().init_static_shared_pool(range).inspect_err(|_| {
             error!("Failed to initialize pre-shared pool.");
         })?;
@@ -134,6 +173,11 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
 
     let bcc_handover: Box<dyn DiceArtifacts> = match vm_type(fdt)? {
         VmType::ProtectedVm => {
+            // The flag is informational only, so a malformed value must not stop the service VM.
+            match read_is_new_instance(fdt) {
+                Ok(new_instance) => debug!("New instance: {new_instance}"),
+                Err(e) => warn!("Failed to read the new instance flag: {e}"),
+            }
             let dice_range = read_dice_range_from(fdt)?;
             info!("DICE range: {dice_range:#x?}");
             // SAFETY: This region was written by pvmfw in its writable_data region. The region
@@ -160,9 +204,49 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
     let mut pci_root = pci::initialize(pci_info, MEMORY.lock().as_mut().unwrap())
         .map_err(Error::PciInitializationFailed)?;
     debug!("PCI root: {pci_root:#x?}");
+    match read_assigned_device_nodes(fdt) {
+        Ok(paths) => {
+            for path in paths {
+                warn!("Ignoring assigned device {path}: rialto has no driver for it");
+            }
+        }
+        Err(e) => warn!("Failed to read the assigned devices: {e}"),
+    }
     let socket_device = find_socket_device::<HalImpl>(&mut pci_root)?;
     debug!("Found socket device: guest cid = {:?}", socket_device.guest_cid());
+    if !avf_node_present(fdt)? {
+        error!("No /avf node in the DT: rialto must be booted by AVF");
+        return Err(FdtError::NotFound.into());
+    }
+    if let Some(page_size) = read_page_size(fdt)? {
+        if page_size != PAGE_SIZE {
+            error!("Rialto is built for {PAGE_SIZE}-byte pages but the host expects {page_size}");
+            return Err(FdtError::BadValue.into());
+        }
+    }
+    // Rialto isn't built against a kernel ABI, so it has nothing to compare the version with.
+    match read_kernel_version(fdt) {
+        Ok(Some(kernel_version)) => info!("Host expects kernel version {kernel_version}"),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the kernel version: {e}"),
+    }
+    // Rialto is given no APEX, so it trivially matches any allowlist.
+    match read_apex_allowlist(fdt) {
+        Ok(Some(apex_allowlist)) => debug!("APEX allowlist: {apex_allowlist:?}"),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the APEX allowlist: {e}"),
+    }
     let vendor_hashtree_root_digest = read_vendor_hashtree_root_digest(fdt)?;
+    // Also checks that the digest has the size of the algorithm the hashtree is built with.
+    if let Some(algorithm) = read_vendor_hashtree_algorithm(fdt)? {
+        debug!("Vendor hashtree algorithm: {algorithm:?}");
+    }
+    // Nothing in rialto talks to Secretkeeper yet, so a malformed key is only reported.
+    match read_secretkeeper_public_key(fdt) {
+        Ok(Some(_)) => debug!("Secretkeeper public key provided"),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the Secretkeeper public key: {e}"),
+    }
     let request_context =
         RequestContext { dice_artifacts: bcc_handover.as_ref(), vendor_hashtree_root_digest };
 
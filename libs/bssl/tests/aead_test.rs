@@ -134,6 +134,13 @@ fn aes_256_gcm_fails_to_decrypt_corrupted_ciphertext() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn aead_key_lengths_match_algorithms() {
+    assert_eq!(Aead::aes_128_gcm_randnonce().key_length(), 16);
+    assert_eq!(Aead::aes_256_gcm().key_length(), 32);
+    assert_eq!(Aead::aes_256_gcm_randnonce().key_length(), 32);
+}
+
 fn aes_256_gcm_encrypt(message: &[u8]) -> Result<Vec<u8>> {
     let tag_len = None;
     let aead_ctx = AeadContext::new(Aead::aes_256_gcm(), &KEY1, tag_len)?;
@@ -41,6 +41,11 @@ pub struct VmPayloadConfig {
     #[serde(default)]
     pub prefer_staged: bool,
 
+    /// Tells VirtualizationService to only pass the factory version of APEXes, failing if only an
+    /// updated version of one is available. Can't be combined with `prefer_staged`.
+    #[serde(default)]
+    pub factory_apexes_only: bool,
+
     /// Whether to export the tomsbtones (VM crashes) out of VM to host
     /// Default: true for debuggable VMs, false for non-debuggable VMs
     pub export_tombstones: Option<bool>,